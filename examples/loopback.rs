@@ -4,7 +4,7 @@
 //! This device should behave similar to the loopback device.
 
 use usb_device::{bus::UsbBusAllocator, prelude::*};
-use usbd_ecm::{UsbEthernetDevice, ETH_FRAME_SIZE, USB_CLASS_CDC};
+use usbd_ecm::{MacAddresses, UsbEthernetDevice, ETH_FRAME_SIZE, USB_CLASS_CDC};
 use usbip_device::UsbIpBus;
 
 fn sleep() {
@@ -16,7 +16,9 @@ fn main() {
 
    log::info!("initializing allocator");
    let bus_allocator = UsbBusAllocator::new(UsbIpBus::new());
-   let mut usb_eth = UsbEthernetDevice::new(&bus_allocator, &[1, 2, 3, 4, 5, 6]);
+   // The addresses are derived from the serial number, such that they are stable
+   let mac_addresses = MacAddresses::from_unique_id(b"TEST");
+   let mut usb_eth = UsbEthernetDevice::with_mac_addresses(&bus_allocator, &mac_addresses);
 
    let mut usb_bus = UsbDeviceBuilder::new(&bus_allocator, UsbVidPid(0x16c0, 0x05e1))
       .manufacturer("Fake company")
@@ -0,0 +1,544 @@
+//! This module implements a learning ethernet bridge between the USB link and another
+//! `smoltcp` [`Device`], e.g. the on-chip ethernet MAC of a USB-to-ethernet adapter.
+//!
+//! The bridge learns, behind which port a mac address lives, from the source addresses of the
+//! frames it forwards, such that unicast frames are only forwarded, where they need to go.
+//! Broadcast, multicast and frames to unknown addresses are flooded to every other port.
+//!
+//! Optionally, the bridge has a local endpoint with its own mac address, such that the device
+//! itself is reachable, e.g. for configuration.
+//! The [`Bridge`] then is a [`Device`] itself, which is handed to a `smoltcp` `Interface`.
+
+use crate::ETH_FRAME_SIZE;
+use smoltcp::{
+   phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken},
+   time::{Duration, Instant},
+   wire::{EthernetAddress, EthernetFrame},
+};
+
+/// The number of mac addresses, the bridge remembers
+pub const FDB_SIZE: usize = 16;
+
+/// The time after which a learned address is forgotten, IEEE 802.1D
+const AGEING_TIME: Duration = Duration::from_secs(300);
+
+/// The number of frames forwarded from each port in a single [`Bridge::poll`] at most
+const MAX_FRAMES_PER_POLL: usize = 8;
+
+/// A port of the bridge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Port {
+   Usb,
+   Other,
+   Local,
+}
+
+/// An entry of the forwarding database
+#[derive(Debug, Clone, Copy)]
+struct FdbEntry {
+   addr: EthernetAddress,
+   port: Port,
+   last_seen: Instant,
+}
+
+/// Where a frame is forwarded to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+   /// The frame is filtered, since its destination lives behind the ingress port
+   Drop,
+   /// Every other port
+   Flood,
+   Port(Port),
+}
+
+/// The forwarding database, which maps mac addresses to ports
+#[derive(Debug)]
+struct Fdb {
+   entries: [Option<FdbEntry>; FDB_SIZE],
+}
+
+impl Fdb {
+   /// Remembers, that `addr` lives behind `port`.
+   /// If the database is full, the entry seen least recently is replaced.
+   fn learn(&mut self, addr: EthernetAddress, port: Port, now: Instant) {
+      let entry = FdbEntry {
+         addr,
+         port,
+         last_seen: now,
+      };
+
+      if let Some(slot) = self.entries.iter_mut().flatten().find(|entry| entry.addr == addr) {
+         if slot.port != port {
+            log::debug!("{} moved to {:?}", addr, port);
+         }
+         *slot = entry;
+         return;
+      }
+
+      let slot = match self.entries.iter_mut().position(|slot| slot.is_none()) {
+         Some(idx) => idx,
+         None => {
+            let (idx, _) = self
+               .entries
+               .iter()
+               .enumerate()
+               .min_by_key(|(_, slot)| slot.map(|entry| entry.last_seen))
+               .unwrap();
+            idx
+         }
+      };
+      self.entries[slot] = Some(entry);
+   }
+
+   /// Returns the port, behind which `addr` lives, if it has been seen recently
+   fn lookup(&self, addr: EthernetAddress, now: Instant) -> Option<Port> {
+      self
+         .entries
+         .iter()
+         .flatten()
+         .find(|entry| entry.addr == addr && now < entry.last_seen + AGEING_TIME)
+         .map(|entry| entry.port)
+   }
+}
+
+/// The local endpoint of the bridge
+struct Local {
+   addr: EthernetAddress,
+   /// A frame received for the local endpoint, that has not been consumed yet
+   rx: [u8; ETH_FRAME_SIZE],
+   rx_len: Option<usize>,
+   /// The frame, that is sent by the local endpoint
+   tx: [u8; ETH_FRAME_SIZE],
+}
+
+/// A learning ethernet bridge between a USB link, e.g. a [`SmolUsb`](crate::SmolUsb) device,
+/// and another [`Device`].
+pub struct Bridge<U: Device, D: Device> {
+   usb: U,
+   other: D,
+   fdb: Fdb,
+   local: Option<Local>,
+   dropped_frames: u32,
+}
+
+impl<U: Device, D: Device> Bridge<U, D> {
+   /// Creates a bridge without a local endpoint
+   pub fn new(usb: U, other: D) -> Self {
+      Self {
+         usb,
+         other,
+         fdb: Fdb {
+            entries: [None; FDB_SIZE],
+         },
+         local: None,
+         dropped_frames: 0,
+      }
+   }
+
+   /// Creates a bridge with a local endpoint, which receives the frames addressed to `addr`
+   /// and is used through the [`Device`] implementation of the bridge.
+   pub fn with_local(usb: U, other: D, addr: EthernetAddress) -> Self {
+      let mut bridge = Self::new(usb, other);
+      bridge.local = Some(Local {
+         addr,
+         rx: [0; ETH_FRAME_SIZE],
+         rx_len: None,
+         tx: [0; ETH_FRAME_SIZE],
+      });
+      bridge
+   }
+
+   /// Returns the USB side of the bridge
+   pub fn usb(&mut self) -> &mut U {
+      &mut self.usb
+   }
+
+   /// Returns the other side of the bridge
+   pub fn other(&mut self) -> &mut D {
+      &mut self.other
+   }
+
+   /// Returns the number of frames, that could not be forwarded,
+   /// since the egress port was busy or the frame was too long for it
+   pub fn dropped_frames(&self) -> u32 {
+      self.dropped_frames
+   }
+
+   /// Forwards the frames received on either side.
+   ///
+   /// Frames for the local endpoint are kept, until they are received through the [`Device`]
+   /// implementation, further ones are dropped in the meantime.
+   ///
+   /// Returns `true`, if any frame has been processed.
+   pub fn poll(&mut self, timestamp: Instant) -> bool {
+      let mut processed = false;
+      for _ in 0..MAX_FRAMES_PER_POLL {
+         let usb = self.forward_from_usb(timestamp);
+         let other = self.forward_from_other(timestamp);
+         if !usb && !other {
+            break;
+         }
+         processed = true;
+      }
+      processed
+   }
+
+   fn forward_from_usb(&mut self, timestamp: Instant) -> bool {
+      let (rx, _) = match self.usb.receive(timestamp) {
+         None => return false,
+         Some(tokens) => tokens,
+      };
+
+      let (fdb, local, other) = (&mut self.fdb, &mut self.local, &mut self.other);
+      let forwarded = rx.consume(|frame| forward(Port::Usb, frame, fdb, local, other, Port::Other, timestamp));
+      self.count(forwarded);
+      true
+   }
+
+   fn forward_from_other(&mut self, timestamp: Instant) -> bool {
+      let (rx, _) = match self.other.receive(timestamp) {
+         None => return false,
+         Some(tokens) => tokens,
+      };
+
+      let (fdb, local, usb) = (&mut self.fdb, &mut self.local, &mut self.usb);
+      let forwarded = rx.consume(|frame| forward(Port::Other, frame, fdb, local, usb, Port::Usb, timestamp));
+      self.count(forwarded);
+      true
+   }
+
+   fn count(&mut self, forwarded: bool) {
+      if !forwarded {
+         self.dropped_frames = self.dropped_frames.wrapping_add(1);
+      }
+   }
+}
+
+/// Forwards a frame received on `ingress` to the `peer` port and the local endpoint, if needed.
+///
+/// Returns `false`, if the frame had to be dropped, since a port was busy or the frame too long.
+fn forward<T: Device>(
+   ingress: Port,
+   frame: &[u8],
+   fdb: &mut Fdb,
+   local: &mut Option<Local>,
+   peer: &mut T,
+   peer_port: Port,
+   timestamp: Instant,
+) -> bool {
+   let (to_peer, to_local) = match target(ingress, frame, fdb, local.as_ref().map(|local| local.addr), timestamp) {
+      Target::Drop => return true,
+      Target::Flood => (true, true),
+      Target::Port(port) => (port == peer_port, port == Port::Local),
+   };
+
+   let mut forwarded = true;
+   if let (true, Some(local)) = (to_local && frame.len() <= ETH_FRAME_SIZE, local) {
+      match local.rx_len {
+         Some(_) => forwarded = false,
+         None => {
+            local.rx[..frame.len()].copy_from_slice(frame);
+            local.rx_len = Some(frame.len());
+         }
+      }
+   }
+   if to_peer {
+      forwarded &= transmit(peer, frame, timestamp);
+   }
+
+   if !forwarded {
+      log::trace!("egress port can not take the frame, dropping frame from {:?}", ingress);
+   }
+   forwarded
+}
+
+/// Learns the source address of a frame received on `ingress` and returns, where it is forwarded to
+fn target(
+   ingress: Port,
+   frame: &[u8],
+   fdb: &mut Fdb,
+   local_addr: Option<EthernetAddress>,
+   timestamp: Instant,
+) -> Target {
+   let frame = match EthernetFrame::new_checked(frame) {
+      Err(_) => return Target::Drop,
+      Ok(frame) => frame,
+   };
+
+   let (src, dst) = (frame.src_addr(), frame.dst_addr());
+   if src.is_unicast() && ingress != Port::Local {
+      fdb.learn(src, ingress, timestamp);
+   }
+
+   let port = match dst.is_unicast() {
+      false => None,
+      true if Some(dst) == local_addr => Some(Port::Local),
+      true => fdb.lookup(dst, timestamp),
+   };
+   match port {
+      None => Target::Flood,
+      Some(port) if port == ingress => Target::Drop,
+      Some(port) => Target::Port(port),
+   }
+}
+
+/// Copies `frame` into the transmit queue of `device`.
+/// Returns `false`, if the frame is too long for the device or it can not take a frame right now.
+fn transmit<T: Device>(device: &mut T, frame: &[u8], timestamp: Instant) -> bool {
+   if frame.len() > device.capabilities().max_transmission_unit {
+      log::trace!("frame of {} bytes exceeds the MTU of the egress port", frame.len());
+      return false;
+   }
+
+   match device.transmit(timestamp) {
+      None => false,
+      Some(tx) => {
+         tx.consume(frame.len(), |buf| buf.copy_from_slice(frame));
+         true
+      }
+   }
+}
+
+impl<U: Device, D: Device> Device for Bridge<U, D> {
+   type RxToken<'a>
+      = LocalRxToken<'a>
+   where
+      Self: 'a;
+   type TxToken<'a>
+      = LocalTxToken<'a, U, D>
+   where
+      Self: 'a;
+
+   fn capabilities(&self) -> DeviceCapabilities {
+      let mut cap = DeviceCapabilities::default();
+      cap.medium = Medium::Ethernet;
+      cap.max_transmission_unit = self
+         .usb
+         .capabilities()
+         .max_transmission_unit
+         .min(self.other.capabilities().max_transmission_unit)
+         .min(ETH_FRAME_SIZE);
+      cap.max_burst_size = Some(1);
+
+      cap
+   }
+
+   fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+      // Forward frames one by one, until one for the local endpoint arrives
+      while self.local.as_ref()?.rx_len.is_none() {
+         if !self.forward_from_usb(timestamp) && !self.forward_from_other(timestamp) {
+            break;
+         }
+      }
+
+      let local = self.local.as_mut()?;
+      let len = local.rx_len?;
+      Some((
+         LocalRxToken {
+            frame: &local.rx[..len],
+            rx_len: &mut local.rx_len,
+         },
+         LocalTxToken {
+            usb: &mut self.usb,
+            other: &mut self.other,
+            fdb: &mut self.fdb,
+            tx: &mut local.tx,
+            timestamp,
+         },
+      ))
+   }
+
+   fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
+      let local = self.local.as_mut()?;
+      Some(LocalTxToken {
+         usb: &mut self.usb,
+         other: &mut self.other,
+         fdb: &mut self.fdb,
+         tx: &mut local.tx,
+         timestamp,
+      })
+   }
+}
+
+pub struct LocalRxToken<'a> {
+   frame: &'a [u8],
+   rx_len: &'a mut Option<usize>,
+}
+
+impl RxToken for LocalRxToken<'_> {
+   fn consume<R, F>(self, f: F) -> R
+   where
+      F: FnOnce(&[u8]) -> R,
+   {
+      let result = f(self.frame);
+      *self.rx_len = None;
+      result
+   }
+}
+
+pub struct LocalTxToken<'a, U: Device, D: Device> {
+   usb: &'a mut U,
+   other: &'a mut D,
+   fdb: &'a mut Fdb,
+   tx: &'a mut [u8; ETH_FRAME_SIZE],
+   timestamp: Instant,
+}
+
+impl<U: Device, D: Device> TxToken for LocalTxToken<'_, U, D> {
+   fn consume<R, F>(self, len: usize, f: F) -> R
+   where
+      F: FnOnce(&mut [u8]) -> R,
+   {
+      let frame = &mut self.tx[..len];
+      let result = f(frame);
+
+      // A busy port drops the frame, like any other ethernet link would
+      let (to_usb, to_other) = match target(Port::Local, frame, self.fdb, None, self.timestamp) {
+         Target::Drop => (false, false),
+         Target::Flood => (true, true),
+         Target::Port(port) => (port == Port::Usb, port == Port::Other),
+      };
+      if to_usb && !transmit(self.usb, frame, self.timestamp) {
+         log::trace!("usb port can not take the frame, dropping local frame");
+      }
+      if to_other && !transmit(self.other, frame, self.timestamp) {
+         log::trace!("other port can not take the frame, dropping local frame");
+      }
+      result
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   extern crate std;
+   use std::{collections::VecDeque, vec::Vec};
+
+   const HOST: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 1]);
+   const REMOTE: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 2]);
+   const LOCAL: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 3]);
+
+   /// A device, whose received and sent frames are kept in queues
+   #[derive(Default)]
+   struct QueueDevice {
+      rx: VecDeque<Vec<u8>>,
+      tx: Vec<Vec<u8>>,
+   }
+
+   struct QueueRxToken(Vec<u8>);
+
+   impl RxToken for QueueRxToken {
+      fn consume<R, F>(self, f: F) -> R
+      where
+         F: FnOnce(&[u8]) -> R,
+      {
+         f(&self.0)
+      }
+   }
+
+   struct QueueTxToken<'a>(&'a mut Vec<Vec<u8>>);
+
+   impl TxToken for QueueTxToken<'_> {
+      fn consume<R, F>(self, len: usize, f: F) -> R
+      where
+         F: FnOnce(&mut [u8]) -> R,
+      {
+         let mut frame = std::vec![0; len];
+         let result = f(&mut frame);
+         self.0.push(frame);
+         result
+      }
+   }
+
+   impl Device for QueueDevice {
+      type RxToken<'a> = QueueRxToken;
+      type TxToken<'a> = QueueTxToken<'a>;
+
+      fn capabilities(&self) -> DeviceCapabilities {
+         let mut cap = DeviceCapabilities::default();
+         cap.medium = Medium::Ethernet;
+         cap.max_transmission_unit = ETH_FRAME_SIZE;
+         cap
+      }
+
+      fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+         let frame = self.rx.pop_front()?;
+         Some((QueueRxToken(frame), QueueTxToken(&mut self.tx)))
+      }
+
+      fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+         Some(QueueTxToken(&mut self.tx))
+      }
+   }
+
+   fn frame(dst: EthernetAddress, src: EthernetAddress) -> Vec<u8> {
+      let mut buf = std::vec![0; 60];
+      let mut frame = EthernetFrame::new_unchecked(&mut buf);
+      frame.set_dst_addr(dst);
+      frame.set_src_addr(src);
+      buf
+   }
+
+   #[test]
+   fn addresses_are_learned() {
+      let mut bridge = Bridge::new(QueueDevice::default(), QueueDevice::default());
+      let now = Instant::from_secs(1);
+
+      // The remote address is unknown, so the frame is flooded
+      bridge.usb().rx.push_back(frame(REMOTE, HOST));
+      assert!(bridge.poll(now));
+      assert_eq!(bridge.other().tx.len(), 1);
+
+      // The reply goes to the learned port
+      bridge.other().rx.push_back(frame(HOST, REMOTE));
+      bridge.poll(now);
+      assert_eq!(bridge.usb().tx.len(), 1);
+
+      // Frames for addresses behind the ingress port are filtered
+      bridge.other().rx.push_back(frame(REMOTE, REMOTE));
+      bridge.poll(now);
+      assert_eq!(bridge.usb().tx.len(), 1);
+
+      // Once aged, the address is flooded again
+      bridge.other().rx.push_back(frame(HOST, EthernetAddress([0x02, 0, 0, 0, 0, 9])));
+      bridge.poll(now + AGEING_TIME);
+      assert_eq!(bridge.usb().tx.len(), 2);
+      assert!(!bridge.poll(now + AGEING_TIME));
+   }
+
+   #[test]
+   fn frames_exceeding_the_mtu_are_dropped() {
+      let mut bridge = Bridge::new(QueueDevice::default(), QueueDevice::default());
+      let now = Instant::from_secs(1);
+
+      // E.g. a VLAN tagged frame, that does not fit into the transmit buffers of the USB side
+      let mut vlan = frame(HOST, REMOTE);
+      vlan.resize(ETH_FRAME_SIZE + 4, 0);
+      bridge.other().rx.push_back(vlan);
+      assert!(bridge.poll(now));
+      assert!(bridge.usb().tx.is_empty());
+      assert_eq!(bridge.dropped_frames(), 1);
+   }
+
+   #[test]
+   fn local_endpoint_receives_and_sends() {
+      let mut bridge = Bridge::with_local(QueueDevice::default(), QueueDevice::default(), LOCAL);
+      let now = Instant::from_secs(1);
+
+      // Broadcasts reach the other port and the local endpoint
+      bridge.usb().rx.push_back(frame(EthernetAddress::BROADCAST, HOST));
+      bridge.usb().rx.push_back(frame(LOCAL, HOST));
+      let (rx, _) = bridge.receive(now).unwrap();
+      assert_eq!(rx.consume(|frame| EthernetFrame::new_unchecked(frame).dst_addr()), EthernetAddress::BROADCAST);
+      let (rx, _) = bridge.receive(now).unwrap();
+      assert_eq!(rx.consume(|frame| EthernetFrame::new_unchecked(frame).dst_addr()), LOCAL);
+      assert_eq!(bridge.other().tx.len(), 1);
+      assert!(bridge.receive(now).is_none());
+
+      // The reply of the local endpoint only goes to the port of the host
+      let tx = bridge.transmit(now).unwrap();
+      tx.consume(60, |buf| buf.copy_from_slice(&frame(HOST, LOCAL)));
+      assert_eq!(bridge.usb().tx.len(), 1);
+      assert_eq!(bridge.other().tx.len(), 1);
+   }
+}
@@ -1,14 +1,23 @@
 //! This module implements the transmit and receive buffers.
-//! There is the possibility to use synchronization mechanisms, to facilitate
-//! implementation of `smoltcp`.
-
-use crate::{EP_PKG_USIZE, ETH_FRAME_SIZE};
+//! The frames are passed between the USB side and the application side through
+//! [`Queue`]s, such that both sides can be driven from different contexts.
+
+use crate::{
+   queue::{Consumer, Producer, Queue},
+   EP_PKG_USIZE, ETH_FRAME_SIZE, ETH_MIN_FRAME_SIZE,
+};
+use core::{
+   sync::atomic::{AtomicU32, Ordering},
+   task::Waker,
+};
 
 #[derive(Debug, Clone)]
 pub struct RxBufInner {
    buf: [u8; ETH_FRAME_SIZE],
    idx: usize,
    complete: bool,
+   discarding: bool,
+   timestamp: Option<u64>,
 }
 
 impl RxBufInner {
@@ -22,6 +31,18 @@ impl RxBufInner {
    pub fn reset(&mut self) {
       self.idx = 0;
       self.complete = false;
+      self.timestamp = None;
+   }
+
+   /// Returns the time in microseconds, at which the frame has been received completely.
+   /// Returns `None`, if the frame is not complete or no clock is available.
+   pub fn timestamp(&self) -> Option<u64> {
+      self.timestamp
+   }
+
+   /// Sets the receive timestamp of the frame
+   pub fn set_timestamp(&mut self, timestamp: Option<u64>) {
+      self.timestamp = timestamp;
    }
 
    /// If a frame is ready, it is returned.
@@ -36,6 +57,11 @@ impl RxBufInner {
       }
    }
 
+   /// Returns the number of bytes of the frame received so far
+   pub fn received_len(&self) -> usize {
+      self.idx
+   }
+
    /// Returns mutably the part of the buffer that
    /// is not written yet, such that a data packet can be copied into it
    /// NOTE: The buffer part left can be empty
@@ -56,6 +82,33 @@ impl RxBufInner {
          self.complete = true;
       }
    }
+
+   /// Returns `true`, if the frame currently arriving from the host gets discarded
+   pub fn is_discarding(&self) -> bool {
+      self.discarding
+   }
+
+   /// Discard the frame currently arriving from the host.
+   /// NOTE: This does not affect a frame, that is already in the buffer
+   pub fn start_discarding(&mut self) {
+      self.discarding = true;
+   }
+
+   /// Stops discarding, e.g. since the rest of the discarded frame never arrives after a bus reset
+   pub fn stop_discarding(&mut self) {
+      self.discarding = false;
+   }
+
+   /// After reading a packet of a discarded frame, the discarding needs to be advanced.
+   /// Returns `true`, if the discarded frame has been read completely
+   pub fn advance_discarding(&mut self, num_bytes: usize) -> bool {
+      if num_bytes < EP_PKG_USIZE {
+         self.discarding = false;
+         true
+      } else {
+         false
+      }
+   }
 }
 
 #[derive(Debug, Clone)]
@@ -77,6 +130,11 @@ impl TxBufInner {
       self.len = 0;
    }
 
+   /// Starts sending the frame over from its beginning
+   pub fn rewind(&mut self) {
+      self.idx = 0;
+   }
+
    /// Get the section of the frame to be sent next
    pub fn try_get_packet(&self) -> Option<&[u8]> {
       match self.is_sending() {
@@ -105,113 +163,366 @@ impl TxBufInner {
 
    /// Tries to mutably aqcuire the buffer in order to copy
    /// the data into it.
+   /// If `pad` is set, frames shorter than [`ETH_MIN_FRAME_SIZE`] are zero padded.
    /// Returns none, if there is already a frame in transit.
-   pub fn try_send_frame(&mut self, len: usize) -> Option<&mut [u8]> {
+   pub fn try_send_frame(&mut self, len: usize, pad: bool) -> Option<&mut [u8]> {
       match self.is_sending() {
          true => None,
          false => {
-            self.len = len;
+            self.len = match pad {
+               true => len.max(ETH_MIN_FRAME_SIZE),
+               false => len,
+            };
             self.idx = 0;
 
+            // Zero the padding, the frame itself is filled in by the caller
+            for byte in self.buf[len..self.len].iter_mut() {
+               *byte = 0;
+            }
+
             Some(&mut self.buf[..len])
          }
       }
    }
 }
 
-#[cfg(not(feature = "smoltcp"))]
-pub use unsync::*;
+/// What to do with a frame arriving from the host, while the receive queue
+/// is full of frames, that have not been processed by the application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RxOverflowPolicy {
+   /// Stop reading from the host, until a waiting frame has been processed.
+   /// The OUT pipe stalls in the meantime.
+   #[default]
+   Backpressure,
+   /// Drop the incoming frame.
+   DropNewest,
+   /// Drop the oldest waiting frame and queue the incoming frame instead.
+   OverwriteOldest,
+}
 
-#[cfg(not(feature = "smoltcp"))]
-mod unsync {
-   use super::*;
+/// What to do with frames, the application wants to send, while the bus is suspended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TxSuspendPolicy {
+   /// Refuse new frames and drop the queued frames, once the bus is suspended.
+   /// The dropped frames are counted and raise [`Event::FrameDropped`](crate::Event::FrameDropped).
+   #[default]
+   Refuse,
+   /// Queue frames, while there is room, and send them once the bus has been resumed.
+   Park,
+   /// Like [`Park`](TxSuspendPolicy::Park), but also request a remote wakeup from the host
+   /// through the [`RemoteWakeup`](crate::RemoteWakeup) hook.
+   Wakeup,
+}
 
-   /// Structure holds and manages the receive side.
-   #[derive(Debug)]
-   pub struct RxBuf(RxBufInner);
+/// Structure holds and manages the receive side.
+#[derive(Debug)]
+pub struct RxBuf {
+   queue: Queue<RxBufInner>,
+   policy: RxOverflowPolicy,
+   max_frame_size: usize,
+   dropped: AtomicU32,
+   invalid: AtomicU32,
+   waker: WakerCell,
+}
 
-   impl RxBuf {
-      pub fn new() -> Self {
-         Self(RxBufInner {
+impl RxBuf {
+   pub fn new() -> Self {
+      Self {
+         queue: Queue::new(|| RxBufInner {
             buf: [0; ETH_FRAME_SIZE],
             idx: 0,
             complete: false,
-         })
+            discarding: false,
+            timestamp: None,
+         }),
+         policy: RxOverflowPolicy::default(),
+         max_frame_size: ETH_FRAME_SIZE,
+         dropped: AtomicU32::new(0),
+         invalid: AtomicU32::new(0),
+         waker: WakerCell::new(),
       }
+   }
 
-      pub fn lock_mut(&mut self) -> Option<&mut RxBufInner> {
-         Some(&mut self.0)
+   /// Returns the producer side of the queue, which is used by the USB side
+   pub fn producer(&self) -> Option<RxProducer<'_>> {
+      self.queue.producer()
+   }
+
+   /// Returns the consumer side of the queue, which is used by the application side
+   pub fn consumer(&self) -> Option<RxConsumer<'_>> {
+      Some(RxConsumer {
+         consumer: self.queue.consumer()?,
+         rx_buf: self,
+      })
+   }
+
+   /// Queues a completely received frame, that has been held back, since the queue was full.
+   ///
+   /// With the [`RxOverflowPolicy::Backpressure`], the USB side stops reading from the host
+   /// in this case, so the frame is queued as soon as there is room again.
+   /// Returns `true`, if a frame has been queued.
+   pub fn commit_pending(&self) -> bool {
+      let mut producer = match self.producer() {
+         None => return false,
+         Some(producer) => producer,
+      };
+
+      if !producer.slot().frame_complete() || !producer.commit() {
+         return false;
       }
+
+      // The slot of the next frame may still hold an old frame
+      producer.slot_mut().reset();
+      self.wake();
+      true
    }
 
-   /// Stucture holds and manages the send side
-   #[derive(Debug)]
-   pub struct TxBuf(TxBufInner);
+   /// Registers the waker of a task, that waits for a frame to be received
+   pub fn register_waker(&self, waker: &Waker) {
+      self.waker.register(waker);
+   }
 
-   impl TxBuf {
-      pub fn new() -> Self {
-         Self(TxBufInner {
+   /// Wakes the task waiting for a frame, if any
+   pub fn wake(&self) {
+      self.waker.wake();
+   }
+
+   pub fn policy(&self) -> RxOverflowPolicy {
+      self.policy
+   }
+
+   pub fn set_policy(&mut self, policy: RxOverflowPolicy) {
+      self.policy = policy;
+   }
+
+   pub fn max_frame_size(&self) -> usize {
+      self.max_frame_size
+   }
+
+   pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+      self.max_frame_size = max_frame_size.min(ETH_FRAME_SIZE);
+   }
+
+   /// Returns the number of frames, that have been dropped so far
+   pub fn dropped_frames(&self) -> u32 {
+      self.dropped.load(Ordering::Relaxed)
+   }
+
+   /// Counts a dropped frame.
+   pub fn count_dropped(&self) {
+      increment(&self.dropped);
+   }
+
+   /// Returns the number of frames, that have been rejected as invalid so far
+   pub fn invalid_frames(&self) -> u32 {
+      self.invalid.load(Ordering::Relaxed)
+   }
+
+   /// Counts an invalid frame.
+   pub fn count_invalid(&self) {
+      increment(&self.invalid);
+   }
+}
+
+/// Increments a counter.
+/// NOTE: Only the USB side writes to the counters, therefore we do not need an atomic increment.
+fn increment(counter: &AtomicU32) {
+   let value = counter.load(Ordering::Relaxed);
+   counter.store(value.wrapping_add(1), Ordering::Relaxed);
+}
+
+/// Stucture holds and manages the send side
+#[derive(Debug)]
+pub struct TxBuf {
+   queue: Queue<TxBufInner>,
+   pad: bool,
+   suspend_policy: TxSuspendPolicy,
+   dropped: AtomicU32,
+   waker: WakerCell,
+}
+
+impl TxBuf {
+   pub fn new() -> Self {
+      Self {
+         queue: Queue::new(|| TxBufInner {
             buf: [0; ETH_FRAME_SIZE],
             idx: 0,
             len: 0,
-         })
+         }),
+         pad: false,
+         suspend_policy: TxSuspendPolicy::default(),
+         dropped: AtomicU32::new(0),
+         waker: WakerCell::new(),
       }
+   }
 
-      pub fn lock_mut(&mut self) -> Option<&mut TxBufInner> {
-         Some(&mut self.0)
-      }
+   /// Returns the producer side of the queue, which is used by the application side
+   pub fn producer(&self) -> Option<TxProducer<'_>> {
+      self.queue.producer()
+   }
+
+   /// Returns the consumer side of the queue, which is used by the USB side
+   pub fn consumer(&self) -> Option<TxConsumer<'_>> {
+      self.queue.consumer()
+   }
+
+   /// Registers the waker of a task, that waits for room in the queue
+   pub fn register_waker(&self, waker: &Waker) {
+      self.waker.register(waker);
+   }
+
+   /// Wakes the task waiting for room in the queue, if any
+   pub fn wake(&self) {
+      self.waker.wake();
+   }
+
+   /// Returns `true`, if short frames are padded to [`ETH_MIN_FRAME_SIZE`]
+   pub fn pad(&self) -> bool {
+      self.pad
+   }
+
+   pub fn set_pad(&mut self, pad: bool) {
+      self.pad = pad;
+   }
+
+   pub fn suspend_policy(&self) -> TxSuspendPolicy {
+      self.suspend_policy
+   }
+
+   pub fn set_suspend_policy(&mut self, policy: TxSuspendPolicy) {
+      self.suspend_policy = policy;
+   }
+
+   /// Returns the number of queued frames, that have been dropped so far
+   pub fn dropped_frames(&self) -> u32 {
+      self.dropped.load(Ordering::Relaxed)
+   }
+
+   /// Counts a dropped frame.
+   pub fn count_dropped(&self) {
+      increment(&self.dropped);
    }
 }
 
-#[cfg(feature = "smoltcp")]
-pub use sync::*;
+pub type RxProducer<'a> = Producer<'a, RxBufInner>;
 
-#[cfg(feature = "smoltcp")]
-mod sync {
-   use super::*;
-   use crate::lock::{Guard, Lock, LockHandle};
+/// The consumer side of the receive queue.
+///
+/// Popping a frame makes room for a frame, that is held back by the USB side.
+pub struct RxConsumer<'a> {
+   consumer: Consumer<'a, RxBufInner>,
+   rx_buf: &'a RxBuf,
+}
+
+impl RxConsumer<'_> {
+   /// Returns the oldest frame, or `None` if the queue is empty
+   pub fn peek(&self) -> Option<&RxBufInner> {
+      self.consumer.peek()
+   }
 
-   /// Structure holds and manages the receive side.
+   /// Returns the oldest frame, or `None` if the queue is empty
+   pub fn peek_mut(&mut self) -> Option<&mut RxBufInner> {
+      self.consumer.peek_mut()
+   }
+
+   /// Frees the oldest frame and queues a held back frame in its place
+   pub fn pop(&mut self) {
+      self.consumer.pop();
+      self.rx_buf.commit_pending();
+   }
+}
+pub type TxProducer<'a> = Producer<'a, TxBufInner>;
+pub type TxConsumer<'a> = Consumer<'a, TxBufInner>;
+
+#[cfg(not(feature = "sync"))]
+pub use unsync::*;
+
+#[cfg(not(feature = "sync"))]
+mod unsync {
+   use core::{
+      cell::{RefCell, RefMut},
+      task::Waker,
+   };
+
+   pub type BufLock<T> = RefCell<T>;
+   pub type BufGuard<'a, T> = RefMut<'a, T>;
+
+   pub fn try_lock<T>(lock: &BufLock<T>) -> Option<BufGuard<'_, T>> {
+      lock.try_borrow_mut().ok()
+   }
+
+   /// Holds the waker of a task, that waits for the other side
    #[derive(Debug)]
-   pub struct RxBuf(Lock<RxBufInner>);
+   pub struct WakerCell(RefCell<Option<Waker>>);
 
-   impl RxBuf {
+   impl WakerCell {
       pub fn new() -> Self {
-         Self(Lock::new(RxBufInner {
-            buf: [0; ETH_FRAME_SIZE],
-            idx: 0,
-            complete: false,
-         }))
+         Self(RefCell::new(None))
       }
 
-      pub fn lock_mut(&self) -> Option<Guard<RxBufInner>> {
-         self.0.try_lock()
+      pub fn register(&self, waker: &Waker) {
+         let mut slot = self.0.borrow_mut();
+         if !slot.as_ref().is_some_and(|old| old.will_wake(waker)) {
+            *slot = Some(waker.clone());
+         }
       }
 
-      pub fn get_handle(&self) -> LockHandle<RxBufInner> {
-         self.0.get_handle()
+      pub fn wake(&self) {
+         let waker = self.0.borrow_mut().take();
+         if let Some(waker) = waker {
+            waker.wake();
+         }
       }
    }
+}
 
-   /// Stucture holds and manages the send side
-   #[derive(Debug)]
-   pub struct TxBuf(Lock<TxBufInner>);
+#[cfg(feature = "sync")]
+pub use sync::*;
+
+#[cfg(feature = "sync")]
+mod sync {
+   use crate::lock::{Guard, Lock};
+   use core::{cell::RefCell, fmt, task::Waker};
+   use critical_section::Mutex;
+
+   pub type BufLock<T> = Lock<T>;
+   pub type BufGuard<'a, T> = Guard<'a, T>;
+
+   pub fn try_lock<T>(lock: &BufLock<T>) -> Option<BufGuard<'_, T>> {
+      lock.try_lock()
+   }
+
+   /// Holds the waker of a task, that waits for the other side
+   pub struct WakerCell(Mutex<RefCell<Option<Waker>>>);
 
-   impl TxBuf {
+   impl WakerCell {
       pub fn new() -> Self {
-         Self(Lock::new(TxBufInner {
-            buf: [0; ETH_FRAME_SIZE],
-            idx: 0,
-            len: 0,
-         }))
+         Self(Mutex::new(RefCell::new(None)))
       }
 
-      pub fn lock_mut(&self) -> Option<Guard<TxBufInner>> {
-         self.0.try_lock()
+      pub fn register(&self, waker: &Waker) {
+         // The old waker is dropped outside of the critical section
+         let _old = critical_section::with(|cs| {
+            let mut slot = self.0.borrow_ref_mut(cs);
+            match slot.as_ref().is_some_and(|old| old.will_wake(waker)) {
+               true => None,
+               false => slot.replace(waker.clone()),
+            }
+         });
       }
 
-      pub fn get_handle(&self) -> LockHandle<TxBufInner> {
-         self.0.get_handle()
+      pub fn wake(&self) {
+         // The waker is woken outside of the critical section
+         let waker = critical_section::with(|cs| self.0.borrow_ref_mut(cs).take());
+         if let Some(waker) = waker {
+            waker.wake();
+         }
+      }
+   }
+
+   impl fmt::Debug for WakerCell {
+      fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+         f.debug_struct("WakerCell").finish()
       }
    }
 }
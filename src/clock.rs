@@ -0,0 +1,13 @@
+//! This module contains the [`Clock`] trait, which is used to timestamp received frames.
+
+/// A source of time, provided by the user.
+///
+/// If a [`Clock`] is set on the [`UsbEthernetDevice`](crate::UsbEthernetDevice),
+/// every received frame is timestamped at the moment its last USB packet arrives.
+pub trait Clock: Sync {
+   /// Returns the current time in microseconds.
+   ///
+   /// The epoch is up to the implementation, but it should be the same
+   /// as the one used by the network stack, if the timestamps are passed on to it.
+   fn now_micros(&self) -> u64;
+}
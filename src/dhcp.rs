@@ -0,0 +1,486 @@
+//! This module implements a minimal DHCPv4 server, that hands out a single address
+//! to the host on the other side of the USB link.
+//!
+//! It works on raw ethernet frames, such that it can be used without a network stack.
+//! Frames received from the host are passed to [`DhcpServer::process`] first, which answers
+//! DISCOVER and REQUEST messages through the [`FrameSender`].
+//! With a network stack, the messages received on a UDP socket bound to port 67 are passed
+//! to [`DhcpServer::process_message`] instead.
+
+use crate::{
+   wire::{
+      emit_udp_frame, EthernetHeader, Ipv4Header, UdpHeader, ETHERTYPE_IPV4, ETH_ADDR_BROADCAST,
+      IPV4_ADDR_BROADCAST, IP_PROTO_UDP, UDP_PAYLOAD_OFFSET,
+   },
+   FrameSender,
+};
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+// BOOTP message layout, RFC 2131
+const OP_BOOTREQUEST: u8 = 1;
+const OP_BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTIONS_OFFSET: usize = 240;
+
+/// Some clients ignore replies shorter than a BOOTP message
+const MIN_MESSAGE_SIZE: usize = 300;
+
+// DHCP options, RFC 2132
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS_SERVER: u8 = 6;
+const OPT_REQUESTED_ADDR: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_END: u8 = 255;
+
+// DHCP message types
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPDECLINE: u8 = 4;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+const DHCPRELEASE: u8 = 7;
+
+/// Configures the [`DhcpServer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhcpConfig {
+   /// The mac address, the replies are sent from
+   pub server_mac: [u8; 6],
+   /// The IPv4 address of the device, which also identifies the server
+   pub server_addr: [u8; 4],
+   /// The IPv4 address, that is handed out to the host
+   pub client_addr: [u8; 4],
+   pub netmask: [u8; 4],
+   /// The default gateway, that is handed out to the host, if any
+   pub router: Option<[u8; 4]>,
+   /// The DNS server, that is handed out to the host, if any
+   pub dns_server: Option<[u8; 4]>,
+   /// The lease time in seconds
+   pub lease_time: u32,
+}
+
+/// The lease, that has been acknowledged to a host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DhcpLease {
+   /// The mac address of the host holding the lease
+   pub client_mac: [u8; 6],
+   /// The time in microseconds, at which the lease expires
+   pub expires_at: u64,
+}
+
+/// A DHCPv4 server with a single lease.
+#[derive(Debug, Clone)]
+pub struct DhcpServer {
+   config: DhcpConfig,
+   lease: Option<DhcpLease>,
+}
+
+/// The parts of a request, that are relevant to the server
+struct DhcpRequest {
+   message_type: u8,
+   xid: [u8; 4],
+   flags: u16,
+   client_mac: [u8; 6],
+   client_addr: [u8; 4],
+   requested_addr: Option<[u8; 4]>,
+   server_id: Option<[u8; 4]>,
+}
+
+impl DhcpServer {
+   pub fn new(config: DhcpConfig) -> Self {
+      Self { config, lease: None }
+   }
+
+   /// Returns the current lease, if it has not expired at `now`, in microseconds
+   pub fn lease(&self, now: u64) -> Option<DhcpLease> {
+      self.lease.filter(|lease| lease.expires_at > now)
+   }
+
+   /// Forgets the lease, such that the address is offered again.
+   ///
+   /// This should be called after a bus reset, see [`Event::Reset`](crate::Event::Reset),
+   /// since the host might have been replaced in the meantime.
+   pub fn reset(&mut self) {
+      self.lease = None;
+   }
+
+   /// Processes a frame received from the host.
+   ///
+   /// `now` is the current time in microseconds, like the one of a [`Clock`](crate::Clock).
+   ///
+   /// # Returns
+   /// - `true`, if the frame was a DHCP message, which should not be passed on
+   /// - `false` otherwise
+   pub fn process(&mut self, frame: &[u8], now: u64, sender: &mut FrameSender) -> bool {
+      let mut reply = [0; UDP_PAYLOAD_OFFSET + MIN_MESSAGE_SIZE];
+      match self.respond(frame, now, &mut reply) {
+         None => false,
+         Some(0) => true,
+         Some(len) => {
+            if !sender.try_send_frame(len, |buf| buf.copy_from_slice(&reply[..len])) {
+               log::debug!("transmit queue full, dropping dhcp reply");
+            }
+            true
+         }
+      }
+   }
+
+   /// Processes a DHCP message received on UDP port 67, e.g. through a `smoltcp` socket.
+   ///
+   /// If the message needs a reply, it is written into `reply`, which needs to hold at least
+   /// 300 bytes, and its length is returned.
+   /// The reply has to be sent to the broadcast address `255.255.255.255`, port 68,
+   /// since the host can not answer ARP requests for its address yet.
+   /// Returns `None` without processing the message, if `reply` is too short.
+   pub fn process_message(&mut self, message: &[u8], now: u64, reply: &mut [u8]) -> Option<usize> {
+      if reply.len() < MIN_MESSAGE_SIZE {
+         log::warn!("reply buffer of {} bytes is too short for a dhcp message", reply.len());
+         return None;
+      }
+      let request = parse_request(message)?;
+      let reply_type = self.handle(&request, now)?;
+      self.emit_message(&request, reply_type, &mut reply[..MIN_MESSAGE_SIZE]);
+      Some(MIN_MESSAGE_SIZE)
+   }
+
+   /// Writes the reply to `frame` into `reply`.
+   ///
+   /// Returns `None`, if the frame is not a DHCP message and `Some(0)`,
+   /// if it is one, that does not need a reply.
+   fn respond(&mut self, frame: &[u8], now: u64, reply: &mut [u8]) -> Option<usize> {
+      let (eth, packet) = EthernetHeader::parse(frame)?;
+      if eth.ethertype != ETHERTYPE_IPV4 {
+         return None;
+      }
+
+      let (ip, datagram) = Ipv4Header::parse(packet)?;
+      if ip.protocol != IP_PROTO_UDP {
+         return None;
+      }
+
+      let (udp, message) = UdpHeader::parse(&ip, datagram)?;
+      if udp.dst_port != SERVER_PORT {
+         return None;
+      }
+
+      let request = match parse_request(message) {
+         None => {
+            log::debug!("ignoring malformed dhcp message");
+            return Some(0);
+         }
+         Some(request) => request,
+      };
+
+      let reply_type = match self.handle(&request, now) {
+         None => return Some(0),
+         Some(reply_type) => reply_type,
+      };
+      Some(self.emit_reply(&request, reply_type, reply))
+   }
+
+   /// Updates the lease and returns the type of the reply, if any
+   fn handle(&mut self, request: &DhcpRequest, now: u64) -> Option<u8> {
+      // The address is available, if there is no lease or the host already holds it
+      let available = self.lease(now).map_or(true, |lease| lease.client_mac == request.client_mac);
+
+      match request.message_type {
+         DHCPDISCOVER if available => {
+            log::debug!("offering {:?} to {:02x?}", self.config.client_addr, request.client_mac);
+            Some(DHCPOFFER)
+         }
+         DHCPDISCOVER => {
+            log::debug!("address is leased to another host, not offering");
+            None
+         }
+         DHCPREQUEST => {
+            // The host selected another server
+            if request.server_id.is_some_and(|id| id != self.config.server_addr) {
+               return None;
+            }
+
+            // The address is requested in an option while selecting,
+            // and in the message itself while renewing
+            let addr = request.requested_addr.unwrap_or(request.client_addr);
+            match available && addr == self.config.client_addr {
+               true => {
+                  log::debug!("leasing {:?} to {:02x?}", addr, request.client_mac);
+                  self.lease = Some(DhcpLease {
+                     client_mac: request.client_mac,
+                     expires_at: now + self.config.lease_time as u64 * 1_000_000,
+                  });
+                  Some(DHCPACK)
+               }
+               false => {
+                  log::debug!("refusing request for {:?}", addr);
+                  Some(DHCPNAK)
+               }
+            }
+         }
+         DHCPDECLINE | DHCPRELEASE => {
+            if self.lease.is_some_and(|lease| lease.client_mac == request.client_mac) {
+               log::debug!("lease released by {:02x?}", request.client_mac);
+               self.lease = None;
+            }
+            None
+         }
+         _ => None,
+      }
+   }
+
+   /// Writes the reply frame into `buf` and returns its length
+   fn emit_reply(&self, request: &DhcpRequest, reply_type: u8, buf: &mut [u8]) -> usize {
+      self.emit_message(request, reply_type, &mut buf[UDP_PAYLOAD_OFFSET..UDP_PAYLOAD_OFFSET + MIN_MESSAGE_SIZE]);
+
+      // A NAK, as well as replies to hosts, that can not receive unicasts yet, are broadcast
+      let broadcast = reply_type == DHCPNAK || request.flags & FLAG_BROADCAST != 0;
+      let (dst_mac, dst_addr) = match broadcast {
+         true => (ETH_ADDR_BROADCAST, IPV4_ADDR_BROADCAST),
+         false => (request.client_mac, self.config.client_addr),
+      };
+
+      let eth = EthernetHeader {
+         dst: dst_mac,
+         src: self.config.server_mac,
+         ethertype: ETHERTYPE_IPV4,
+      };
+      let ip = Ipv4Header {
+         src: self.config.server_addr,
+         dst: dst_addr,
+         protocol: IP_PROTO_UDP,
+      };
+      let udp = UdpHeader {
+         src_port: SERVER_PORT,
+         dst_port: CLIENT_PORT,
+      };
+      emit_udp_frame(buf, &eth, &ip, &udp, MIN_MESSAGE_SIZE)
+   }
+
+   /// Writes the reply message into `message`
+   fn emit_message(&self, request: &DhcpRequest, reply_type: u8, message: &mut [u8]) {
+      message.fill(0);
+
+      message[0] = OP_BOOTREPLY;
+      message[1] = HTYPE_ETHERNET;
+      message[2] = 6;
+      message[4..8].copy_from_slice(&request.xid);
+      message[10..12].copy_from_slice(&request.flags.to_be_bytes());
+      if reply_type != DHCPNAK {
+         message[16..20].copy_from_slice(&self.config.client_addr);
+      }
+      message[28..34].copy_from_slice(&request.client_mac);
+      message[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+      let mut options = OptionWriter {
+         buf: &mut message[OPTIONS_OFFSET..],
+         idx: 0,
+      };
+      options.write(OPT_MESSAGE_TYPE, &[reply_type]);
+      options.write(OPT_SERVER_ID, &self.config.server_addr);
+      if reply_type != DHCPNAK {
+         options.write(OPT_LEASE_TIME, &self.config.lease_time.to_be_bytes());
+         options.write(OPT_SUBNET_MASK, &self.config.netmask);
+         if let Some(router) = self.config.router {
+            options.write(OPT_ROUTER, &router);
+         }
+         if let Some(dns_server) = self.config.dns_server {
+            options.write(OPT_DNS_SERVER, &dns_server);
+         }
+      }
+      options.write(OPT_END, &[]);
+   }
+}
+
+/// Parses a DHCP request
+fn parse_request(message: &[u8]) -> Option<DhcpRequest> {
+   if message.len() < OPTIONS_OFFSET
+      || message[0] != OP_BOOTREQUEST
+      || message[1] != HTYPE_ETHERNET
+      || message[2] != 6
+      || message[236..240] != MAGIC_COOKIE
+   {
+      return None;
+   }
+
+   let mut request = DhcpRequest {
+      message_type: 0,
+      xid: [0; 4],
+      flags: u16::from_be_bytes([message[10], message[11]]),
+      client_mac: [0; 6],
+      client_addr: [0; 4],
+      requested_addr: None,
+      server_id: None,
+   };
+   request.xid.copy_from_slice(&message[4..8]);
+   request.client_addr.copy_from_slice(&message[12..16]);
+   request.client_mac.copy_from_slice(&message[28..34]);
+
+   let mut options = &message[OPTIONS_OFFSET..];
+   loop {
+      match options {
+         [] | [OPT_END, ..] => break,
+         [OPT_PAD, rest @ ..] => options = rest,
+         [code, len, rest @ ..] if rest.len() >= *len as usize => {
+            let (value, rest) = rest.split_at(*len as usize);
+            match (*code, value) {
+               (OPT_MESSAGE_TYPE, [message_type]) => request.message_type = *message_type,
+               (OPT_REQUESTED_ADDR, [a, b, c, d]) => request.requested_addr = Some([*a, *b, *c, *d]),
+               (OPT_SERVER_ID, [a, b, c, d]) => request.server_id = Some([*a, *b, *c, *d]),
+               _ => (),
+            }
+            options = rest;
+         }
+         _ => return None,
+      }
+   }
+
+   match request.message_type {
+      0 => None,
+      _ => Some(request),
+   }
+}
+
+/// Appends DHCP options to a buffer
+struct OptionWriter<'a> {
+   buf: &'a mut [u8],
+   idx: usize,
+}
+
+impl OptionWriter<'_> {
+   fn write(&mut self, code: u8, value: &[u8]) {
+      self.buf[self.idx] = code;
+      if code == OPT_END {
+         self.idx += 1;
+         return;
+      }
+
+      self.buf[self.idx + 1] = value.len() as u8;
+      self.buf[self.idx + 2..self.idx + 2 + value.len()].copy_from_slice(value);
+      self.idx += 2 + value.len();
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::ETH_HEADER_SIZE;
+
+   const HOST_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 2];
+   const OTHER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 3];
+   const SECOND: u64 = 1_000_000;
+
+   fn server() -> DhcpServer {
+      DhcpServer::new(DhcpConfig {
+         server_mac: [0x02, 0, 0, 0, 0, 1],
+         server_addr: [192, 168, 7, 1],
+         client_addr: [192, 168, 7, 2],
+         netmask: [255, 255, 255, 0],
+         router: None,
+         dns_server: Some([192, 168, 7, 1]),
+         lease_time: 60,
+      })
+   }
+
+   /// Builds a DHCP request frame from a client without an address
+   fn request(buf: &mut [u8], client_mac: [u8; 6], message_type: u8, requested_addr: Option<[u8; 4]>) -> usize {
+      let message = &mut buf[UDP_PAYLOAD_OFFSET..UDP_PAYLOAD_OFFSET + MIN_MESSAGE_SIZE];
+      message.fill(0);
+      message[0] = OP_BOOTREQUEST;
+      message[1] = HTYPE_ETHERNET;
+      message[2] = 6;
+      message[4..8].copy_from_slice(&[1, 2, 3, 4]);
+      message[28..34].copy_from_slice(&client_mac);
+      message[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+      let mut options = OptionWriter {
+         buf: &mut message[OPTIONS_OFFSET..],
+         idx: 0,
+      };
+      options.write(OPT_MESSAGE_TYPE, &[message_type]);
+      if let Some(addr) = requested_addr {
+         options.write(OPT_REQUESTED_ADDR, &addr);
+      }
+      options.write(OPT_END, &[]);
+
+      let eth = EthernetHeader {
+         dst: ETH_ADDR_BROADCAST,
+         src: client_mac,
+         ethertype: ETHERTYPE_IPV4,
+      };
+      let ip = Ipv4Header {
+         src: [0; 4],
+         dst: IPV4_ADDR_BROADCAST,
+         protocol: IP_PROTO_UDP,
+      };
+      let udp = UdpHeader {
+         src_port: CLIENT_PORT,
+         dst_port: SERVER_PORT,
+      };
+      emit_udp_frame(buf, &eth, &ip, &udp, MIN_MESSAGE_SIZE)
+   }
+
+   /// Returns the message type of a reply and the address it hands out
+   fn parse_reply(frame: &[u8]) -> (u8, [u8; 4]) {
+      let (ip, datagram) = Ipv4Header::parse(&frame[ETH_HEADER_SIZE..]).unwrap();
+      let (_, message) = UdpHeader::parse(&ip, datagram).unwrap();
+      assert_eq!(message[0], OP_BOOTREPLY);
+      assert_eq!(message[OPTIONS_OFFSET], OPT_MESSAGE_TYPE);
+      (message[OPTIONS_OFFSET + 2], [message[16], message[17], message[18], message[19]])
+   }
+
+   #[test]
+   fn single_lease_is_offered_and_acknowledged() {
+      let mut server = server();
+      let (mut frame, mut reply) = ([0; 400], [0; 400]);
+
+      let len = request(&mut frame, HOST_MAC, DHCPDISCOVER, None);
+      let message = &frame[UDP_PAYLOAD_OFFSET..len];
+      assert!(server.process_message(message, 0, &mut reply[..MIN_MESSAGE_SIZE - 1]).is_none());
+      let reply_len = server.respond(&frame[..len], 0, &mut reply).unwrap();
+      assert_eq!(parse_reply(&reply[..reply_len]), (DHCPOFFER, [192, 168, 7, 2]));
+
+      let len = request(&mut frame, HOST_MAC, DHCPREQUEST, Some([192, 168, 7, 2]));
+      let reply_len = server.respond(&frame[..len], 0, &mut reply).unwrap();
+      assert_eq!(parse_reply(&reply[..reply_len]), (DHCPACK, [192, 168, 7, 2]));
+      assert_eq!(server.lease(0).unwrap().client_mac, HOST_MAC);
+
+      // Another host does not get the address, while it is leased
+      let len = request(&mut frame, OTHER_MAC, DHCPDISCOVER, None);
+      assert_eq!(server.respond(&frame[..len], SECOND, &mut reply), Some(0));
+      let len = request(&mut frame, OTHER_MAC, DHCPREQUEST, Some([192, 168, 7, 2]));
+      let reply_len = server.respond(&frame[..len], SECOND, &mut reply).unwrap();
+      assert_eq!(parse_reply(&reply[..reply_len]).0, DHCPNAK);
+
+      // Once the lease expired, the address is offered again
+      let len = request(&mut frame, OTHER_MAC, DHCPDISCOVER, None);
+      let reply_len = server.respond(&frame[..len], 60 * SECOND, &mut reply).unwrap();
+      assert_eq!(parse_reply(&reply[..reply_len]).0, DHCPOFFER);
+   }
+
+   #[test]
+   fn reset_forgets_lease() {
+      let mut server = server();
+      let (mut frame, mut reply) = ([0; 400], [0; 400]);
+
+      let len = request(&mut frame, HOST_MAC, DHCPREQUEST, Some([192, 168, 7, 2]));
+      server.respond(&frame[..len], 0, &mut reply).unwrap();
+      assert!(server.lease(0).is_some());
+
+      server.reset();
+      assert!(server.lease(0).is_none());
+   }
+
+   #[test]
+   fn other_frames_are_passed_on() {
+      let mut server = server();
+      let mut reply = [0; 400];
+      assert_eq!(server.respond(&[0; 60], 0, &mut reply), None);
+   }
+}
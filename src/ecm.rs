@@ -4,8 +4,8 @@ use usb_device::{
     class::{ControlIn, ControlOut, UsbClass},
     control::{Recipient, Request, RequestType},
     descriptor::DescriptorWriter,
-    endpoint::{EndpointIn, EndpointOut},
-    Result as UsbResult,
+    endpoint::{EndpointAddress, EndpointIn, EndpointOut},
+    Result as UsbResult, UsbError,
 };
 
 const USB_CLASS_CDC_DATA: u8 = 0x0a;
@@ -29,11 +29,18 @@ const SET_ETHERNET_PACKET_FILTER: u8 = 0x43;
 const GET_ETHERNET_STATISTICS: u8 = 0x44;
 
 // CDC ECM Class notification codes, Section 6.3 in CDC ECM spec
+const NETWORK_CONNECTION: u8 = 0x00;
 // NOT IMPLEMENTED
-//const NETWORK_CONNECTION: u8 = 0x00;
 //const RESPONSE_AVAILABLE: u8 = 0x01;
 //const CONNECTION_SPEED_CHANGE: u8 = 0x2A;
 
+// bmRequestType of a class notification to the host
+const NOTIFICATION_REQUEST_TYPE: u8 = 0xA1;
+
+// Alternate settings of the data interface, Section 3.3 in CDC ECM spec
+const DATA_ALT_SETTING_IDLE: u8 = 0x00;
+const DATA_ALT_SETTING_ACTIVE: u8 = 0x01;
+
 pub struct CdcEcmClass<'a, B: UsbBus> {
     comm_if: InterfaceNumber,
     comm_ep: EndpointIn<'a, B>,
@@ -41,8 +48,13 @@ pub struct CdcEcmClass<'a, B: UsbBus> {
     read_ep: EndpointOut<'a, B>,
     write_ep: EndpointIn<'a, B>,
 
+    mac_addr: [u8; 6],
     mac_string_index: StringIndex,
     mac_string: [u8; 12],
+
+    data_active: bool,
+    pending_notification: Option<bool>,
+    packet_filter: u16,
 }
 
 // TODO: Implement Debug
@@ -50,10 +62,6 @@ pub struct CdcEcmClass<'a, B: UsbBus> {
 impl<'a, B: UsbBus> CdcEcmClass<'a, B> {
     /// Create e new [`CdcEcmClass`](CdcEcmClass)
     pub fn new(alloc: &'a UsbBusAllocator<B>, mac_addr: &[u8; 6]) -> Self {
-        // Generat the mac string as a bytes sequence
-        let mut mac_str = [0; 12];
-        hex::encode_to_slice(mac_addr, &mut mac_str).unwrap();
-
         Self {
             comm_if: alloc.interface(),
             comm_ep: alloc.interrupt(8, 255),
@@ -61,8 +69,13 @@ impl<'a, B: UsbBus> CdcEcmClass<'a, B> {
             read_ep: alloc.bulk(EP_PKG_SIZE),
             write_ep: alloc.bulk(EP_PKG_SIZE),
 
+            mac_addr: *mac_addr,
             mac_string_index: alloc.string(),
-            mac_string: mac_str,
+            mac_string: encode_mac(mac_addr),
+
+            data_active: false,
+            pending_notification: None,
+            packet_filter: 0,
         }
     }
 
@@ -74,14 +87,94 @@ impl<'a, B: UsbBus> CdcEcmClass<'a, B> {
     }
 
     /// Get the in endpoint
-    pub fn get_write_ep(&self) -> &'a EndpointIn<B> {
+    pub fn get_write_ep(&self) -> &EndpointIn<'a, B> {
         &self.write_ep
     }
 
     /// Get the out endpoint
-    pub fn get_read_ep(&self) -> &'a EndpointOut<B> {
+    pub fn get_read_ep(&self) -> &EndpointOut<'a, B> {
         &self.read_ep
     }
+
+    /// Get the mac address, that is reported to the host
+    pub fn mac_address(&self) -> [u8; 6] {
+        self.mac_addr
+    }
+
+    /// Changes the mac address, that is reported to the host.
+    ///
+    /// Returns `true`, if the address changed.
+    pub fn set_mac_address(&mut self, mac_addr: &[u8; 6]) -> bool {
+        if self.mac_addr == *mac_addr {
+            return false;
+        }
+
+        self.mac_addr = *mac_addr;
+        self.mac_string = encode_mac(mac_addr);
+        true
+    }
+
+    /// Get the address of the notification endpoint
+    pub fn get_comm_ep_address(&self) -> EndpointAddress {
+        self.comm_ep.address()
+    }
+
+    /// Returns `true`, if the host has activated the data interface
+    pub fn is_data_active(&self) -> bool {
+        self.data_active
+    }
+
+    /// Returns the ethernet packet filter bitmap, that has been set by the host
+    pub fn packet_filter(&self) -> u16 {
+        self.packet_filter
+    }
+
+    /// Checks, whether this is a standard request directed to the data interface
+    fn is_for_data_if(&self, req: &Request) -> bool {
+        req.request_type == RequestType::Standard
+            && req.recipient == Recipient::Interface
+            && req.index == u8::from(self.data_if) as u16
+    }
+
+    /// Tries to send a pending network connection notification to the host
+    pub fn send_notification(&mut self) {
+        let connected = match self.pending_notification {
+            None => return,
+            Some(connected) => connected,
+        };
+
+        let comm_if = u8::from(self.comm_if);
+        let notification = [
+            NOTIFICATION_REQUEST_TYPE,
+            NETWORK_CONNECTION,
+            connected as u8, // wValue
+            0x00,
+            comm_if, // wIndex
+            0x00,
+            0x00, // wLength
+            0x00,
+        ];
+
+        match self.comm_ep.write(&notification) {
+            Ok(_) => self.pending_notification = None,
+            // If busy, try again later
+            Err(UsbError::WouldBlock) => (),
+            Err(err) => log::error!("failed to send notification: {:?}", err),
+        }
+    }
+}
+
+/// Encodes the mac address as 12 uppercase hex digits, as required for the iMACAddress string,
+/// Section 5.4 in CDC ECM spec
+fn encode_mac(mac_addr: &[u8; 6]) -> [u8; 12] {
+    const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+    let mut mac_str = [0; 12];
+    for (digits, byte) in mac_str.chunks_exact_mut(2).zip(mac_addr) {
+        digits[0] = HEX_DIGITS[(byte >> 4) as usize];
+        digits[1] = HEX_DIGITS[(byte & 0x0f) as usize];
+    }
+    mac_str
 }
 
 impl<B: UsbBus> UsbClass<B> for CdcEcmClass<'_, B> {
@@ -148,8 +241,26 @@ impl<B: UsbBus> UsbClass<B> for CdcEcmClass<'_, B> {
         // Communications endpoint descriptor
         writer.endpoint(&self.comm_ep)?;
 
+        // Data interface descriptor without endpoints, the host activates the
+        // data interface by selecting the alternate setting with endpoints
+        writer.interface_alt(
+            self.data_if,
+            DATA_ALT_SETTING_IDLE,
+            USB_CLASS_CDC_DATA,
+            0x00,
+            0x00,
+            None,
+        )?;
+
         // Data interface descriptor
-        writer.interface(self.data_if, USB_CLASS_CDC_DATA, 0x00, 0x00)?;
+        writer.interface_alt(
+            self.data_if,
+            DATA_ALT_SETTING_ACTIVE,
+            USB_CLASS_CDC_DATA,
+            0x00,
+            0x00,
+            None,
+        )?;
 
         // Data OUT endpoint descriptor
         writer.endpoint(&self.read_ep)?;
@@ -163,15 +274,31 @@ impl<B: UsbBus> UsbClass<B> for CdcEcmClass<'_, B> {
     fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
         // If the mac address is requested, we return it as a str
         if index == self.mac_string_index {
-            // We know that this is valid because of the output of the hex library
+            // We know that this is valid, since `encode_mac` only outputs hex digits
             Some(unsafe { core::str::from_utf8_unchecked(&self.mac_string) })
         } else {
             None
         }
     }
 
+    fn reset(&mut self) {
+        self.data_active = false;
+        self.pending_notification = None;
+        self.packet_filter = 0;
+    }
+
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
+
+        if self.is_for_data_if(req) && req.request == Request::GET_INTERFACE {
+            let alt_setting = match self.data_active {
+                true => DATA_ALT_SETTING_ACTIVE,
+                false => DATA_ALT_SETTING_IDLE,
+            };
+            xfer.accept_with(&[alt_setting]).ok();
+            return;
+        }
+
         if !self.is_for_me(req) {
             return;
         }
@@ -198,6 +325,37 @@ impl<B: UsbBus> UsbClass<B> for CdcEcmClass<'_, B> {
 
     fn control_out(&mut self, xfer: ControlOut<B>) {
         let req = xfer.request();
+
+        // Selecting a configuration resets the alternate settings.
+        // The request itself is handled by `usb-device`.
+        if req.request_type == RequestType::Standard
+            && req.recipient == Recipient::Device
+            && req.request == Request::SET_CONFIGURATION
+        {
+            self.data_active = false;
+            return;
+        }
+
+        if self.is_for_data_if(req) && req.request == Request::SET_INTERFACE {
+            let data_active = match req.value as u8 {
+                DATA_ALT_SETTING_IDLE => false,
+                DATA_ALT_SETTING_ACTIVE => true,
+                _ => {
+                    xfer.reject().ok();
+                    return;
+                }
+            };
+            xfer.accept().ok();
+
+            // Notify the host about the changed network connection
+            if self.data_active != data_active {
+                self.data_active = data_active;
+                self.pending_notification = Some(data_active);
+                self.send_notification();
+            }
+            return;
+        }
+
         if !self.is_for_me(req) {
             return;
         }
@@ -213,8 +371,10 @@ impl<B: UsbBus> UsbClass<B> for CdcEcmClass<'_, B> {
                 xfer.reject().ok();
             }
             SET_ETHERNET_PACKET_FILTER => {
-                log::debug!("ethernet packet filters are not supported");
-                xfer.reject().ok();
+                // NOTE: We pass on all frames regardless, the filter is only informative
+                log::debug!("ethernet packet filter set to {:#06x}", req.value);
+                self.packet_filter = req.value;
+                xfer.accept().ok();
             }
             SET_ETHERNET_POWER_MANAGEMENT_PATTERN_FILTER => {
                 log::debug!("power management not supported");
@@ -227,3 +387,13 @@ impl<B: UsbBus> UsbClass<B> for CdcEcmClass<'_, B> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mac_string_is_uppercase_hex() {
+        assert_eq!(&encode_mac(&[0x02, 0xab, 0x00, 0x1f, 0xc0, 0x09]), b"02AB001FC009");
+    }
+}
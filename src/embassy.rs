@@ -0,0 +1,222 @@
+//! This module implements the [`Driver`] trait of `embassy-net` on top of the
+//! application side of a [`UsbEthernetDevice`].
+//!
+//! The tasks of the network stack are woken from the endpoint callbacks of the
+//! [`UsbEthernetClass`], once a frame arrived, room in the transmit queue freed up
+//! or the host changed the link state.
+
+use crate::{
+   buffer::{RxBuf, RxConsumer, TxProducer},
+   link::Link,
+   FrameReceiver, FrameSender, UsbEthernetClass, UsbEthernetDevice, QUEUE_DEPTH,
+};
+use core::task::Context;
+use embassy_net_driver::{Capabilities, Driver, HardwareAddress, LinkState, RxToken, TxToken};
+use usb_device::bus::UsbBus;
+
+pub struct EmbassyUsb<'b> {
+   rx_buf: &'b RxBuf,
+   sender: FrameSender<'b>,
+   link: &'b Link,
+   mac_addr: [u8; 6],
+}
+
+impl<'a, B> UsbEthernetDevice<'a, B>
+where
+   B: UsbBus,
+{
+   /// Splits the device into its USB side and an [`EmbassyUsb`] driver,
+   /// that can be handed to `embassy-net`.
+   ///
+   /// The driver uses the mac address of the device as its hardware address, see
+   /// [`device_mac_address`](UsbEthernetDevice::device_mac_address).
+   pub fn get_embassy<'b>(&'b mut self) -> (UsbEthernetClass<'b, 'a, B>, EmbassyUsb<'b>) {
+      let mac_addr = self.device_mac;
+      let (class, receiver, sender) = self.split();
+      (class, EmbassyUsb::new(receiver, sender, mac_addr))
+   }
+}
+
+impl<'b> EmbassyUsb<'b> {
+   /// Create a new [`EmbassyUsb`] driver from the application side handles.
+   pub fn new(receiver: FrameReceiver<'b>, sender: FrameSender<'b>, mac_addr: [u8; 6]) -> Self {
+      Self {
+         rx_buf: receiver.rx_buf(),
+         link: receiver.link(),
+         sender,
+         mac_addr,
+      }
+   }
+
+   /// Returns the transmit producer, if a frame can be queued right now
+   fn tx_producer(&self) -> Option<TxProducer<'b>> {
+      if !self.sender.accepts_frames() {
+         return None;
+      }
+      self.sender.tx_buf().producer().filter(|producer| !producer.is_full())
+   }
+}
+
+impl Driver for EmbassyUsb<'_> {
+   type RxToken<'a>
+      = UsbRxToken<'a>
+   where
+      Self: 'a;
+   type TxToken<'a>
+      = UsbTxToken<'a>
+   where
+      Self: 'a;
+
+   fn receive(&mut self, cx: &mut Context) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+      // Register first, such that a frame arriving in between is not missed
+      self.rx_buf.register_waker(cx.waker());
+      self.sender.tx_buf().register_waker(cx.waker());
+
+      // Only proceed, if there is a frame ready and also room for a response
+      let rx_consumer = self.rx_buf.consumer().filter(|consumer| consumer.peek().is_some())?;
+      let tx_producer = self.tx_producer()?;
+
+      Some((
+         UsbRxToken { consumer: rx_consumer },
+         UsbTxToken::new(tx_producer, &self.sender),
+      ))
+   }
+
+   fn transmit(&mut self, cx: &mut Context) -> Option<Self::TxToken<'_>> {
+      self.sender.tx_buf().register_waker(cx.waker());
+
+      // Return early, if the transmit queue is full
+      let tx_producer = self.tx_producer()?;
+      Some(UsbTxToken::new(tx_producer, &self.sender))
+   }
+
+   fn link_state(&mut self, cx: &mut Context) -> LinkState {
+      self.link.register_waker(cx.waker());
+      match self.link.is_up() {
+         true => LinkState::Up,
+         false => LinkState::Down,
+      }
+   }
+
+   fn capabilities(&self) -> Capabilities {
+      let mut cap = Capabilities::default();
+      cap.max_transmission_unit = self.rx_buf.max_frame_size();
+      cap.max_burst_size = Some(QUEUE_DEPTH);
+
+      cap
+   }
+
+   fn hardware_address(&self) -> HardwareAddress {
+      HardwareAddress::Ethernet(self.mac_addr)
+   }
+}
+
+pub struct UsbTxToken<'a> {
+   producer: TxProducer<'a>,
+   sender: &'a FrameSender<'a>,
+   pad: bool,
+}
+
+impl<'a> UsbTxToken<'a> {
+   fn new(producer: TxProducer<'a>, sender: &'a FrameSender<'a>) -> Self {
+      Self {
+         producer,
+         sender,
+         pad: sender.tx_buf().pad(),
+      }
+   }
+
+   /// Commits the frame and triggers its transmission
+   fn commit(mut self) {
+      self.producer.commit();
+      drop(self.producer);
+      self.sender.frame_queued();
+   }
+}
+
+impl TxToken for UsbTxToken<'_> {
+   fn consume<R, F>(mut self, len: usize, f: F) -> R
+   where
+      F: FnOnce(&mut [u8]) -> R,
+   {
+      // We know that there is room in the queue, because we checked
+      // and nobody else can produce frames in the meantime.
+      // The network stack never exceeds the MTU we reported.
+      let result = f(self.producer.slot_mut().try_send_frame(len, self.pad).unwrap());
+      self.commit();
+      result
+   }
+}
+
+pub struct UsbRxToken<'a> {
+   consumer: RxConsumer<'a>,
+}
+
+impl RxToken for UsbRxToken<'_> {
+   fn consume<R, F>(mut self, f: F) -> R
+   where
+      F: FnOnce(&mut [u8]) -> R,
+   {
+      // We know that we have a frame ready because we checked
+      // and nobody else can consume frames in the meantime.
+      let result = f(self.consumer.peek_mut().unwrap().try_get_frame().unwrap());
+
+      // Free the slot after reading the frame
+      self.consumer.pop();
+      result
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::buffer::TxBuf;
+   extern crate std;
+   use core::task::Waker;
+
+   const FRAME_LEN: usize = 42;
+   const MAC_ADDR: [u8; 6] = [0x02, 0, 0, 0, 0, 1];
+
+   #[test]
+   fn driver_exchanges_frames_and_reports_link() {
+      let (rx_buf, tx_buf, link) = (RxBuf::new(), TxBuf::new(), Link::new());
+      let (receiver, sender) = (FrameReceiver::new(&rx_buf, &link), FrameSender::new(&tx_buf, &link, None, None));
+      let mut driver = EmbassyUsb::new(receiver, sender, MAC_ADDR);
+      let mut cx = Context::from_waker(Waker::noop());
+
+      assert_eq!(driver.hardware_address(), HardwareAddress::Ethernet(MAC_ADDR));
+      assert!(driver.link_state(&mut cx) == LinkState::Down);
+      link.set_up(true);
+      assert!(driver.link_state(&mut cx) == LinkState::Up);
+
+      // Receive a frame like the USB side does
+      assert!(driver.receive(&mut cx).is_none());
+      let mut producer = rx_buf.producer().unwrap();
+      let slot = producer.slot_mut();
+      slot.insert_packet()[..FRAME_LEN].iter_mut().for_each(|byte| *byte = 1);
+      slot.advance(FRAME_LEN);
+      assert!(producer.commit());
+
+      let (rx, tx) = driver.receive(&mut cx).unwrap();
+      rx.consume(|frame| assert_eq!(frame, &[1; FRAME_LEN][..]));
+      tx.consume(FRAME_LEN, |frame| frame.iter_mut().for_each(|byte| *byte = 2));
+
+      let mut consumer = tx_buf.consumer().unwrap();
+      assert_eq!(consumer.peek_mut().unwrap().try_get_packet().unwrap(), &[2; FRAME_LEN][..]);
+   }
+
+   #[test]
+   fn capabilities_follow_frame_size() {
+      let (mut rx_buf, tx_buf, link) = (RxBuf::new(), TxBuf::new(), Link::new());
+      rx_buf.set_max_frame_size(1000);
+      let driver = EmbassyUsb::new(
+         FrameReceiver::new(&rx_buf, &link),
+         FrameSender::new(&tx_buf, &link, None, None),
+         MAC_ADDR,
+      );
+
+      let cap = driver.capabilities();
+      assert_eq!(cap.max_transmission_unit, 1000);
+      assert_eq!(cap.max_burst_size, Some(QUEUE_DEPTH));
+   }
+}
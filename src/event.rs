@@ -0,0 +1,34 @@
+//! This module contains the [`Event`]s of the USB network lifecycle and the
+//! [`EventHandler`] trait, through which the application gets notified about them.
+
+/// An event in the lifecycle of the USB network interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+   /// The host selected a configuration
+   Configured,
+   /// The host activated the data interface, the link is up
+   DataActivated,
+   /// The host deactivated the data interface, the link is down
+   DataDeactivated,
+   /// The host reset the bus
+   Reset,
+   /// The bus has been suspended
+   Suspend,
+   /// The bus has been resumed
+   Resume,
+   /// A received frame has been dropped, because it was invalid or due to the
+   /// [`RxOverflowPolicy`](crate::RxOverflowPolicy), or a queued frame has been dropped
+   /// due to the [`TxSuspendPolicy`](crate::TxSuspendPolicy)
+   FrameDropped,
+   /// The host changed the ethernet packet filter bitmap, Section 6.2.4 in CDC ECM spec
+   PacketFilterChanged(u16),
+}
+
+/// A handler for [`Event`]s, provided by the user.
+///
+/// The events are raised from the USB side, i.e. from the context the
+/// [`UsbEthernetClass`](crate::UsbEthernetClass) is polled in.
+/// The handler should therefore return quickly, e.g. by setting a flag or signaling a task.
+pub trait EventHandler: Sync {
+   fn handle_event(&self, event: Event);
+}
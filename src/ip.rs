@@ -0,0 +1,535 @@
+//! This module implements an IP-only view of the USB link, which hides the ethernet framing
+//! from the application.
+//!
+//! The link is point-to-point, so every address behind it is reached through the device:
+//! ARP requests and IPv6 neighbor solicitations of the host are answered with the mac address
+//! of the device, other frames without an IP packet are dropped.
+//! Sent packets are addressed to the mac address of the host, which is learned from the
+//! received packets, and multicast packets to the matching group address.
+//!
+//! With the `smoltcp` feature enabled, [`IpUsb`] is a `smoltcp` device with `Medium::Ip`.
+
+use crate::{
+   buffer::{RxBuf, RxConsumer},
+   wire::{
+      checksum_add, checksum_finish, emit_arp_frame, ipv4_multicast_mac, ipv6_multicast_mac, write_u16, ArpPacket,
+      EthernetHeader, Ipv6Header, PseudoHeader, ARP_OP_REPLY, ARP_OP_REQUEST, ETHERTYPE_ARP, ETHERTYPE_IPV4,
+      ETHERTYPE_IPV6, ETH_ADDR_BROADCAST, IPV4_HEADER_SIZE, IPV6_HEADER_SIZE, IP_PROTO_ICMPV6,
+   },
+   FrameReceiver, FrameSender, UsbEthernetClass, UsbEthernetDevice, ETH_HEADER_SIZE,
+};
+use usb_device::bus::UsbBus;
+
+// Neighbor discovery, RFC 4861
+const NEIGHBOR_SOLICITATION: u8 = 135;
+const NEIGHBOR_ADVERTISEMENT: u8 = 136;
+const ND_HOP_LIMIT: u8 = 255;
+const OPT_TARGET_LL_ADDR: u8 = 2;
+/// The router, solicited and override flags
+const NA_FLAGS: u8 = 0xe0;
+const NA_SIZE: usize = 32;
+
+/// The size of the largest reply to a control frame
+const MAX_REPLY_SIZE: usize = ETH_HEADER_SIZE + IPV6_HEADER_SIZE + NA_SIZE;
+
+pub struct IpUsb<'b> {
+   rx_buf: &'b RxBuf,
+   sender: FrameSender<'b>,
+   host_mac: [u8; 6],
+   device_mac: [u8; 6],
+}
+
+impl<'a, B> UsbEthernetDevice<'a, B>
+where
+   B: UsbBus,
+{
+   /// Splits the device into its USB side and an [`IpUsb`] handle,
+   /// that sends and receives IP packets instead of ethernet frames.
+   ///
+   /// The replies to the host are sent from the mac address of the device, see
+   /// [`device_mac_address`](UsbEthernetDevice::device_mac_address).
+   pub fn get_ip<'b>(&'b mut self) -> (UsbEthernetClass<'b, 'a, B>, IpUsb<'b>) {
+      let (host_mac, device_mac) = (self.mac_address(), self.device_mac_address());
+      let (class, receiver, sender) = self.split();
+      (class, IpUsb::new(receiver, sender, host_mac, device_mac))
+   }
+}
+
+impl<'b> IpUsb<'b> {
+   /// Creates the IP-only view from the application side of a [`UsbEthernetDevice`].
+   ///
+   /// `host_mac` is used as destination, until the host sent the first packet.
+   pub fn new(receiver: FrameReceiver<'b>, sender: FrameSender<'b>, host_mac: [u8; 6], device_mac: [u8; 6]) -> Self {
+      Self {
+         rx_buf: receiver.rx_buf(),
+         sender,
+         host_mac,
+         device_mac,
+      }
+   }
+
+   /// Tries to receive an IP packet.
+   ///
+   /// If a packet is ready, the closure will be executed, which allows to copy out the packet.
+   /// ARP requests and neighbor solicitations received on the way are answered.
+   ///
+   /// # Returns
+   /// - the length of the packet, if a packet was received
+   /// - `None`: otherwise
+   pub fn try_receive_packet<F>(&mut self, f: F) -> Option<usize>
+   where
+      F: FnOnce(&[u8]),
+   {
+      let mut consumer = self.next_packet()?;
+      let packet = &consumer.peek_mut()?.try_get_frame()?[ETH_HEADER_SIZE..];
+      let len = packet.len();
+      f(packet);
+
+      consumer.pop();
+      Some(len)
+   }
+
+   /// Tries to send an IP packet of `len` bytes, which is written by the closure.
+   ///
+   /// # Returns
+   /// - `true`, if the packet was queued
+   /// - `false`, if the transmit queue is full or the packet is too long,
+   ///   or too short to hold its IP header
+   pub fn try_send_packet<F>(&mut self, len: usize, f: F) -> bool
+   where
+      F: FnOnce(&mut [u8]),
+   {
+      if len < IPV4_HEADER_SIZE {
+         return false;
+      }
+
+      let (host_mac, device_mac) = (self.host_mac, self.device_mac);
+      self.sender.try_send_frame_checked(ETH_HEADER_SIZE + len, |frame| {
+         f(&mut frame[ETH_HEADER_SIZE..]);
+         let packet = &frame[ETH_HEADER_SIZE..];
+         let header_size = match packet[0] >> 4 {
+            6 => IPV6_HEADER_SIZE,
+            _ => IPV4_HEADER_SIZE,
+         };
+         if packet.len() < header_size {
+            log::debug!("packet of {} bytes is too short for its header, dropping packet", len);
+            return false;
+         }
+         emit_header(frame, host_mac, device_mac);
+         true
+      })
+   }
+
+   /// Answers and drops the frames without an IP packet at the head of the receive queue.
+   ///
+   /// Returns the consumer, once an IP packet is at the head.
+   fn next_packet(&mut self) -> Option<RxConsumer<'b>> {
+      loop {
+         let mut consumer = self.rx_buf.consumer()?;
+         let frame = consumer.peek_mut()?.try_get_frame()?;
+         let (eth, packet) = EthernetHeader::parse(frame)?;
+         // Neighbor solicitations are answered here, since the application has no link layer
+         let is_ip = match eth.ethertype {
+            ETHERTYPE_IPV4 => true,
+            ETHERTYPE_IPV6 => !is_neighbor_solicitation(packet),
+            _ => false,
+         };
+         if is_ip {
+            // Follow the host, in case it changes its mac address
+            if eth.src[0] & 0x01 == 0 {
+               self.host_mac = eth.src;
+            }
+            return Some(consumer);
+         }
+
+         let mut reply = [0; MAX_REPLY_SIZE];
+         let reply_len = self.reply(frame, &mut reply);
+         consumer.pop();
+         drop(consumer);
+
+         if let Some(len) = reply_len {
+            if !self.sender.try_send_frame(len, |buf| buf.copy_from_slice(&reply[..len])) {
+               log::debug!("transmit queue full, dropping reply");
+            }
+         }
+      }
+   }
+
+   /// Writes the reply to an ARP request or neighbor solicitation into `buf`, if any,
+   /// and returns its length
+   fn reply(&self, frame: &[u8], buf: &mut [u8]) -> Option<usize> {
+      let (eth, packet) = EthernetHeader::parse(frame)?;
+      match eth.ethertype {
+         ETHERTYPE_ARP => {
+            // Probes and announcements of the host for its own address are not answered
+            let arp = ArpPacket::parse(packet)?;
+            if arp.op != ARP_OP_REQUEST || arp.sender_ip == [0; 4] || arp.sender_ip == arp.target_ip {
+               return None;
+            }
+
+            let reply = ArpPacket {
+               op: ARP_OP_REPLY,
+               sender_mac: self.device_mac,
+               sender_ip: arp.target_ip,
+               target_mac: arp.sender_mac,
+               target_ip: arp.sender_ip,
+            };
+            Some(emit_arp_frame(buf, arp.sender_mac, &reply))
+         }
+         ETHERTYPE_IPV6 => {
+            let (ip, message) = Ipv6Header::parse(packet)?;
+            if ip.next_header != IP_PROTO_ICMPV6
+               || ip.hop_limit != ND_HOP_LIMIT
+               || message.len() < 24
+               || message[0] != NEIGHBOR_SOLICITATION
+               || checksum_finish(checksum_add(ip.pseudo_header_checksum(message.len()), message)) != 0
+            {
+               return None;
+            }
+
+            // Duplicate address detection of the host is not answered
+            let mut target = [0; 16];
+            target.copy_from_slice(&message[8..24]);
+            if ip.src == [0; 16] || target[0] == 0xff {
+               return None;
+            }
+
+            let eth = EthernetHeader {
+               dst: eth.src,
+               src: self.device_mac,
+               ethertype: ETHERTYPE_IPV6,
+            };
+            let ip = Ipv6Header {
+               src: target,
+               dst: ip.src,
+               next_header: IP_PROTO_ICMPV6,
+               hop_limit: ND_HOP_LIMIT,
+            };
+            eth.emit(buf);
+            ip.emit(&mut buf[ETH_HEADER_SIZE..], NA_SIZE);
+
+            let advert = &mut buf[ETH_HEADER_SIZE + IPV6_HEADER_SIZE..MAX_REPLY_SIZE];
+            advert.fill(0);
+            advert[0] = NEIGHBOR_ADVERTISEMENT;
+            advert[4] = NA_FLAGS;
+            advert[8..24].copy_from_slice(&target);
+            advert[24] = OPT_TARGET_LL_ADDR;
+            advert[25] = 1;
+            advert[26..32].copy_from_slice(&self.device_mac);
+            let checksum = checksum_finish(checksum_add(ip.pseudo_header_checksum(NA_SIZE), advert));
+            write_u16(advert, 2, checksum);
+            Some(MAX_REPLY_SIZE)
+         }
+         _ => None,
+      }
+   }
+}
+
+/// Returns `true`, if the IPv6 `packet` is a neighbor solicitation
+fn is_neighbor_solicitation(packet: &[u8]) -> bool {
+   packet.len() > IPV6_HEADER_SIZE && packet[6] == IP_PROTO_ICMPV6 && packet[IPV6_HEADER_SIZE] == NEIGHBOR_SOLICITATION
+}
+
+/// Writes the ethernet header for the IP packet following it in `frame`
+fn emit_header(frame: &mut [u8], host_mac: [u8; 6], device_mac: [u8; 6]) {
+   let packet = &frame[ETH_HEADER_SIZE..];
+   // The ethertype only depends on the version, also for a truncated header
+   let ethertype = match packet.first().map(|byte| byte >> 4) {
+      Some(6) => ETHERTYPE_IPV6,
+      _ => ETHERTYPE_IPV4,
+   };
+   let dst = match ethertype {
+      ETHERTYPE_IPV6 if packet.len() >= IPV6_HEADER_SIZE => {
+         let mut dst = [0; 16];
+         dst.copy_from_slice(&packet[24..40]);
+         match dst[0] == 0xff {
+            true => ipv6_multicast_mac(&dst),
+            false => host_mac,
+         }
+      }
+      ETHERTYPE_IPV4 if packet.len() >= IPV4_HEADER_SIZE => {
+         let dst = [packet[16], packet[17], packet[18], packet[19]];
+         match dst {
+            [0xff, 0xff, 0xff, 0xff] => ETH_ADDR_BROADCAST,
+            [224..=239, ..] => ipv4_multicast_mac(&dst),
+            _ => host_mac,
+         }
+      }
+      _ => host_mac,
+   };
+
+   let eth = EthernetHeader {
+      dst,
+      src: device_mac,
+      ethertype,
+   };
+   eth.emit(frame);
+}
+
+#[cfg(feature = "smoltcp")]
+mod smol {
+   use super::*;
+   use crate::{buffer::TxProducer, QUEUE_DEPTH};
+   use smoltcp::{
+      phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken},
+      time::Instant,
+   };
+
+   impl IpUsb<'_> {
+      /// Returns the transmit producer, if a frame can be queued right now
+      fn tx_producer(&self) -> Option<TxProducer<'_>> {
+         if !self.sender.accepts_frames() {
+            return None;
+         }
+         self.sender.tx_buf().producer().filter(|producer| !producer.is_full())
+      }
+   }
+
+   impl Device for IpUsb<'_> {
+      type RxToken<'a>
+         = IpRxToken<'a>
+      where
+         Self: 'a;
+      type TxToken<'a>
+         = IpTxToken<'a>
+      where
+         Self: 'a;
+
+      fn capabilities(&self) -> DeviceCapabilities {
+         let mut cap = DeviceCapabilities::default();
+         cap.medium = Medium::Ip;
+         cap.max_transmission_unit = self.rx_buf.max_frame_size().saturating_sub(ETH_HEADER_SIZE);
+         cap.max_burst_size = Some(QUEUE_DEPTH);
+
+         cap
+      }
+
+      fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+         // Only proceed, if there is a packet ready and also room for a response
+         let consumer = self.next_packet()?;
+         let producer = self.tx_producer()?;
+         Some((IpRxToken { consumer }, IpTxToken::new(producer, self)))
+      }
+
+      fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+         let producer = self.tx_producer()?;
+         Some(IpTxToken::new(producer, self))
+      }
+   }
+
+   pub struct IpRxToken<'a> {
+      consumer: RxConsumer<'a>,
+   }
+
+   impl RxToken for IpRxToken<'_> {
+      fn consume<R, F>(mut self, f: F) -> R
+      where
+         F: FnOnce(&[u8]) -> R,
+      {
+         // We know that there is an IP packet at the head, because we checked
+         let result = f(&self.consumer.peek_mut().unwrap().try_get_frame().unwrap()[ETH_HEADER_SIZE..]);
+         self.consumer.pop();
+         result
+      }
+   }
+
+   pub struct IpTxToken<'a> {
+      producer: TxProducer<'a>,
+      sender: &'a FrameSender<'a>,
+      host_mac: [u8; 6],
+      device_mac: [u8; 6],
+   }
+
+   impl<'a> IpTxToken<'a> {
+      fn new(producer: TxProducer<'a>, ip: &'a IpUsb<'a>) -> Self {
+         Self {
+            producer,
+            sender: &ip.sender,
+            host_mac: ip.host_mac,
+            device_mac: ip.device_mac,
+         }
+      }
+   }
+
+   impl TxToken for IpTxToken<'_> {
+      fn consume<R, F>(mut self, len: usize, f: F) -> R
+      where
+         F: FnOnce(&mut [u8]) -> R,
+      {
+         let pad = self.sender.tx_buf().pad();
+         // We know that there is room in the queue, because we checked
+         // and nobody else can produce frames in the meantime
+         let frame = self.producer.slot_mut().try_send_frame(ETH_HEADER_SIZE + len, pad).unwrap();
+         let result = f(&mut frame[ETH_HEADER_SIZE..ETH_HEADER_SIZE + len]);
+         emit_header(frame, self.host_mac, self.device_mac);
+
+         self.producer.commit();
+         drop(self.producer);
+         self.sender.frame_queued();
+         result
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{buffer::TxBuf, link::Link, EP_PKG_USIZE};
+
+   const HOST_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 1];
+   const DEVICE_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 2];
+
+   /// Puts a frame into the receive queue, like the USB side does
+   fn receive_frame(rx_buf: &RxBuf, frame: &[u8]) {
+      let mut producer = rx_buf.producer().unwrap();
+      let buf = producer.slot_mut();
+      buf.reset();
+      buf.insert_packet()[..frame.len()].copy_from_slice(frame);
+      buf.advance(frame.len());
+      // The frame is ended by a short packet
+      if frame.len() >= EP_PKG_USIZE {
+         buf.advance(0);
+      }
+      assert!(producer.commit());
+   }
+
+   /// Takes the oldest frame out of the transmit queue
+   fn sent_frame(tx_buf: &TxBuf, buf: &mut [u8]) -> usize {
+      let mut consumer = tx_buf.consumer().unwrap();
+      let frame = consumer.peek_mut().unwrap();
+      let mut len = 0;
+      while let Some(packet) = frame.try_get_packet() {
+         let packet_len = packet.len();
+         buf[len..len + packet_len].copy_from_slice(packet);
+         frame.advance(packet_len);
+         len += packet_len;
+      }
+      frame.reset();
+      consumer.pop();
+      len
+   }
+
+   #[test]
+   fn arp_is_answered_and_headers_are_stripped() {
+      let (rx_buf, tx_buf, link) = (RxBuf::new(), TxBuf::new(), Link::new());
+      let mut ip = IpUsb::new(
+         FrameReceiver::new(&rx_buf, &link),
+         FrameSender::new(&tx_buf, &link, None, None),
+         HOST_MAC,
+         DEVICE_MAC,
+      );
+
+      let mut frame = [0; 64];
+      let request = ArpPacket {
+         op: ARP_OP_REQUEST,
+         sender_mac: HOST_MAC,
+         sender_ip: [192, 168, 7, 2],
+         target_mac: [0; 6],
+         target_ip: [192, 168, 7, 1],
+      };
+      let len = emit_arp_frame(&mut frame, ETH_ADDR_BROADCAST, &request);
+      receive_frame(&rx_buf, &frame[..len]);
+
+      let eth = EthernetHeader {
+         dst: DEVICE_MAC,
+         src: HOST_MAC,
+         ethertype: ETHERTYPE_IPV4,
+      };
+      eth.emit(&mut frame);
+      frame[ETH_HEADER_SIZE] = 0x45;
+      receive_frame(&rx_buf, &frame[..ETH_HEADER_SIZE + 20]);
+
+      // The ARP request is answered on the way to the packet
+      let mut packet = [0; 20];
+      assert_eq!(ip.try_receive_packet(|p| packet.copy_from_slice(p)), Some(20));
+      assert_eq!(packet[0], 0x45);
+      assert!(ip.try_receive_packet(|_| ()).is_none());
+
+      let mut reply = [0; 1600];
+      let len = sent_frame(&tx_buf, &mut reply);
+      let arp = ArpPacket::parse(&reply[ETH_HEADER_SIZE..len]).unwrap();
+      assert_eq!((arp.op, arp.sender_mac, arp.sender_ip), (ARP_OP_REPLY, DEVICE_MAC, [192, 168, 7, 1]));
+
+      // Sent packets are addressed to the host, or to the group for multicast
+      assert!(ip.try_send_packet(20, |p| p.copy_from_slice(&packet)));
+      sent_frame(&tx_buf, &mut reply);
+      assert_eq!(EthernetHeader::parse(&reply).unwrap().0, EthernetHeader { dst: HOST_MAC, src: DEVICE_MAC, ..eth });
+
+      packet[16..20].copy_from_slice(&[224, 0, 0, 251]);
+      assert!(ip.try_send_packet(20, |p| p.copy_from_slice(&packet)));
+      sent_frame(&tx_buf, &mut reply);
+      assert_eq!(&reply[..6], &[0x01, 0x00, 0x5e, 0, 0, 251]);
+
+      // Packets too short for their header are refused
+      assert!(!ip.try_send_packet(19, |p| p.copy_from_slice(&packet[..19])));
+      assert!(!ip.try_send_packet(20, |p| p[0] = 0x60));
+      assert!(tx_buf.consumer().unwrap().peek().is_none());
+   }
+
+   #[test]
+   fn ethertype_follows_the_version() {
+      let mut frame = [0; ETH_HEADER_SIZE + 20];
+      frame[ETH_HEADER_SIZE] = 0x60;
+      emit_header(&mut frame, HOST_MAC, DEVICE_MAC);
+      let (eth, _) = EthernetHeader::parse(&frame).unwrap();
+      assert_eq!((eth.dst, eth.ethertype), (HOST_MAC, ETHERTYPE_IPV6));
+   }
+
+   #[test]
+   fn neighbor_solicitations_are_answered() {
+      let (rx_buf, tx_buf, link) = (RxBuf::new(), TxBuf::new(), Link::new());
+      let mut ip = IpUsb::new(
+         FrameReceiver::new(&rx_buf, &link),
+         FrameSender::new(&tx_buf, &link, None, None),
+         HOST_MAC,
+         DEVICE_MAC,
+      );
+
+      let target = [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+      let ns = Ipv6Header {
+         src: [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2],
+         dst: [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xff, 0, 0, 1],
+         next_header: IP_PROTO_ICMPV6,
+         hop_limit: ND_HOP_LIMIT,
+      };
+      let mut frame = [0; ETH_HEADER_SIZE + IPV6_HEADER_SIZE + 24];
+      EthernetHeader {
+         dst: ipv6_multicast_mac(&ns.dst),
+         src: HOST_MAC,
+         ethertype: ETHERTYPE_IPV6,
+      }
+      .emit(&mut frame);
+      ns.emit(&mut frame[ETH_HEADER_SIZE..], 24);
+      let message = &mut frame[ETH_HEADER_SIZE + IPV6_HEADER_SIZE..];
+      message[0] = NEIGHBOR_SOLICITATION;
+      message[8..24].copy_from_slice(&target);
+      let checksum = checksum_finish(checksum_add(ns.pseudo_header_checksum(24), message));
+      write_u16(message, 2, checksum);
+
+      // The solicitation is answered instead of being passed to the application
+      receive_frame(&rx_buf, &frame);
+      assert!(ip.try_receive_packet(|_| ()).is_none());
+
+      let mut reply = [0; 1600];
+      let len = sent_frame(&tx_buf, &mut reply);
+      assert_eq!(len, MAX_REPLY_SIZE);
+      let (eth, packet) = EthernetHeader::parse(&reply[..len]).unwrap();
+      assert_eq!((eth.dst, eth.src), (HOST_MAC, DEVICE_MAC));
+      let (ip6, advert) = Ipv6Header::parse(packet).unwrap();
+      assert_eq!((ip6.src, ip6.dst), (target, ns.src));
+      assert_eq!(advert[0], NEIGHBOR_ADVERTISEMENT);
+      assert_eq!(checksum_finish(checksum_add(ip6.pseudo_header_checksum(advert.len()), advert)), 0);
+      assert_eq!(&advert[26..32], &DEVICE_MAC);
+
+      // Also by the smoltcp device
+      #[cfg(feature = "smoltcp")]
+      {
+         use smoltcp::{phy::Device, time::Instant};
+
+         receive_frame(&rx_buf, &frame);
+         assert!(ip.receive(Instant::from_millis(0)).is_none());
+         assert_eq!(sent_frame(&tx_buf, &mut reply), MAX_REPLY_SIZE);
+         assert_eq!(reply[ETH_HEADER_SIZE + IPV6_HEADER_SIZE], NEIGHBOR_ADVERTISEMENT);
+      }
+   }
+}
@@ -0,0 +1,331 @@
+//! This module implements the self-assignment of an IPv4 link-local address, RFC 3927,
+//! for hosts, that do not run a DHCP client on the USB interface.
+//!
+//! An address from `169.254.0.0/16` is picked and probed with ARP requests.
+//! Once no other host claimed it, it is announced and [`LinkLocalV4::address`] returns it,
+//! such that it can be assigned to the network stack.
+//!
+//! Since it needs to observe the ARP traffic, it works on raw frames:
+//! Frames received from the host are passed to [`LinkLocalV4::process`], and
+//! [`LinkLocalV4::poll`] sends the probes and announcements.
+
+use crate::{
+   wire::{
+      emit_arp_frame, ArpPacket, EthernetHeader, ARP_OP_REQUEST, ARP_PACKET_SIZE, ETHERTYPE_ARP, ETH_ADDR_BROADCAST,
+   },
+   FrameSender, ETH_HEADER_SIZE,
+};
+
+// Timing constants in microseconds, RFC 3927 section 9
+const PROBE_WAIT: u64 = 1_000_000;
+const PROBE_NUM: u8 = 3;
+const PROBE_MIN: u64 = 1_000_000;
+const PROBE_MAX: u64 = 2_000_000;
+const ANNOUNCE_WAIT: u64 = 2_000_000;
+const ANNOUNCE_NUM: u8 = 2;
+const ANNOUNCE_INTERVAL: u64 = 2_000_000;
+const MAX_CONFLICTS: u8 = 10;
+const RATE_LIMIT_INTERVAL: u64 = 60_000_000;
+const DEFEND_INTERVAL: u64 = 10_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+   /// Waiting for the first probe, or for the next one after a conflict
+   Init,
+   /// The number of probes sent
+   Probing(u8),
+   /// The number of announcements sent, the address is claimed
+   Announcing(u8),
+   /// The address is claimed and defended
+   Bound,
+}
+
+/// Claims an IPv4 link-local address.
+#[derive(Debug, Clone)]
+pub struct LinkLocalV4 {
+   mac: [u8; 6],
+   state: State,
+   /// The address, that is probed or claimed
+   addr: [u8; 4],
+   /// The time in microseconds, at which the next probe or announcement is due,
+   /// or `None`, if it has not been scheduled yet
+   next_at: Option<u64>,
+   /// The number of conflicts, since an address was claimed
+   conflicts: u8,
+   /// The time in microseconds, at which the address was defended last, if any
+   last_defended: Option<u64>,
+   /// An announcement needs to be sent to defend the address
+   defend: bool,
+   /// The state of the pseudo random number generator
+   rng: u32,
+}
+
+impl LinkLocalV4 {
+   /// Creates a new [`LinkLocalV4`] for the device with `mac`.
+   ///
+   /// The addresses are picked pseudo randomly, seeded by `mac`, such that the same address
+   /// is picked after every reboot, if there is no conflict.
+   pub fn new(mac: [u8; 6]) -> Self {
+      let rng = mac.iter().fold(0x811c_9dc5u32, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193));
+      let mut this = Self {
+         mac,
+         state: State::Init,
+         addr: [0; 4],
+         next_at: None,
+         conflicts: 0,
+         last_defended: None,
+         defend: false,
+         rng: rng | 1,
+      };
+      this.pick_addr();
+      this
+   }
+
+   /// Returns the claimed address, if any
+   pub fn address(&self) -> Option<[u8; 4]> {
+      match self.state {
+         State::Announcing(_) | State::Bound => Some(self.addr),
+         State::Init | State::Probing(_) => None,
+      }
+   }
+
+   /// Starts over with probing the address.
+   ///
+   /// This should be called, once the data interface is activated,
+   /// see [`Event::DataActivated`](crate::Event::DataActivated), since the host
+   /// might have been replaced in the meantime.
+   pub fn reset(&mut self) {
+      self.state = State::Init;
+      self.next_at = None;
+      self.last_defended = None;
+      self.defend = false;
+   }
+
+   /// Sends the next probe or announcement, if one is due at `now`, in microseconds
+   pub fn poll(&mut self, now: u64, sender: &mut FrameSender) {
+      let mut frame = [0; ETH_HEADER_SIZE + ARP_PACKET_SIZE];
+      if let Some(len) = self.poll_frame(now, &mut frame) {
+         if !sender.try_send_frame(len, |buf| buf.copy_from_slice(&frame[..len])) {
+            log::debug!("transmit queue full, dropping arp frame");
+         }
+      }
+   }
+
+   /// Processes a frame received from the host, to detect conflicts.
+   ///
+   /// The frames are only observed, so they are passed on afterwards.
+   pub fn process(&mut self, frame: &[u8], now: u64) {
+      let arp = match EthernetHeader::parse(frame) {
+         Some((eth, packet)) if eth.ethertype == ETHERTYPE_ARP => ArpPacket::parse(packet),
+         _ => None,
+      };
+      let arp = match arp {
+         Some(arp) if arp.sender_mac != self.mac => arp,
+         _ => return,
+      };
+
+      match self.state {
+         State::Init | State::Probing(_) => {
+            // Another host uses the address or probes for it as well
+            let probe = arp.op == ARP_OP_REQUEST && arp.sender_ip == [0; 4] && arp.target_ip == self.addr;
+            if arp.sender_ip == self.addr || probe {
+               self.conflict(now);
+            }
+         }
+         State::Announcing(_) | State::Bound => {
+            if arp.sender_ip != self.addr {
+               return;
+            }
+
+            match self.last_defended {
+               Some(last_defended) if now < last_defended + DEFEND_INTERVAL => {
+                  log::info!("lost link-local address {:?}", self.addr);
+                  self.conflict(now);
+               }
+               _ => {
+                  log::debug!("defending link-local address {:?}", self.addr);
+                  self.last_defended = Some(now);
+                  self.defend = true;
+               }
+            }
+         }
+      }
+   }
+
+   /// Writes the next probe or announcement into `buf`, if one is due, and returns its length
+   fn poll_frame(&mut self, now: u64, buf: &mut [u8]) -> Option<usize> {
+      if self.defend {
+         self.defend = false;
+         return Some(self.emit_arp(buf, self.addr));
+      }
+
+      let next_at = match self.next_at {
+         None => {
+            self.next_at = Some(now + self.random(PROBE_WAIT));
+            return None;
+         }
+         Some(next_at) => next_at,
+      };
+      if now < next_at {
+         return None;
+      }
+
+      match self.state {
+         State::Init => self.probe(now, buf, 0),
+         State::Probing(sent) if sent < PROBE_NUM => self.probe(now, buf, sent),
+         State::Probing(_) => {
+            log::info!("claimed link-local address {:?}", self.addr);
+            self.conflicts = 0;
+            self.announce(now, buf, 0)
+         }
+         State::Announcing(sent) => self.announce(now, buf, sent),
+         State::Bound => None,
+      }
+   }
+
+   fn probe(&mut self, now: u64, buf: &mut [u8], sent: u8) -> Option<usize> {
+      self.state = State::Probing(sent + 1);
+      self.next_at = Some(match sent + 1 < PROBE_NUM {
+         true => now + PROBE_MIN + self.random(PROBE_MAX - PROBE_MIN),
+         false => now + ANNOUNCE_WAIT,
+      });
+      Some(self.emit_arp(buf, [0; 4]))
+   }
+
+   fn announce(&mut self, now: u64, buf: &mut [u8], sent: u8) -> Option<usize> {
+      self.state = match sent + 1 < ANNOUNCE_NUM {
+         true => State::Announcing(sent + 1),
+         false => State::Bound,
+      };
+      self.next_at = Some(now + ANNOUNCE_INTERVAL);
+      Some(self.emit_arp(buf, self.addr))
+   }
+
+   /// Gives up the address and schedules probing another one
+   fn conflict(&mut self, now: u64) {
+      log::debug!("link-local address {:?} is in use", self.addr);
+      self.conflicts = self.conflicts.saturating_add(1);
+      self.state = State::Init;
+      self.last_defended = None;
+      self.defend = false;
+      self.pick_addr();
+
+      let delay = match self.conflicts >= MAX_CONFLICTS {
+         true => RATE_LIMIT_INTERVAL,
+         false => self.random(PROBE_WAIT),
+      };
+      self.next_at = Some(now + delay);
+   }
+
+   /// Picks an address from `169.254.1.0` to `169.254.254.255`
+   fn pick_addr(&mut self) {
+      let rand = self.next_random();
+      self.addr = [169, 254, 1 + (rand % 254) as u8, (rand >> 8) as u8];
+   }
+
+   /// Writes an ARP request for the address into `buf`, either a probe or an announcement
+   fn emit_arp(&self, buf: &mut [u8], sender_ip: [u8; 4]) -> usize {
+      let arp = ArpPacket {
+         op: ARP_OP_REQUEST,
+         sender_mac: self.mac,
+         sender_ip,
+         target_mac: [0; 6],
+         target_ip: self.addr,
+      };
+      emit_arp_frame(buf, ETH_ADDR_BROADCAST, &arp)
+   }
+
+   /// Returns a pseudo random time below `bound` microseconds
+   fn random(&mut self, bound: u64) -> u64 {
+      self.next_random() as u64 % bound
+   }
+
+   /// Advances the xorshift generator
+   fn next_random(&mut self) -> u32 {
+      self.rng ^= self.rng << 13;
+      self.rng ^= self.rng >> 17;
+      self.rng ^= self.rng << 5;
+      self.rng
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   const MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 1];
+   const HOST_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 2];
+   const SECOND: u64 = 1_000_000;
+
+   /// Polls until the next frame is sent and returns its ARP packet
+   fn next_arp(ll: &mut LinkLocalV4, now: &mut u64) -> ArpPacket {
+      let mut buf = [0; 64];
+      loop {
+         if let Some(len) = ll.poll_frame(*now, &mut buf) {
+            return ArpPacket::parse(&buf[ETH_HEADER_SIZE..len]).unwrap();
+         }
+         *now += SECOND / 10;
+      }
+   }
+
+   fn arp_frame(sender_ip: [u8; 4], target_ip: [u8; 4]) -> [u8; 64] {
+      let mut buf = [0; 64];
+      let arp = ArpPacket {
+         op: ARP_OP_REQUEST,
+         sender_mac: HOST_MAC,
+         sender_ip,
+         target_mac: [0; 6],
+         target_ip,
+      };
+      emit_arp_frame(&mut buf, ETH_ADDR_BROADCAST, &arp);
+      buf
+   }
+
+   #[test]
+   fn address_is_probed_and_announced() {
+      let mut ll = LinkLocalV4::new(MAC);
+      let mut now = 0;
+
+      for _ in 0..PROBE_NUM {
+         let probe = next_arp(&mut ll, &mut now);
+         assert_eq!(probe.sender_ip, [0; 4]);
+         assert_eq!(ll.address(), None);
+      }
+
+      let announcement = next_arp(&mut ll, &mut now);
+      let addr = ll.address().unwrap();
+      assert_eq!(announcement.sender_ip, addr);
+      assert_eq!(announcement.target_ip, addr);
+      assert_eq!(&addr[..2], &[169, 254]);
+      assert!((1..=254).contains(&addr[2]));
+
+      next_arp(&mut ll, &mut now);
+      assert_eq!(ll.state, State::Bound);
+
+      // The same address is picked after a reboot
+      assert_eq!(LinkLocalV4::new(MAC).addr, addr);
+   }
+
+   #[test]
+   fn conflicts_pick_another_address() {
+      let mut ll = LinkLocalV4::new(MAC);
+      let mut now = 0;
+
+      next_arp(&mut ll, &mut now);
+      let probed = ll.addr;
+      ll.process(&arp_frame([0; 4], probed), now);
+      assert_eq!(ll.state, State::Init);
+      assert_ne!(ll.addr, probed);
+
+      while ll.state != State::Bound {
+         next_arp(&mut ll, &mut now);
+      }
+      let addr = ll.address().unwrap();
+
+      // The address is defended once, but given up on a second conflict
+      ll.process(&arp_frame(addr, [169, 254, 0, 1]), now);
+      assert_eq!(next_arp(&mut ll, &mut now).sender_ip, addr);
+      ll.process(&arp_frame(addr, [169, 254, 0, 1]), now + SECOND);
+      assert_eq!(ll.address(), None);
+   }
+}
@@ -0,0 +1,20 @@
+//! This module contains the [`TxKick`] trait, which lets the application side trigger
+//! the transmission of a frame, without waiting for the next USB event.
+
+/// A hook, provided by the user, to get the USB side going.
+///
+/// `usb-device` only polls the classes, if there was an endpoint event.
+/// Without a hook, a frame queued by the application side therefore waits for the next
+/// USB event, before its first packet is written.
+///
+/// If a [`TxKick`] is set on the [`UsbEthernetDevice`](crate::UsbEthernetDevice), it is
+/// called every time a frame has been queued.
+/// Typically, it pends the interrupt, in which the USB side is driven, which then calls
+/// [`UsbEthernetClass::try_send`](crate::UsbEthernetClass::try_send).
+pub trait TxKick: Sync {
+   /// Called after a frame has been queued for transmission.
+   ///
+   /// This is called from the application side, so it should return quickly
+   /// and must not access the USB side directly.
+   fn kick(&self);
+}
@@ -3,24 +3,102 @@
 use crate::{
     buffer::{RxBuf, TxBuf},
     ecm::CdcEcmClass,
+    link::Link,
 };
 use usb_device::{
     bus::{StringIndex, UsbBus, UsbBusAllocator},
     class::{ControlIn, ControlOut, UsbClass},
     descriptor::DescriptorWriter,
+    device::UsbDeviceState,
     endpoint::EndpointAddress,
-    Result as UsbResult, UsbError,
+    Result as UsbResult,
 };
 
 pub(crate) mod buffer;
+pub(crate) mod clock;
 pub(crate) mod ecm;
+pub(crate) mod event;
+pub(crate) mod kick;
+pub(crate) mod link;
+pub(crate) mod mac;
+pub(crate) mod queue;
+pub(crate) mod split;
+pub(crate) mod wakeup;
+pub use crate::{
+    buffer::{RxOverflowPolicy, TxSuspendPolicy},
+    clock::Clock,
+    event::{Event, EventHandler},
+    kick::TxKick,
+    mac::{local_unicast_mac, MacAddresses},
+    split::{FrameReceiver, FrameSender, UsbEthernetClass},
+    wakeup::RemoteWakeup,
+};
 
-#[cfg(feature = "smoltcp")]
+#[cfg(feature = "sync")]
 pub(crate) mod lock;
+
 #[cfg(feature = "smoltcp")]
 pub(crate) mod smoltcp;
 #[cfg(feature = "smoltcp")]
-pub use crate::smoltcp::SmolUsb;
+pub use crate::smoltcp::{SmolConfig, SmolIfaceConfig, SmolUsb};
+
+#[cfg(feature = "smoltcp")]
+pub(crate) mod bridge;
+#[cfg(feature = "smoltcp")]
+pub use crate::bridge::{Bridge, FDB_SIZE};
+
+// Not every service needs every helper
+#[cfg(any(
+    feature = "dhcp-server",
+    feature = "router-advertisement",
+    feature = "mdns",
+    feature = "ipv4-link-local",
+    feature = "ping-responder",
+    feature = "ip-only",
+    feature = "napt"
+))]
+#[allow(dead_code)]
+pub(crate) mod wire;
+
+#[cfg(feature = "dhcp-server")]
+pub(crate) mod dhcp;
+#[cfg(feature = "dhcp-server")]
+pub use crate::dhcp::{DhcpConfig, DhcpLease, DhcpServer};
+
+#[cfg(feature = "router-advertisement")]
+pub(crate) mod ra;
+#[cfg(feature = "router-advertisement")]
+pub use crate::ra::{RaConfig, RouterAdvertiser, MAX_RA_PACKET_SIZE};
+
+#[cfg(feature = "mdns")]
+pub(crate) mod mdns;
+#[cfg(feature = "mdns")]
+pub use crate::mdns::{MdnsConfig, MdnsResponder, MdnsService, MAX_MDNS_MESSAGE_SIZE};
+
+#[cfg(feature = "ipv4-link-local")]
+pub(crate) mod ipv4ll;
+#[cfg(feature = "ipv4-link-local")]
+pub use crate::ipv4ll::LinkLocalV4;
+
+#[cfg(feature = "ping-responder")]
+pub(crate) mod ping;
+#[cfg(feature = "ping-responder")]
+pub use crate::ping::PingResponder;
+
+#[cfg(feature = "napt")]
+pub(crate) mod napt;
+#[cfg(feature = "napt")]
+pub use crate::napt::{NaptConfig, NaptRouter, Uplink, NAPT_TABLE_SIZE};
+
+#[cfg(feature = "ip-only")]
+pub(crate) mod ip;
+#[cfg(feature = "ip-only")]
+pub use crate::ip::IpUsb;
+
+#[cfg(feature = "embassy-net-driver")]
+pub(crate) mod embassy;
+#[cfg(feature = "embassy-net-driver")]
+pub use crate::embassy::EmbassyUsb;
 
 // We support both USB 1.1 packets with a size of 64 bytes
 // as well as USB 2.0 packets with a size of 512 bytes.
@@ -36,9 +114,21 @@ const EP_PKG_SIZE: u16 = 512;
 /// EP_PKG_SIZE as USIZE
 const EP_PKG_USIZE: usize = EP_PKG_SIZE as usize;
 
+/// Number of frames, that can be queued in each direction.
+///
+/// Each queue holds one additional frame buffer, which is in the process of being
+/// received from the host or written by the application respectively.
+pub const QUEUE_DEPTH: usize = 2;
+
 /// Length of an ethernet frame
 pub const ETH_FRAME_SIZE: usize = 1514;
 
+/// Minimum length of an ethernet frame, excluding the frame check sequence
+pub const ETH_MIN_FRAME_SIZE: usize = 60;
+
+/// Length of an ethernet header
+pub const ETH_HEADER_SIZE: usize = 14;
+
 /// The device class of this device.
 pub const USB_CLASS_CDC: u8 = 0x02;
 
@@ -46,26 +136,185 @@ pub const USB_CLASS_CDC: u8 = 0x02;
 // TODO: Documentation
 pub struct UsbEthernetDevice<'a, B: UsbBus> {
     ecm: CdcEcmClass<'a, B>,
+    device_mac: [u8; 6],
     tx_buf: TxBuf,
     rx_buf: RxBuf,
+    link: Link,
+    clock: Option<&'a dyn Clock>,
+    kick: Option<&'a dyn TxKick>,
+    wakeup: Option<&'a dyn RemoteWakeup>,
+    events: Option<&'a dyn EventHandler>,
 }
 
 impl<'a, B: UsbBus> UsbEthernetDevice<'a, B> {
     /// Create a new [`UsbEthernetDevice`]('UsbEthernetDevice').
+    ///
+    /// `mac_addr` is reported to the host as the address of its network interface.
+    ///
+    /// NOTE: The address is rewritten into a locally administered unicast address with
+    /// [`local_unicast_mac`], so a globally unique address is not reported as it is.
+    /// The address of the device is derived from the rewritten one by flipping its lowest bit,
+    /// see [`MacAddresses::from_host`].
     pub fn new(alloc: &'a UsbBusAllocator<B>, mac_addr: &[u8; 6]) -> Self {
+        Self::with_mac_addresses(alloc, &MacAddresses::from_host(*mac_addr))
+    }
+
+    /// Create a new [`UsbEthernetDevice`] with distinct mac addresses for the host and the device.
+    pub fn with_mac_addresses(alloc: &'a UsbBusAllocator<B>, mac_addresses: &MacAddresses) -> Self {
         Self {
-            ecm: CdcEcmClass::new(alloc, mac_addr),
+            ecm: CdcEcmClass::new(alloc, &mac_addresses.host()),
+            device_mac: mac_addresses.device(),
             tx_buf: TxBuf::new(),
             rx_buf: RxBuf::new(),
+            link: Link::new(),
+            clock: None,
+            kick: None,
+            wakeup: None,
+            events: None,
         }
     }
 
+    /// Sets the [`Clock`], that is used to timestamp received frames.
+    pub fn set_clock(&mut self, clock: &'a dyn Clock) {
+        self.clock = Some(clock);
+    }
+
+    /// Sets the [`TxKick`] hook, that is called, when a frame has been queued
+    /// through a [`FrameSender`] or a network stack adapter.
+    pub fn set_tx_kick(&mut self, kick: &'a dyn TxKick) {
+        self.kick = Some(kick);
+    }
+
+    /// Sets the [`RemoteWakeup`] hook, that is used with the [`TxSuspendPolicy::Wakeup`].
+    pub fn set_remote_wakeup(&mut self, wakeup: &'a dyn RemoteWakeup) {
+        self.wakeup = Some(wakeup);
+    }
+
+    /// Sets the [`EventHandler`], that gets notified about the [`Event`]s of the USB network lifecycle.
+    pub fn set_event_handler(&mut self, events: &'a dyn EventHandler) {
+        self.events = Some(events);
+    }
+
+    /// Splits the device into its USB side and the application side.
+    ///
+    /// The [`UsbEthernetClass`] implements [`UsbClass`] and needs to be polled by the `usb-device` stack.
+    /// The [`FrameReceiver`] and [`FrameSender`] are used to exchange ethernet frames with the host.
+    ///
+    /// With the `sync` feature enabled, the buffers are synchronized and all halves are `Send`,
+    /// such that they can be moved into different contexts.
+    pub fn split(&mut self) -> (UsbEthernetClass<'_, 'a, B>, FrameReceiver<'_>, FrameSender<'_>) {
+        (
+            UsbEthernetClass::new(
+                &mut self.ecm,
+                &self.tx_buf,
+                &self.rx_buf,
+                &self.link,
+                self.clock,
+                self.events,
+                self.device_mac,
+            ),
+            FrameReceiver::new(&self.rx_buf, &self.link),
+            FrameSender::new(&self.tx_buf, &self.link, self.kick, self.wakeup),
+        )
+    }
+
+    /// Sets the [`RxOverflowPolicy`], that decides what happens to frames arriving from the host,
+    /// while a received frame has not yet been processed.
+    ///
+    /// Defaults to [`RxOverflowPolicy::Backpressure`].
+    pub fn set_rx_overflow_policy(&mut self, policy: RxOverflowPolicy) {
+        self.rx_buf.set_policy(policy);
+    }
+
+    /// Returns the number of frames, that have been dropped due to the [`RxOverflowPolicy`]
+    pub fn dropped_frames(&self) -> u32 {
+        self.rx_buf.dropped_frames()
+    }
+
+    /// Sets the maximum size of a frame received from the host.
+    ///
+    /// Longer frames are rejected and counted as invalid.
+    /// Defaults to, and can not be larger than [`ETH_FRAME_SIZE`].
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.rx_buf.set_max_frame_size(max_frame_size);
+    }
+
+    /// Returns the number of frames, that have been rejected because they were too short
+    /// to hold an ethernet header or longer than the maximum frame size.
+    pub fn invalid_frames(&self) -> u32 {
+        self.rx_buf.invalid_frames()
+    }
+
+    /// If enabled, frames shorter than [`ETH_MIN_FRAME_SIZE`] are zero padded before they are sent.
+    ///
+    /// Some host stacks and bridges drop such short frames. Disabled by default.
+    pub fn set_frame_padding(&mut self, pad: bool) {
+        self.tx_buf.set_pad(pad);
+    }
+
+    /// Passes the state of the `UsbDevice` on to the device.
+    ///
+    /// See [`UsbEthernetClass::update_device_state`].
+    pub fn update_device_state(&mut self, state: UsbDeviceState) {
+        self.split().0.update_device_state(state)
+    }
+
+    /// Returns the mac address, that is reported to the host
+    pub fn mac_address(&self) -> [u8; 6] {
+        self.ecm.mac_address()
+    }
+
+    /// Changes the mac address, that is reported to the host.
+    ///
+    /// Like [`MacAddresses::new`], the address is made a locally administered unicast address
+    /// and the address of the device is changed, if both turn out to be equal.
+    /// See [`UsbEthernetClass::set_mac_address`].
+    pub fn set_mac_address(&mut self, mac_addr: &[u8; 6]) -> bool {
+        self.set_mac_addresses(&MacAddresses::new(*mac_addr, self.device_mac))
+    }
+
+    /// Changes the mac addresses of the host and the device.
+    ///
+    /// Returns `true`, if the address of the host changed, see [`UsbEthernetClass::set_mac_address`].
+    /// The address of the device is picked up by adapters created afterwards.
+    pub fn set_mac_addresses(&mut self, mac_addresses: &MacAddresses) -> bool {
+        self.device_mac = mac_addresses.device();
+        self.split().0.set_mac_address(&mac_addresses.host())
+    }
+
+    /// Returns the mac address of the device, that is used by the network stack adapters
+    pub fn device_mac_address(&self) -> [u8; 6] {
+        self.device_mac
+    }
+
+    /// Returns `true`, if the host has activated the data interface
+    pub fn is_link_up(&self) -> bool {
+        self.link.is_up()
+    }
+
+    /// Returns `true`, if the bus is suspended.
+    ///
+    /// The state is passed on through [`update_device_state`](UsbEthernetDevice::update_device_state).
+    pub fn is_suspended(&self) -> bool {
+        self.link.is_suspended()
+    }
+
+    /// Sets the [`TxSuspendPolicy`], that decides what happens to frames sent by the application,
+    /// while the bus is suspended.
+    ///
+    /// Defaults to [`TxSuspendPolicy::Refuse`].
+    pub fn set_tx_suspend_policy(&mut self, policy: TxSuspendPolicy) {
+        self.tx_buf.set_suspend_policy(policy);
+    }
+
+    /// Returns the number of queued frames, that have been dropped due to the [`TxSuspendPolicy`]
+    pub fn dropped_tx_frames(&self) -> u32 {
+        self.tx_buf.dropped_frames()
+    }
+
     /// Check, wether an ethernet frame is ready to be received
     pub fn frame_ready(&mut self) -> bool {
-        match self.rx_buf.lock_mut() {
-            None => false,
-            Some(buf) => buf.frame_complete(),
-        }
+        self.split().1.frame_ready()
     }
 
     /// Tries to receive an ethernet frame.
@@ -79,55 +328,19 @@ impl<'a, B: UsbBus> UsbEthernetDevice<'a, B> {
     where
         F: FnOnce(&[u8]),
     {
-        #[allow(unused_mut)]
-        let mut buf = match self.rx_buf.lock_mut() {
-            None => return None,
-            Some(buf) => buf,
-        };
-
-        match buf.try_get_frame() {
-            None => None,
-            Some(frame) => {
-                let len = frame.len();
-                f(frame);
-
-                // Reset the buffer after reading it
-                buf.reset();
-                Some(len)
-            }
-        }
+        self.split().1.try_receive_frame(f)
     }
 
-    /// Attempts to receive data into rx_buf
-    fn try_recv(&mut self) {
-        #[allow(unused_mut)]
-        let mut buf = match self.rx_buf.lock_mut() {
-            None => return,
-            Some(buf) => buf,
-        };
-
-        // Do not receive if there is an ethernet packet waiting
-        // The pipe will stall until the ethernet packet gets processed.
-        if buf.frame_complete() {
-            return;
-        }
-
-        // Read a packet from the host
-        match self.ecm.get_read_ep().read(buf.insert_packet()) {
-            Ok(bytes_read) => buf.advance(bytes_read),
-            // This can only be triggered by a a host ingoring our boundaries
-            Err(UsbError::BufferOverflow) => {
-                log::warn!("received more data than fits in one ethernet packet, dropping packet");
-                buf.reset();
-            }
-            // If busy, try again later
-            // FIXME: Should be possible to trigger this, remove?
-            Err(UsbError::WouldBlock) => log::warn!("would block should not be able to happen"),
-            Err(err) => {
-                log::error!("unexpected usb error: {:?}", err);
-                //self.reset();
-            }
-        }
+    /// Tries to receive an ethernet frame together with its receive timestamp.
+    ///
+    /// Works like [`try_receive_frame`](UsbEthernetDevice::try_receive_frame), but the closure
+    /// also gets the time in microseconds at which the frame has been received completely.
+    /// The timestamp is `None`, if no [`Clock`] has been set.
+    pub fn try_receive_frame_timestamped<F>(&mut self, f: F) -> Option<usize>
+    where
+        F: FnOnce(&[u8], Option<u64>),
+    {
+        self.split().1.try_receive_frame_timestamped(f)
     }
 
     /// Tries to send an ethernet frame
@@ -136,87 +349,48 @@ impl<'a, B: UsbBus> UsbEthernetDevice<'a, B> {
     ///
     /// # Returns
     /// - `true`, if the packet was sent
-    /// - `false` otherwise
+    /// - `false` otherwise, e.g. if `len` is shorter than an ethernet header
     pub fn try_send_frame<F>(&mut self, len: usize, f: F) -> bool
     where
         F: FnOnce(&mut [u8]),
     {
-        // If length to big, we simply return
-        if len >= ETH_FRAME_SIZE {
-            return false;
-        }
-
-        #[allow(unused_mut)]
-        let result = match self.tx_buf.lock_mut() {
-            None => false,
-            Some(mut buf) => match buf.try_send_frame(len) {
-                None => false,
-                Some(buf) => {
-                    f(buf);
-                    true
-                }
-            },
-        };
+        let (class, _, mut sender) = self.split();
+        let result = sender.try_send_frame(len, f);
 
         // Trigger sending the first packet
-        self.try_send();
+        class.try_send();
         result
     }
 
-    /// Attempts to write data out to the host from tx_buf
-    fn try_send(&mut self) {
-        #[allow(unused_mut)]
-        let mut buf = match self.tx_buf.lock_mut() {
-            None => return,
-            Some(buf) => buf,
-        };
-
-        // Skip if there is no data to send
-        if !buf.is_sending() {
-            return;
-        }
+    /// Tries to send an ethernet frame, that is assembled from multiple parts.
+    ///
+    /// This allows to send e.g. a separately held header and payload, without
+    /// first copying them into a scratch buffer.
+    ///
+    /// # Returns
+    /// - `true`, if the packet was sent
+    /// - `false` otherwise
+    pub fn try_send_frame_vectored(&mut self, parts: &[&[u8]]) -> bool {
+        let (class, _, mut sender) = self.split();
+        let result = sender.try_send_frame_vectored(parts);
 
-        // Retreive the packet
-        let pkg = match buf.try_get_packet() {
-            None => return,
-            Some(pkg) => pkg,
-        };
-
-        // Send the packet to the host
-        match self.ecm.get_write_ep().write(pkg) {
-            Ok(bytes_written) if pkg.len() == bytes_written => buf.advance(bytes_written),
-            Ok(bytes_written) => {
-                log::error!("wrote {} bytes, expected {}", bytes_written, pkg.len());
-                //self.reset();
-            }
-            Err(UsbError::WouldBlock) => (),
-            Err(err) => {
-                log::error!("received unexpected error {:?}", err);
-                //self.reset();
-            }
-        }
+        // Trigger sending the first packet
+        class.try_send();
+        result
     }
 }
 
 impl<B: UsbBus> UsbClass<B> for UsbEthernetDevice<'_, B> {
     fn endpoint_out(&mut self, addr: EndpointAddress) {
-        if addr == self.ecm.get_read_ep().address() {
-            self.try_recv();
-        }
+        self.split().0.endpoint_out(addr)
     }
 
     fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
-        if addr == self.ecm.get_write_ep().address() {
-            self.try_send();
-        }
+        self.split().0.endpoint_in_complete(addr)
     }
 
     fn reset(&mut self) {
-        // TODO: How to do this threadsafe?
-        //self.tx_idx = 0;
-        //self.tx_len = 0;
-        //self.rx_idx = 0;
-        //self.rx_complete = false;
+        self.split().0.reset()
     }
 
     // Pass through the control and setup calls
@@ -229,16 +403,14 @@ impl<B: UsbBus> UsbClass<B> for UsbEthernetDevice<'_, B> {
     }
 
     fn control_in(&mut self, xfer: ControlIn<B>) {
-        self.ecm.control_in(xfer);
+        self.split().0.control_in(xfer);
     }
 
     fn control_out(&mut self, xfer: ControlOut<B>) {
-        self.ecm.control_out(xfer);
+        self.split().0.control_out(xfer);
     }
 
     fn poll(&mut self) {
-        // NOTE: We can not trigger try_send from the `smoltcp` side.
-        // Therefore we rely on poll to pick up and send out the data
-        self.try_send()
+        self.split().0.poll()
     }
 }
@@ -0,0 +1,68 @@
+//! This module tracks the state of the link to the host.
+//!
+//! The link is up, once the host activated the data interface of the ECM function
+//! by selecting its alternate setting with the data endpoints.
+//! Independently of that, the bus can be suspended by the host.
+
+use crate::buffer::WakerCell;
+use core::{
+   sync::atomic::{AtomicBool, Ordering},
+   task::Waker,
+};
+
+#[derive(Debug)]
+pub struct Link {
+   up: AtomicBool,
+   suspended: AtomicBool,
+   waker: WakerCell,
+}
+
+impl Link {
+   pub fn new() -> Self {
+      Self {
+         up: AtomicBool::new(false),
+         suspended: AtomicBool::new(false),
+         waker: WakerCell::new(),
+      }
+   }
+
+   /// Returns `true`, if the link to the host is up
+   pub fn is_up(&self) -> bool {
+      self.up.load(Ordering::Acquire)
+   }
+
+   /// Sets the link state and wakes the task waiting for a change.
+   /// Returns `true`, if the state has changed.
+   ///
+   /// NOTE: Only the USB side changes the link state, therefore we do not need an atomic swap.
+   pub fn set_up(&self, up: bool) -> bool {
+      if self.is_up() == up {
+         return false;
+      }
+
+      self.up.store(up, Ordering::Release);
+      self.waker.wake();
+      true
+   }
+
+   /// Returns `true`, if the bus is suspended
+   pub fn is_suspended(&self) -> bool {
+      self.suspended.load(Ordering::Acquire)
+   }
+
+   /// Sets the suspend state.
+   /// Returns `true`, if the state has changed.
+   pub fn set_suspended(&self, suspended: bool) -> bool {
+      if self.is_suspended() == suspended {
+         return false;
+      }
+
+      self.suspended.store(suspended, Ordering::Release);
+      true
+   }
+
+   /// Registers the waker of a task, that waits for the link state to change
+   pub fn register_waker(&self, waker: &Waker) {
+      self.waker.register(waker);
+   }
+}
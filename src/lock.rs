@@ -1,117 +1,136 @@
-//! This module contains very basic lock implementation based on atomic bool.
+//! This module contains a very basic lock implementation based on the `critical-section` crate.
 //!
-//! This is needed to allow to synchronize the `usb-device` and `smoltcp` sides
+//! This is needed to allow to synchronize the USB side and the application side
 //! of this crate with each other.
 //!
-//! We chose to implement a small lock on our own, to stay independent from the underlying hardware.
-//! This lock only supports two accesors, and both have to be created in the same moment.
+//! The lock state is only ever touched inside of a critical section, which makes the lock
+//! work on every platform, that provides a `critical-section` implementation,
+//! including the ones without atomic compare and swap instructions.
+//! The critical section only spans the acquisition and the release of the lock, not
+//! the time the lock is held.
+//! The lock never blocks, if it is contended, `try_lock` simply fails.
 
 use core::{
-   marker::PhantomData,
+   cell::{Cell, UnsafeCell},
+   fmt,
    ops::{Deref, DerefMut},
-   sync::atomic::{AtomicBool, Ordering},
 };
+use critical_section::Mutex;
 
-#[derive(Debug)]
-pub struct Lock<T>(LockInner<T>);
-
-impl<T> Lock<T> {
-   pub fn new(data: T) -> Self {
-      Self(LockInner::new(data))
-   }
-
-   pub fn get_handle(&self) -> LockHandle<T> {
-      LockHandle::new(self.0.as_ptr())
-   }
-
-   pub fn try_lock(&self) -> Option<Guard<T>> {
-      self.0.try_lock()
-   }
+pub struct Lock<T> {
+   locked: Mutex<Cell<bool>>,
+   data: UnsafeCell<T>,
 }
 
-#[derive(Debug)]
-pub struct LockHandle<'a, T> {
-   lt: &'a PhantomData<()>,
-   inner: *mut LockInner<T>,
-}
+// SAFETY: Access to `data` is only possible through a `Guard`, and the `locked` flag
+// guarantees, that at most one `Guard` exists at any time.
+// The data is moved between contexts, therefore it needs to be `Send`.
+unsafe impl<T: Send> Sync for Lock<T> {}
 
-impl<'a, T> LockHandle<'a, T> {
-   pub fn new(inner: *mut LockInner<T>) -> Self {
+impl<T> Lock<T> {
+   pub fn new(data: T) -> Self {
       Self {
-         lt: &PhantomData,
-         inner,
+         locked: Mutex::new(Cell::new(false)),
+         data: UnsafeCell::new(data),
       }
    }
 
-   pub fn try_lock(&'a self) -> Option<Guard<'a, T>> {
-      let inner: &mut LockInner<T> = unsafe { &mut *self.inner };
-      inner.try_lock()
+   pub fn try_lock(&self) -> Option<Guard<'_, T>> {
+      critical_section::with(|cs| {
+         let locked = self.locked.borrow(cs);
+         match locked.replace(true) {
+            false => Some(Guard(self)),
+            true => None,
+         }
+      })
    }
 }
 
-impl<'a, T> Clone for LockHandle<'a, T> {
-   fn clone(&self) -> Self {
-      Self {
-         lt: &PhantomData,
-         inner: self.inner,
-      }
+impl<T> fmt::Debug for Lock<T> {
+   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      let locked = critical_section::with(|cs| self.locked.borrow(cs).get());
+      f.debug_struct("Lock").field("locked", &locked).finish()
    }
 }
 
+/// Gives access to the data of a [`Lock`], which is released once the guard is dropped.
+///
+/// The guard borrows the lock, and can therefore not outlive it.
 #[derive(Debug)]
-pub struct LockInner<T> {
-   lock: AtomicBool,
-   data: T,
-   // This is to make LockInner `!Send` and `!Sync`.
-   unsend: *const PhantomData<()>,
-}
+pub struct Guard<'a, T>(&'a Lock<T>);
 
-impl<T> LockInner<T> {
-   fn new(data: T) -> Self {
-      Self {
-         lock: AtomicBool::new(false),
-         data,
-         unsend: &PhantomData as *const PhantomData<()>,
-      }
-   }
-}
-
-impl<T> LockInner<T> {
-   fn try_lock(&self) -> Option<Guard<T>> {
-      let was_locked = self
-         .lock
-         .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst);
-
-      match was_locked {
-         Ok(false) => Some(Guard(LockHandle::new(self.as_ptr()))),
-         _ => None,
-      }
-   }
-
-   fn as_ptr(&self) -> *mut Self {
-      self as *const Self as *mut Self
-   }
-}
-
-#[derive(Debug)]
-pub struct Guard<'a, T>(LockHandle<'a, T>);
-
-impl<'a, T> Deref for Guard<'a, T> {
+impl<T> Deref for Guard<'_, T> {
    type Target = T;
    fn deref(&self) -> &T {
-      &unsafe { &*self.0.inner }.data
+      // SAFETY: We hold the lock
+      unsafe { &*self.0.data.get() }
    }
 }
 
 impl<T> DerefMut for Guard<'_, T> {
    fn deref_mut(&mut self) -> &mut T {
-      &mut unsafe { &mut *self.0.inner }.data
+      // SAFETY: We hold the lock
+      unsafe { &mut *self.0.data.get() }
    }
 }
 
 impl<T> Drop for Guard<'_, T> {
    fn drop(&mut self) {
-      let inner: &mut LockInner<T> = unsafe { &mut *self.0.inner };
-      inner.lock.store(false, Ordering::SeqCst);
+      critical_section::with(|cs| self.0.locked.borrow(cs).set(false));
+   }
+}
+
+// NOTE: These tests are meant to be run under Miri as well: `cargo +nightly miri test`
+#[cfg(test)]
+mod tests {
+   use super::*;
+   extern crate std;
+   use std::{sync::Arc, thread, vec::Vec};
+
+   #[test]
+   fn lock_is_exclusive() {
+      let lock = Lock::new(0u32);
+
+      let mut guard = lock.try_lock().unwrap();
+      assert!(lock.try_lock().is_none());
+      *guard += 1;
+      drop(guard);
+
+      let guard = lock.try_lock().unwrap();
+      assert_eq!(*guard, 1);
+   }
+
+   #[test]
+   fn lock_is_shared_between_threads() {
+      const ITERATIONS: usize = 100;
+      let lock = Arc::new(Lock::new([0u8; 16]));
+
+      let threads: Vec<_> = (1..=2u8)
+         .map(|id| {
+            let lock = lock.clone();
+            thread::spawn(move || {
+               let mut acquired = 0;
+               while acquired < ITERATIONS {
+                  let mut guard = match lock.try_lock() {
+                     None => {
+                        thread::yield_now();
+                        continue;
+                     }
+                     Some(guard) => guard,
+                  };
+
+                  // Nobody else may write into the data, while we hold the guard
+                  guard.iter_mut().for_each(|byte| *byte = id);
+                  thread::yield_now();
+                  assert!(guard.iter().all(|byte| *byte == id));
+                  acquired += 1;
+               }
+            })
+         })
+         .collect();
+
+      for thread in threads {
+         thread.join().unwrap();
+      }
    }
 }
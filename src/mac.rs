@@ -0,0 +1,104 @@
+//! This module contains the mac addresses of both ends of the USB link.
+//!
+//! The mac address in the ECM descriptors is the one of the network interface on the host,
+//! while the network stack on the device needs a different one.
+//! Both are always locally administered unicast addresses, see [`local_unicast_mac`],
+//! also if an address from a globally unique range is passed in.
+
+/// Marks an address as locally administered, rather than assigned by the manufacturer
+const LOCALLY_ADMINISTERED_BIT: u8 = 0x02;
+/// Marks an address as a group address
+const MULTICAST_BIT: u8 = 0x01;
+
+/// Returns `mac` as a locally administered unicast address
+pub fn local_unicast_mac(mut mac: [u8; 6]) -> [u8; 6] {
+   mac[0] = (mac[0] | LOCALLY_ADMINISTERED_BIT) & !MULTICAST_BIT;
+   mac
+}
+
+/// The mac addresses of the host and the device.
+///
+/// Both addresses are guaranteed to be distinct, locally administered unicast addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MacAddresses {
+   host: [u8; 6],
+   device: [u8; 6],
+}
+
+impl MacAddresses {
+   /// Uses `host` and `device`, with the locally administered bit set and the multicast bit cleared.
+   ///
+   /// If they turn out to be equal, the lowest bit of the device address is flipped.
+   pub fn new(host: [u8; 6], device: [u8; 6]) -> Self {
+      let host = local_unicast_mac(host);
+      let mut device = local_unicast_mac(device);
+      if device == host {
+         device[5] ^= 0x01;
+      }
+      Self { host, device }
+   }
+
+   /// Derives the addresses from the host address, e.g. an address, that has been used so far.
+   pub fn from_host(host: [u8; 6]) -> Self {
+      Self::new(host, host)
+   }
+
+   /// Derives both addresses from a unique id, e.g. the unique id of the MCU
+   /// or the USB serial number, such that they are stable across reboots.
+   pub fn from_unique_id(id: &[u8]) -> Self {
+      Self::new(hash_mac(b'h', id), hash_mac(b'd', id))
+   }
+
+   /// Returns the address of the host, that is reported in the ECM descriptors
+   pub fn host(&self) -> [u8; 6] {
+      self.host
+   }
+
+   /// Returns the address of the device, that is used by its network stack
+   pub fn device(&self) -> [u8; 6] {
+      self.device
+   }
+}
+
+/// Hashes `id` with FNV-1a, prefixed by `domain`, and returns the lower 48 bits
+fn hash_mac(domain: u8, id: &[u8]) -> [u8; 6] {
+   let hash = core::iter::once(&domain)
+      .chain(id)
+      .fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+         (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+      });
+
+   let mut mac = [0; 6];
+   mac.copy_from_slice(&hash.to_be_bytes()[2..]);
+   mac
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn is_local_unicast(mac: [u8; 6]) -> bool {
+      mac[0] & LOCALLY_ADMINISTERED_BIT != 0 && mac[0] & MULTICAST_BIT == 0
+   }
+
+   #[test]
+   fn addresses_are_local_unicast_and_distinct() {
+      for macs in [
+         MacAddresses::new([0x01, 2, 3, 4, 5, 6], [0x00, 2, 3, 4, 5, 6]),
+         MacAddresses::from_host([0xff; 6]),
+         MacAddresses::from_unique_id(&[0x12, 0x34, 0x56, 0x78]),
+         MacAddresses::from_unique_id(b""),
+      ] {
+         assert!(is_local_unicast(macs.host()));
+         assert!(is_local_unicast(macs.device()));
+         assert_ne!(macs.host(), macs.device());
+      }
+   }
+
+   #[test]
+   fn unique_id_derivation_is_stable() {
+      let macs = MacAddresses::from_unique_id(b"TEST");
+      assert_eq!(macs, MacAddresses::from_unique_id(b"TEST"));
+      assert_ne!(macs, MacAddresses::from_unique_id(b"TEST2"));
+   }
+}
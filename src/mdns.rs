@@ -0,0 +1,610 @@
+//! This module implements a minimal mDNS responder, RFC 6762, such that the device can be
+//! reached as `<hostname>.local` from the host, and optionally a DNS-SD service, RFC 6763,
+//! such that it can be discovered.
+//!
+//! Like the [`DhcpServer`](crate::DhcpServer), it works on raw ethernet frames:
+//! Frames received from the host are passed to [`MdnsResponder::process`] first, which answers
+//! queries over IPv4 and IPv6 through the [`FrameSender`].
+//! With a network stack, the messages received on a UDP socket bound to port 5353, which joined
+//! the mDNS group, are passed to [`MdnsResponder::process_message`] instead.
+//!
+//! Only multicast responses are sent, legacy unicast queries from ports other than 5353 are
+//! not answered.
+//! The names are not probed for uniqueness, so they need to be unique on the USB link.
+
+use crate::{
+   wire::{
+      emit_udp6_frame, emit_udp_frame, ipv4_multicast_mac, ipv6_link_local, ipv6_multicast_mac, read_u16, write_u16,
+      EthernetHeader, Ipv4Header, Ipv6Header, UdpHeader, ETHERTYPE_IPV4, ETHERTYPE_IPV6, IP_PROTO_UDP,
+      UDP6_PAYLOAD_OFFSET, UDP_PAYLOAD_OFFSET,
+   },
+   FrameSender,
+};
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_GROUP_V4: [u8; 4] = [224, 0, 0, 251];
+const MDNS_GROUP_V6: [u8; 16] = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xfb];
+
+/// Responses are sent with the maximum hop limit, RFC 6762 section 11
+const MDNS_HOP_LIMIT: u8 = 255;
+
+/// The size of the messages, that are sent at most
+pub const MAX_MDNS_MESSAGE_SIZE: usize = 512;
+
+const HEADER_SIZE: usize = 12;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const OPCODE_MASK: u16 = 0x7800;
+
+// Record types and classes
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// The top bit of the class requests a unicast response in a question,
+/// and flushes the caches of the host in a record
+const CLASS_TOP_BIT: u16 = 0x8000;
+
+/// The maximum length of a domain name
+const MAX_NAME_SIZE: usize = 255;
+
+/// The name, under which all services on the link are enumerated, RFC 6763 section 9
+const SERVICES_NAME: &[&str] = &["_services", "_dns-sd", "_udp", "local"];
+
+/// A DNS-SD service, that is announced by the [`MdnsResponder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MdnsService<'c> {
+   /// The name of the instance, e.g. `My Device`, without dots
+   pub instance: &'c str,
+   /// The service type, e.g. `_http._tcp`
+   pub service_type: &'c str,
+   /// The port, the service is reachable on
+   pub port: u16,
+   /// The `key=value` pairs of the TXT record
+   pub txt: &'c [&'c str],
+}
+
+/// Configures the [`MdnsResponder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MdnsConfig<'c> {
+   /// The mac address, the responses are sent from
+   pub mac: [u8; 6],
+   /// The host name without `.local`
+   pub hostname: &'c str,
+   /// The IPv4 address, that is answered for the host name, if any.
+   /// Queries over IPv4 are only answered, if the device has an IPv4 address.
+   pub ipv4_addr: Option<[u8; 4]>,
+   /// The IPv6 address, that is answered for the host name, if any.
+   /// Queries over IPv6 are answered from the link-local address derived from `mac`.
+   pub ipv6_addr: Option<[u8; 16]>,
+   /// The service, that is announced, if any
+   pub service: Option<MdnsService<'c>>,
+   /// The time in seconds, for which the host caches the records
+   pub ttl: u32,
+}
+
+/// Answers mDNS queries for the device.
+#[derive(Debug, Clone)]
+pub struct MdnsResponder<'c> {
+   config: MdnsConfig<'c>,
+}
+
+/// The records, that are sent in a response
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Records {
+   a: bool,
+   aaaa: bool,
+   ptr: bool,
+   srv: bool,
+   txt: bool,
+   services: bool,
+}
+
+impl Records {
+   fn is_empty(&self) -> bool {
+      *self == Self::default()
+   }
+
+   fn count(&self) -> u16 {
+      [self.a, self.aaaa, self.ptr, self.srv, self.txt, self.services].iter().filter(|r| **r).count() as u16
+   }
+}
+
+impl<'c> MdnsResponder<'c> {
+   pub fn new(config: MdnsConfig<'c>) -> Self {
+      Self { config }
+   }
+
+   /// Changes the IPv4 address, that is answered, e.g. once a link-local address is claimed
+   pub fn set_ipv4_addr(&mut self, addr: Option<[u8; 4]>) {
+      self.config.ipv4_addr = addr;
+   }
+
+   /// Changes the IPv6 address, that is answered
+   pub fn set_ipv6_addr(&mut self, addr: Option<[u8; 16]>) {
+      self.config.ipv6_addr = addr;
+   }
+
+   /// Processes a frame received from the host.
+   ///
+   /// # Returns
+   /// - `true`, if the frame was an mDNS message, which should not be passed on
+   /// - `false` otherwise
+   pub fn process(&self, frame: &[u8], sender: &mut FrameSender) -> bool {
+      let mut reply = [0; UDP6_PAYLOAD_OFFSET + MAX_MDNS_MESSAGE_SIZE];
+      match self.respond(frame, &mut reply) {
+         None => false,
+         Some(0) => true,
+         Some(len) => {
+            if !sender.try_send_frame(len, |buf| buf.copy_from_slice(&reply[..len])) {
+               log::debug!("transmit queue full, dropping mdns response");
+            }
+            true
+         }
+      }
+   }
+
+   /// Processes an mDNS message received on UDP port 5353, e.g. through a `smoltcp` socket.
+   ///
+   /// If the message is a query for the records of the device, the response is written into
+   /// `reply`, which needs to hold [`MAX_MDNS_MESSAGE_SIZE`] bytes, and its length is returned.
+   /// The response has to be sent to the mDNS group, `224.0.0.251` or `ff02::fb`, port 5353.
+   /// Returns `None`, if `reply` is too short.
+   pub fn process_message(&self, message: &[u8], reply: &mut [u8]) -> Option<usize> {
+      if reply.len() < MAX_MDNS_MESSAGE_SIZE {
+         log::warn!("reply buffer of {} bytes is too short for an mdns message", reply.len());
+         return None;
+      }
+      let records = self.parse_query(message)?;
+      if records.is_empty() {
+         return None;
+      }
+
+      let len = self.emit_message(records, &mut reply[..MAX_MDNS_MESSAGE_SIZE]);
+      if len.is_none() {
+         log::debug!("mdns response does not fit into a message");
+      }
+      len
+   }
+
+   /// Writes the response to `frame` into `reply`.
+   ///
+   /// Returns `None`, if the frame is not an mDNS message and `Some(0)`,
+   /// if it is one, that does not need a reply.
+   fn respond(&self, frame: &[u8], reply: &mut [u8]) -> Option<usize> {
+      let (eth, packet) = EthernetHeader::parse(frame)?;
+      match eth.ethertype {
+         ETHERTYPE_IPV4 => {
+            let (ip, datagram) = Ipv4Header::parse(packet)?;
+            if ip.protocol != IP_PROTO_UDP {
+               return None;
+            }
+
+            let (udp, message) = UdpHeader::parse(&ip, datagram)?;
+            if udp.dst_port != MDNS_PORT {
+               return None;
+            }
+
+            let src = match self.config.ipv4_addr {
+               Some(src) if udp.src_port == MDNS_PORT => src,
+               _ => return Some(0),
+            };
+            let len = match self.process_message(message, &mut reply[UDP_PAYLOAD_OFFSET..]) {
+               None => return Some(0),
+               Some(len) => len,
+            };
+
+            let eth = EthernetHeader {
+               dst: ipv4_multicast_mac(&MDNS_GROUP_V4),
+               src: self.config.mac,
+               ethertype: ETHERTYPE_IPV4,
+            };
+            let ip = Ipv4Header {
+               src,
+               dst: MDNS_GROUP_V4,
+               protocol: IP_PROTO_UDP,
+            };
+            Some(emit_udp_frame(reply, &eth, &ip, &Self::udp_header(), len))
+         }
+         ETHERTYPE_IPV6 => {
+            let (ip, datagram) = Ipv6Header::parse(packet)?;
+            if ip.next_header != IP_PROTO_UDP {
+               return None;
+            }
+
+            let (udp, message) = UdpHeader::parse(&ip, datagram)?;
+            if udp.dst_port != MDNS_PORT {
+               return None;
+            }
+
+            if udp.src_port != MDNS_PORT {
+               return Some(0);
+            }
+            let len = match self.process_message(message, &mut reply[UDP6_PAYLOAD_OFFSET..]) {
+               None => return Some(0),
+               Some(len) => len,
+            };
+
+            let eth = EthernetHeader {
+               dst: ipv6_multicast_mac(&MDNS_GROUP_V6),
+               src: self.config.mac,
+               ethertype: ETHERTYPE_IPV6,
+            };
+            let ip = Ipv6Header {
+               src: ipv6_link_local(&self.config.mac),
+               dst: MDNS_GROUP_V6,
+               next_header: IP_PROTO_UDP,
+               hop_limit: MDNS_HOP_LIMIT,
+            };
+            Some(emit_udp6_frame(reply, &eth, &ip, &Self::udp_header(), len))
+         }
+         _ => None,
+      }
+   }
+
+   fn udp_header() -> UdpHeader {
+      UdpHeader {
+         src_port: MDNS_PORT,
+         dst_port: MDNS_PORT,
+      }
+   }
+
+   /// Returns the records, that answer the questions of a query
+   fn parse_query(&self, message: &[u8]) -> Option<Records> {
+      if message.len() < HEADER_SIZE || read_u16(message, 2) & (FLAG_RESPONSE | OPCODE_MASK) != 0 {
+         return None;
+      }
+
+      let mut host = Name::new();
+      host.push_labels(&[self.config.hostname, "local"]);
+      let (mut service, mut instance, mut services) = (Name::new(), Name::new(), Name::new());
+      if let Some(svc) = &self.config.service {
+         service.push_labels(&[svc.service_type, "local"]);
+         instance.push_labels(&[svc.instance, svc.service_type, "local"]);
+         services.push_labels(SERVICES_NAME);
+      }
+
+      let mut records = Records::default();
+      let mut offset = HEADER_SIZE;
+      for _ in 0..read_u16(message, 4) {
+         let (name, next) = Name::parse(message, offset)?;
+         if next + 4 > message.len() {
+            return None;
+         }
+         let (qtype, qclass) = (read_u16(message, next), read_u16(message, next + 2) & !CLASS_TOP_BIT);
+         offset = next + 4;
+
+         if qclass != CLASS_IN {
+            continue;
+         }
+         let matches = |name_type| qtype == name_type || qtype == TYPE_ANY;
+         if name.eq(&host) {
+            records.a |= matches(TYPE_A) && self.config.ipv4_addr.is_some();
+            records.aaaa |= matches(TYPE_AAAA) && self.config.ipv6_addr.is_some();
+         } else if self.config.service.is_some() {
+            if name.eq(&service) {
+               records.ptr |= matches(TYPE_PTR);
+            } else if name.eq(&instance) {
+               records.srv |= matches(TYPE_SRV);
+               records.txt |= matches(TYPE_TXT);
+            } else if name.eq(&services) {
+               records.services |= matches(TYPE_PTR);
+            }
+         }
+      }
+      Some(records)
+   }
+
+   /// Writes the response with the `answers` into `message` and returns its length,
+   /// or `None`, if it does not fit
+   fn emit_message(&self, answers: Records, message: &mut [u8]) -> Option<usize> {
+      // The records needed to resolve the answers are added, RFC 6763 section 12
+      let mut additional = Records::default();
+      if answers.ptr {
+         additional.srv = !answers.srv;
+         additional.txt = !answers.txt;
+      }
+      if answers.ptr || answers.srv {
+         additional.a = !answers.a && self.config.ipv4_addr.is_some();
+         additional.aaaa = !answers.aaaa && self.config.ipv6_addr.is_some();
+      }
+
+      let mut writer = MessageWriter { buf: message, idx: 0 };
+      writer.bytes(&[0; HEADER_SIZE])?;
+      self.write_records(&mut writer, answers)?;
+      self.write_records(&mut writer, additional)?;
+
+      let len = writer.idx;
+      write_u16(message, 2, FLAG_RESPONSE | FLAG_AUTHORITATIVE);
+      write_u16(message, 6, answers.count());
+      write_u16(message, 10, additional.count());
+      Some(len)
+   }
+
+   fn write_records(&self, writer: &mut MessageWriter, records: Records) -> Option<()> {
+      let config = &self.config;
+      let mut host = Name::new();
+      host.push_labels(&[config.hostname, "local"]);
+
+      if let (true, Some(addr)) = (records.a, config.ipv4_addr) {
+         writer.record(&host, TYPE_A, true, config.ttl, |w| w.bytes(&addr))?;
+      }
+      if let (true, Some(addr)) = (records.aaaa, config.ipv6_addr) {
+         writer.record(&host, TYPE_AAAA, true, config.ttl, |w| w.bytes(&addr))?;
+      }
+
+      let svc = match &config.service {
+         None => return Some(()),
+         Some(svc) => svc,
+      };
+      let mut service = Name::new();
+      service.push_labels(&[svc.service_type, "local"]);
+      let mut instance = Name::new();
+      instance.push_labels(&[svc.instance, svc.service_type, "local"]);
+
+      if records.services {
+         let mut services = Name::new();
+         services.push_labels(SERVICES_NAME);
+         writer.record(&services, TYPE_PTR, false, config.ttl, |w| w.bytes(service.as_bytes()))?;
+      }
+      if records.ptr {
+         writer.record(&service, TYPE_PTR, false, config.ttl, |w| w.bytes(instance.as_bytes()))?;
+      }
+      if records.srv {
+         writer.record(&instance, TYPE_SRV, true, config.ttl, |w| {
+            // Priority and weight
+            w.bytes(&[0; 4])?;
+            w.bytes(&svc.port.to_be_bytes())?;
+            w.bytes(host.as_bytes())
+         })?;
+      }
+      if records.txt {
+         writer.record(&instance, TYPE_TXT, true, config.ttl, |w| {
+            // A TXT record holds at least one string, which may be empty
+            if svc.txt.is_empty() {
+               return w.bytes(&[0]);
+            }
+            for entry in svc.txt {
+               w.bytes(&[entry.len().min(255) as u8])?;
+               w.bytes(&entry.as_bytes()[..entry.len().min(255)])?;
+            }
+            Some(())
+         })?;
+      }
+      Some(())
+   }
+}
+
+/// A domain name in its uncompressed wire format
+struct Name {
+   buf: [u8; MAX_NAME_SIZE],
+   len: usize,
+}
+
+impl Name {
+   fn new() -> Self {
+      Self {
+         buf: [0; MAX_NAME_SIZE],
+         len: 0,
+      }
+   }
+
+   /// Parses the name at `offset` and returns it together with the offset following it
+   fn parse(message: &[u8], mut offset: usize) -> Option<(Self, usize)> {
+      let mut name = Self::new();
+      let mut end = None;
+
+      // Every pointer needs to point before the previous one, which rules out loops
+      let mut limit = offset;
+      loop {
+         let len = *message.get(offset)? as usize;
+         match len {
+            0 => break,
+            1..=63 => {
+               let label = message.get(offset + 1..offset + 1 + len)?;
+               name.push_label(label)?;
+               offset += 1 + len;
+            }
+            0xc0..=0xff => {
+               let target = read_u16(message.get(offset..offset + 2)?, 0) as usize & 0x3fff;
+               end.get_or_insert(offset + 2);
+               if target >= limit.min(offset) {
+                  return None;
+               }
+               limit = target;
+               offset = target;
+            }
+            _ => return None,
+         }
+      }
+      name.buf[name.len] = 0;
+      name.len += 1;
+      Some((name, end.unwrap_or(offset + 1)))
+   }
+
+   /// Appends the dot separated labels of every part and terminates the name
+   fn push_labels(&mut self, parts: &[&str]) {
+      for label in parts.iter().flat_map(|part| part.split('.')) {
+         if !label.is_empty() && label.len() <= 63 && self.push_label(label.as_bytes()).is_none() {
+            break;
+         }
+      }
+      if self.len < MAX_NAME_SIZE {
+         self.buf[self.len] = 0;
+         self.len += 1;
+      }
+   }
+
+   /// Appends a label, leaving room for the terminating zero
+   fn push_label(&mut self, label: &[u8]) -> Option<()> {
+      if self.len + 1 + label.len() >= MAX_NAME_SIZE {
+         return None;
+      }
+      self.buf[self.len] = label.len() as u8;
+      self.buf[self.len + 1..self.len + 1 + label.len()].copy_from_slice(label);
+      self.len += 1 + label.len();
+      Some(())
+   }
+
+   fn as_bytes(&self) -> &[u8] {
+      &self.buf[..self.len]
+   }
+
+   /// Names are compared case-insensitively
+   fn eq(&self, other: &Self) -> bool {
+      self.as_bytes().eq_ignore_ascii_case(other.as_bytes())
+   }
+}
+
+/// Appends records to a message
+struct MessageWriter<'a> {
+   buf: &'a mut [u8],
+   idx: usize,
+}
+
+impl MessageWriter<'_> {
+   fn bytes(&mut self, data: &[u8]) -> Option<()> {
+      self.buf.get_mut(self.idx..self.idx + data.len())?.copy_from_slice(data);
+      self.idx += data.len();
+      Some(())
+   }
+
+   /// Writes a record, whose data is written by `rdata`.
+   /// `unique` records flush the other records of the same name from the caches.
+   fn record<F>(&mut self, name: &Name, rtype: u16, unique: bool, ttl: u32, rdata: F) -> Option<()>
+   where
+      F: FnOnce(&mut Self) -> Option<()>,
+   {
+      let class = match unique {
+         true => CLASS_IN | CLASS_TOP_BIT,
+         false => CLASS_IN,
+      };
+      self.bytes(name.as_bytes())?;
+      self.bytes(&rtype.to_be_bytes())?;
+      self.bytes(&class.to_be_bytes())?;
+      self.bytes(&ttl.to_be_bytes())?;
+
+      let len_idx = self.idx;
+      self.bytes(&[0; 2])?;
+      rdata(self)?;
+      let len = self.idx - len_idx - 2;
+      write_u16(self.buf, len_idx, len as u16);
+      Some(())
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::ETH_HEADER_SIZE;
+
+   fn responder() -> MdnsResponder<'static> {
+      MdnsResponder::new(MdnsConfig {
+         mac: [0x02, 0, 0, 0, 0, 1],
+         hostname: "mydevice",
+         ipv4_addr: Some([169, 254, 7, 1]),
+         ipv6_addr: None,
+         service: Some(MdnsService {
+            instance: "My Device",
+            service_type: "_http._tcp",
+            port: 80,
+            txt: &["path=/"],
+         }),
+         ttl: 120,
+      })
+   }
+
+   /// Builds a query with a single question
+   fn query(buf: &mut [u8], name: &[&str], qtype: u16) -> usize {
+      let mut qname = Name::new();
+      qname.push_labels(name);
+      buf[..HEADER_SIZE].fill(0);
+      write_u16(buf, 4, 1);
+      let mut writer = MessageWriter { buf, idx: HEADER_SIZE };
+      writer.bytes(qname.as_bytes()).unwrap();
+      writer.bytes(&qtype.to_be_bytes()).unwrap();
+      writer.bytes(&CLASS_IN.to_be_bytes()).unwrap();
+      writer.idx
+   }
+
+   /// Returns the types of the answers and additional records of a response
+   fn record_types(message: &[u8]) -> ([u16; 4], [u16; 4]) {
+      let mut types = [[0; 4], [0; 4]];
+      let mut offset = HEADER_SIZE;
+      for (section, count) in [(0, read_u16(message, 6)), (1, read_u16(message, 10))] {
+         for record_type in types[section].iter_mut().take(count as usize) {
+            let (_, next) = Name::parse(message, offset).unwrap();
+            *record_type = read_u16(message, next);
+            offset = next + 10 + read_u16(message, next + 8) as usize;
+         }
+      }
+      assert_eq!(offset, message.len());
+      (types[0], types[1])
+   }
+
+   #[test]
+   fn host_name_is_answered() {
+      let responder = responder();
+      let (mut message, mut reply) = ([0; 100], [0; MAX_MDNS_MESSAGE_SIZE]);
+
+      let len = query(&mut message, &["MyDevice", "local"], TYPE_A);
+      let reply_len = responder.process_message(&message[..len], &mut reply).unwrap();
+      assert_eq!(record_types(&reply[..reply_len]), ([TYPE_A, 0, 0, 0], [0; 4]));
+      assert_eq!(&reply[reply_len - 4..reply_len], &[169, 254, 7, 1]);
+      let short = &mut reply[..MAX_MDNS_MESSAGE_SIZE - 1];
+      assert!(responder.process_message(&message[..len], short).is_none());
+
+      // There is no IPv6 address, and other names are not answered
+      let len = query(&mut message, &["mydevice", "local"], TYPE_AAAA);
+      assert!(responder.process_message(&message[..len], &mut reply).is_none());
+      let len = query(&mut message, &["other", "local"], TYPE_ANY);
+      assert!(responder.process_message(&message[..len], &mut reply).is_none());
+   }
+
+   #[test]
+   fn service_is_answered_with_additional_records() {
+      let responder = responder();
+      let (mut message, mut reply) = ([0; 100], [0; MAX_MDNS_MESSAGE_SIZE]);
+
+      let len = query(&mut message, &["_http._tcp", "local"], TYPE_PTR);
+      let reply_len = responder.process_message(&message[..len], &mut reply).unwrap();
+      assert_eq!(
+         record_types(&reply[..reply_len]),
+         ([TYPE_PTR, 0, 0, 0], [TYPE_A, TYPE_SRV, TYPE_TXT, 0])
+      );
+
+      let len = query(&mut message, SERVICES_NAME, TYPE_PTR);
+      let reply_len = responder.process_message(&message[..len], &mut reply).unwrap();
+      assert_eq!(record_types(&reply[..reply_len]), ([TYPE_PTR, 0, 0, 0], [0; 4]));
+   }
+
+   #[test]
+   fn queries_in_frames_are_answered_by_multicast() {
+      let responder = responder();
+      let (mut frame, mut reply) = ([0; 200], [0; UDP6_PAYLOAD_OFFSET + MAX_MDNS_MESSAGE_SIZE]);
+
+      let len = query(&mut frame[UDP_PAYLOAD_OFFSET..], &["mydevice", "local"], TYPE_A);
+      let eth = EthernetHeader {
+         dst: ipv4_multicast_mac(&MDNS_GROUP_V4),
+         src: [0x02, 0, 0, 0, 0, 2],
+         ethertype: ETHERTYPE_IPV4,
+      };
+      let ip = Ipv4Header {
+         src: [169, 254, 7, 2],
+         dst: MDNS_GROUP_V4,
+         protocol: IP_PROTO_UDP,
+      };
+      let len = emit_udp_frame(&mut frame, &eth, &ip, &MdnsResponder::udp_header(), len);
+
+      let reply_len = responder.respond(&frame[..len], &mut reply).unwrap();
+      let (eth, packet) = EthernetHeader::parse(&reply[..reply_len]).unwrap();
+      assert_eq!(eth.dst, [0x01, 0x00, 0x5e, 0, 0, 0xfb]);
+      let (ip, _) = Ipv4Header::parse(packet).unwrap();
+      assert_eq!((ip.src, ip.dst), ([169, 254, 7, 1], MDNS_GROUP_V4));
+
+      assert_eq!(responder.respond(&frame[..ETH_HEADER_SIZE], &mut reply), None);
+   }
+}
@@ -0,0 +1,1107 @@
+//! This module implements a NAPT router, RFC 3022, which shares an upstream interface,
+//! e.g. a cellular modem, with the host on the other side of the USB link.
+//!
+//! Like the other services, it works on raw ethernet frames: Frames received from the host are
+//! passed to [`NaptRouter::process`], which answers ARP requests for the gateway address and
+//! forwards packets for other networks to the [`Uplink`], with the source translated to the
+//! address of the uplink. [`NaptRouter::poll`] forwards the replies back to the host.
+//! Packets for the gateway itself are passed on, such that e.g. a [`DhcpServer`](crate::DhcpServer)
+//! can hand out an address to the host, with the gateway as router.
+//!
+//! TCP, UDP and ICMP echo flows are translated, which are kept in a connection table of
+//! [`NAPT_TABLE_SIZE`] entries until they time out. Only packets from the remote endpoint of a flow
+//! are let in, together with ICMP errors about it. Fragmented packets are not translated.
+//! Packets, whose TTL expires at the router, are reported to their source with an ICMP
+//! time exceeded message, such that traceroute works through the router.
+
+use crate::{
+   wire::{
+      checksum_add, checksum_finish, emit_arp_frame, read_u16, write_u16, ArpPacket, EthernetHeader, Ipv4Header,
+      ARP_OP_REPLY, ARP_OP_REQUEST, ARP_PACKET_SIZE, ETHERTYPE_ARP, ETHERTYPE_IPV4, IPV4_ADDR_BROADCAST,
+      IPV4_HEADER_SIZE, IP_PROTO_ICMP, IP_PROTO_TCP, IP_PROTO_UDP, UDP_HEADER_SIZE,
+   },
+   FrameSender, ETH_HEADER_SIZE,
+};
+
+/// The number of flows, that are translated at the same time
+pub const NAPT_TABLE_SIZE: usize = 32;
+
+// Idle timeouts of the flows in microseconds
+/// Established TCP connections, RFC 5382
+const TCP_ESTABLISHED_TIMEOUT: u64 = 7_440_000_000;
+/// TCP connections, that are being opened or closed, RFC 5382
+const TCP_TRANSITORY_TIMEOUT: u64 = 240_000_000;
+/// UDP flows, RFC 4787
+const UDP_TIMEOUT: u64 = 120_000_000;
+/// ICMP echo flows, RFC 5508
+const ICMP_TIMEOUT: u64 = 60_000_000;
+
+/// The external ports and identifiers are taken from the dynamic range
+const FIRST_EXTERNAL_PORT: u16 = 49152;
+
+/// The number of packets forwarded from the uplink in a single [`NaptRouter::poll`] at most
+const MAX_PACKETS_PER_POLL: usize = 8;
+
+const TCP_HEADER_SIZE: usize = 20;
+const TCP_FLAGS_OFFSET: usize = 13;
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_FLAG_RST: u8 = 0x04;
+
+// ICMP message types, RFC 792
+const ECHO_REPLY: u8 = 0;
+const DESTINATION_UNREACHABLE: u8 = 3;
+const SOURCE_QUENCH: u8 = 4;
+const REDIRECT: u8 = 5;
+const ECHO_REQUEST: u8 = 8;
+const TIME_EXCEEDED: u8 = 11;
+const PARAMETER_PROBLEM: u8 = 12;
+const ICMP_HEADER_SIZE: usize = 8;
+
+/// The size of an ICMP error at most, which holds the header of the packet and 8 bytes of its payload
+const MAX_ICMP_ERROR_SIZE: usize = IPV4_HEADER_SIZE + ICMP_HEADER_SIZE + 60 + 8;
+
+/// The upstream interface of a [`NaptRouter`], which sends and receives IPv4 packets.
+pub trait Uplink {
+   /// Tries to receive a packet.
+   /// If a packet is ready, the closure will be executed with it.
+   ///
+   /// # Returns
+   /// - the length of the packet, if a packet was received
+   /// - `None`: otherwise
+   fn try_receive_packet<F>(&mut self, f: F) -> Option<usize>
+   where
+      F: FnOnce(&[u8]);
+
+   /// Tries to send a packet of `len` bytes, which is written by the closure.
+   ///
+   /// # Returns
+   /// - `true`, if the packet was queued
+   /// - `false`, if the packet could not be queued
+   fn try_send_packet<F>(&mut self, len: usize, f: F) -> bool
+   where
+      F: FnOnce(&mut [u8]);
+}
+
+/// Configures the [`NaptRouter`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NaptConfig {
+   /// The mac address of the router on the USB link,
+   /// e.g. the [`device_mac_address`](crate::UsbEthernetDevice::device_mac_address)
+   pub mac: [u8; 6],
+   /// The address of the router on the USB link, which the host uses as gateway
+   pub gateway_addr: [u8; 4],
+   /// The prefix length of the USB link. Packets from addresses within it are translated.
+   pub prefix_len: u8,
+}
+
+/// The endpoints of a flow, as seen by the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Flow {
+   protocol: u8,
+   internal_addr: [u8; 4],
+   /// The port of the host, or the identifier of the ICMP echo requests
+   internal_port: u16,
+   remote_addr: [u8; 4],
+   /// The port of the remote endpoint, zero for ICMP
+   remote_port: u16,
+}
+
+/// The key of a packet from the uplink in the connection table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct InboundKey {
+   protocol: u8,
+   external_port: u16,
+   remote_addr: [u8; 4],
+   remote_port: u16,
+}
+
+/// A flow in the connection table
+#[derive(Debug, Clone, Copy)]
+struct Mapping {
+   flow: Flow,
+   /// The port or identifier, the flow uses on the uplink
+   external_port: u16,
+   /// The time in microseconds, at which the last packet of the flow was seen
+   last_used: u64,
+   /// TCP only: A packet of the remote endpoint has been seen
+   established: bool,
+   /// TCP only: A FIN or RST has been seen
+   closing: bool,
+}
+
+impl Mapping {
+   fn timeout(&self) -> u64 {
+      match self.flow.protocol {
+         IP_PROTO_TCP if self.established && !self.closing => TCP_ESTABLISHED_TIMEOUT,
+         IP_PROTO_TCP => TCP_TRANSITORY_TIMEOUT,
+         IP_PROTO_UDP => UDP_TIMEOUT,
+         _ => ICMP_TIMEOUT,
+      }
+   }
+
+   fn is_active(&self, now: u64) -> bool {
+      now < self.last_used + self.timeout()
+   }
+
+   fn matches(&self, key: &InboundKey) -> bool {
+      self.flow.protocol == key.protocol
+         && self.external_port == key.external_port
+         && self.flow.remote_addr == key.remote_addr
+         && self.flow.remote_port == key.remote_port
+   }
+
+   /// Keeps the flow alive with a packet, that carries the TCP `flags`
+   fn refresh(&mut self, now: u64, flags: u8, inbound: bool) {
+      self.last_used = now;
+      if flags & (TCP_FLAG_FIN | TCP_FLAG_RST) != 0 {
+         self.closing = true;
+      } else if inbound {
+         self.established = true;
+      }
+   }
+}
+
+/// The connection table, which maps the flows of the host to ports on the uplink
+#[derive(Debug)]
+struct Table {
+   entries: [Option<Mapping>; NAPT_TABLE_SIZE],
+   /// The external port, that is tried next
+   next_port: u16,
+}
+
+impl Table {
+   /// Returns the mapping of an outgoing `flow`, which is created, if there is none yet.
+   /// Returns `None`, if the table is full of active flows.
+   fn outbound(&mut self, flow: Flow, now: u64) -> Option<&mut Mapping> {
+      let existing = self
+         .entries
+         .iter()
+         .position(|slot| slot.is_some_and(|mapping| mapping.flow == flow && mapping.is_active(now)));
+      if let Some(idx) = existing {
+         return self.entries[idx].as_mut();
+      }
+
+      let idx = self
+         .entries
+         .iter()
+         .position(|slot| slot.map_or(true, |mapping| !mapping.is_active(now)))?;
+      let external_port = self.free_port(flow.protocol, now);
+      log::debug!(
+         "translating {:?}:{} to {:?} with port {}",
+         flow.internal_addr,
+         flow.internal_port,
+         flow.remote_addr,
+         external_port
+      );
+
+      self.entries[idx] = Some(Mapping {
+         flow,
+         external_port,
+         last_used: now,
+         established: false,
+         closing: false,
+      });
+      self.entries[idx].as_mut()
+   }
+
+   /// Returns the active mapping of a packet from the uplink, if any
+   fn inbound(&mut self, key: &InboundKey, now: u64) -> Option<&mut Mapping> {
+      self
+         .entries
+         .iter_mut()
+         .flatten()
+         .find(|mapping| mapping.matches(key) && mapping.is_active(now))
+   }
+
+   /// Returns an external port, that is not used by an active flow of `protocol`
+   fn free_port(&mut self, protocol: u8, now: u64) -> u16 {
+      // There are way more ports than entries, so this terminates quickly
+      loop {
+         let port = self.next_port;
+         self.next_port = self.next_port.checked_add(1).unwrap_or(FIRST_EXTERNAL_PORT);
+
+         let used = self.entries.iter().flatten().any(|mapping| {
+            mapping.flow.protocol == protocol && mapping.external_port == port && mapping.is_active(now)
+         });
+         if !used {
+            return port;
+         }
+      }
+   }
+
+   fn clear(&mut self) {
+      self.entries = [None; NAPT_TABLE_SIZE];
+   }
+}
+
+/// How a packet from the uplink is translated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Translation {
+   /// The destination of the packet, with the port or identifier at `port_idx` of the transport header
+   Packet { port_idx: usize },
+   /// The source of the packet, that is embedded into an ICMP error
+   IcmpError,
+}
+
+/// Translates between the host on the USB link and the address of an [`Uplink`].
+pub struct NaptRouter<U: Uplink> {
+   uplink: U,
+   nat: Nat,
+}
+
+/// The state of the router apart from the uplink, such that both can be borrowed at the same time
+#[derive(Debug)]
+struct Nat {
+   config: NaptConfig,
+   external_addr: Option<[u8; 4]>,
+   /// The mac address of the host, once it sent a packet
+   host_mac: Option<[u8; 6]>,
+   table: Table,
+   dropped_packets: u32,
+}
+
+impl<U: Uplink> NaptRouter<U> {
+   /// Creates a router, which does not forward anything,
+   /// until the address of the uplink is set through [`set_external_addr`](NaptRouter::set_external_addr).
+   pub fn new(config: NaptConfig, uplink: U) -> Self {
+      Self {
+         uplink,
+         nat: Nat {
+            config,
+            external_addr: None,
+            host_mac: None,
+            table: Table {
+               entries: [None; NAPT_TABLE_SIZE],
+               next_port: FIRST_EXTERNAL_PORT,
+            },
+            dropped_packets: 0,
+         },
+      }
+   }
+
+   /// Returns the uplink, e.g. to drive the modem behind it
+   pub fn uplink(&mut self) -> &mut U {
+      &mut self.uplink
+   }
+
+   /// Returns the address of the uplink, that the flows are translated to
+   pub fn external_addr(&self) -> Option<[u8; 4]> {
+      self.nat.external_addr
+   }
+
+   /// Sets the address of the uplink, e.g. once the modem got one.
+   ///
+   /// If the address changes, all flows are forgotten, since they are bound to the old one.
+   pub fn set_external_addr(&mut self, addr: Option<[u8; 4]>) {
+      if addr != self.nat.external_addr {
+         self.nat.table.clear();
+      }
+      self.nat.external_addr = addr;
+   }
+
+   /// Returns the number of packets, that could not be translated or forwarded
+   pub fn dropped_packets(&self) -> u32 {
+      self.nat.dropped_packets
+   }
+
+   /// Processes a frame received from the host.
+   /// `now` is the current time in microseconds, like the one of a [`Clock`](crate::Clock).
+   ///
+   /// # Returns
+   /// - `true`, if the frame was an ARP request for the gateway or a packet for another network,
+   ///   which should not be passed on
+   /// - `false` otherwise
+   pub fn process(&mut self, frame: &[u8], now: u64, sender: &mut FrameSender) -> bool {
+      self.nat.process(frame, now, &mut self.uplink, sender)
+   }
+
+   /// Forwards the packets received from the uplink to the host.
+   ///
+   /// Returns `true`, if any packet was received.
+   pub fn poll(&mut self, now: u64, sender: &mut FrameSender) -> bool {
+      let Self { uplink, nat } = self;
+
+      let mut received = false;
+      for _ in 0..MAX_PACKETS_PER_POLL {
+         let mut error = [0; MAX_ICMP_ERROR_SIZE];
+         let mut error_len = None;
+         if uplink
+            .try_receive_packet(|packet| error_len = nat.forward_inbound(packet, now, sender, &mut error))
+            .is_none()
+         {
+            break;
+         }
+         received = true;
+
+         // The uplink can only be used again, once the packet has been received
+         if let Some(len) = error_len {
+            if !uplink.try_send_packet(len, |buf| buf.copy_from_slice(&error[..len])) {
+               log::debug!("uplink is busy, dropping ICMP error");
+            }
+         }
+      }
+      received
+   }
+}
+
+impl Nat {
+   fn process<U: Uplink>(&mut self, frame: &[u8], now: u64, uplink: &mut U, sender: &mut FrameSender) -> bool {
+      let (eth, packet) = match EthernetHeader::parse(frame) {
+         Some(parsed) => parsed,
+         None => return false,
+      };
+
+      match eth.ethertype {
+         ETHERTYPE_ARP => {
+            let arp = match ArpPacket::parse(packet) {
+               Some(arp) if arp.op == ARP_OP_REQUEST && arp.target_ip == self.config.gateway_addr => arp,
+               _ => return false,
+            };
+
+            let reply = ArpPacket {
+               op: ARP_OP_REPLY,
+               sender_mac: self.config.mac,
+               sender_ip: self.config.gateway_addr,
+               target_mac: arp.sender_mac,
+               target_ip: arp.sender_ip,
+            };
+            if !sender.try_send_frame(ETH_HEADER_SIZE + ARP_PACKET_SIZE, |buf| {
+               emit_arp_frame(buf, arp.sender_mac, &reply);
+            }) {
+               log::debug!("transmit queue full, dropping reply");
+            }
+            true
+         }
+         ETHERTYPE_IPV4 if eth.dst == self.config.mac => self.forward_outbound(&eth, packet, now, uplink, sender),
+         _ => false,
+      }
+   }
+
+   /// Forwards a packet from the host to the uplink, if it is for another network
+   fn forward_outbound<U: Uplink>(
+      &mut self,
+      eth: &EthernetHeader,
+      packet: &[u8],
+      now: u64,
+      uplink: &mut U,
+      sender: &mut FrameSender,
+   ) -> bool {
+      let (ip, segment) = match Ipv4Header::parse(packet) {
+         Some(parsed) => parsed,
+         None => return false,
+      };
+      if !self.in_subnet(ip.src) || self.in_subnet(ip.dst) || ip.dst == IPV4_ADDR_BROADCAST || ip.dst[0] >= 224 {
+         return false;
+      }
+      self.host_mac = Some(eth.src);
+
+      let external_addr = match self.external_addr {
+         Some(addr) => addr,
+         None => return self.discard("uplink has no address"),
+      };
+      if packet[8] <= 1 {
+         // Report the expired packet to the host, e.g. for traceroute
+         if may_report(&ip, segment) {
+            let len = ETH_HEADER_SIZE + time_exceeded_len(packet);
+            let reply_eth = EthernetHeader {
+               dst: eth.src,
+               src: self.config.mac,
+               ethertype: ETHERTYPE_IPV4,
+            };
+            if !sender.try_send_frame(len, |frame| {
+               reply_eth.emit(frame);
+               emit_time_exceeded(&mut frame[ETH_HEADER_SIZE..], self.config.gateway_addr, packet);
+            }) {
+               log::debug!("transmit queue full, dropping ICMP error");
+            }
+         }
+         return self.discard("TTL exceeded");
+      }
+      let (flow, port_idx, flags) = match outbound_flow(&ip, segment) {
+         Some(flow) => flow,
+         None => return self.discard("cannot translate packet"),
+      };
+      let mapping = match self.table.outbound(flow, now) {
+         Some(mapping) => mapping,
+         None => return self.discard("connection table is full"),
+      };
+
+      // The host reuses the flow for a new connection
+      if flags & TCP_FLAG_SYN != 0 && mapping.closing {
+         mapping.established = false;
+         mapping.closing = false;
+      }
+      mapping.refresh(now, flags, false);
+
+      let external_port = mapping.external_port;
+      let len = header_len(packet) + segment.len();
+      if !uplink.try_send_packet(len, |buf| {
+         buf.copy_from_slice(&packet[..len]);
+         translate(buf, 12, external_addr, port_idx, external_port);
+      }) {
+         return self.discard("uplink is busy");
+      }
+      true
+   }
+
+   /// Forwards a packet from the uplink to the host, if it belongs to a flow.
+   ///
+   /// Returns the length of an ICMP error written to `error`, which has to be sent back to the uplink.
+   fn forward_inbound(&mut self, packet: &[u8], now: u64, sender: &mut FrameSender, error: &mut [u8]) -> Option<usize> {
+      let (ip, segment) = match Ipv4Header::parse(packet) {
+         Some(parsed) => parsed,
+         None => return self.discard_inbound("invalid or fragmented packet"),
+      };
+      if Some(ip.dst) != self.external_addr {
+         return self.discard_inbound("not for the uplink address");
+      }
+      let host_mac = match self.host_mac {
+         Some(mac) => mac,
+         None => return self.discard_inbound("host is unknown"),
+      };
+      if packet[8] <= 1 {
+         self.discard_inbound("TTL exceeded");
+         // Report the expired packet to its source, e.g. for traceroute
+         return match may_report(&ip, segment) {
+            true => Some(emit_time_exceeded(error, ip.dst, packet)),
+            false => None,
+         };
+      }
+
+      let (key, translation, flags) = match inbound_key(&ip, segment) {
+         Some(key) => key,
+         None => return self.discard_inbound("cannot translate packet"),
+      };
+      let mapping = match self.table.inbound(&key, now) {
+         Some(mapping) => mapping,
+         None => return self.discard_inbound("no flow"),
+      };
+      // Errors do not keep the flow alive
+      if translation != Translation::IcmpError {
+         mapping.refresh(now, flags, true);
+      }
+
+      let flow = mapping.flow;
+      let len = header_len(packet) + segment.len();
+      let eth = EthernetHeader {
+         dst: host_mac,
+         src: self.config.mac,
+         ethertype: ETHERTYPE_IPV4,
+      };
+      if !sender.try_send_frame(ETH_HEADER_SIZE + len, |frame| {
+         eth.emit(frame);
+         let buf = &mut frame[ETH_HEADER_SIZE..];
+         buf.copy_from_slice(&packet[..len]);
+         match translation {
+            Translation::Packet { port_idx } => translate(buf, 16, flow.internal_addr, port_idx, flow.internal_port),
+            Translation::IcmpError => translate_icmp_error(buf, &flow),
+         }
+      }) {
+         self.discard_inbound("transmit queue full");
+      }
+      None
+   }
+
+   /// Returns `true`, if `addr` is on the USB link
+   fn in_subnet(&self, addr: [u8; 4]) -> bool {
+      let mask = u32::MAX
+         .checked_shl(32 - self.config.prefix_len.min(32) as u32)
+         .unwrap_or(0);
+      (u32::from_be_bytes(addr) ^ u32::from_be_bytes(self.config.gateway_addr)) & mask == 0
+   }
+
+   /// Drops a packet from the host, which is consumed nevertheless
+   fn discard(&mut self, reason: &str) -> bool {
+      self.discard_inbound(reason);
+      true
+   }
+
+   /// Drops a packet from the uplink, without anything to send back
+   fn discard_inbound(&mut self, reason: &str) -> Option<usize> {
+      log::debug!("dropping packet: {}", reason);
+      self.dropped_packets = self.dropped_packets.wrapping_add(1);
+      None
+   }
+}
+
+/// Returns the length of the header of an IPv4 packet
+fn header_len(packet: &[u8]) -> usize {
+   ((packet[0] & 0x0f) as usize) * 4
+}
+
+/// Returns `true`, if an ICMP error may be sent about a packet, i.e. if it is not an ICMP error
+/// itself, RFC 1122 section 3.2.2
+fn may_report(ip: &Ipv4Header, segment: &[u8]) -> bool {
+   ip.protocol != IP_PROTO_ICMP
+      || !matches!(
+         segment.first(),
+         Some(&(DESTINATION_UNREACHABLE | SOURCE_QUENCH | REDIRECT | TIME_EXCEEDED | PARAMETER_PROBLEM))
+      )
+}
+
+/// Returns the length of the ICMP time exceeded message about `packet`
+fn time_exceeded_len(packet: &[u8]) -> usize {
+   // The message holds the header and the first 8 bytes of the payload of the packet
+   IPV4_HEADER_SIZE + ICMP_HEADER_SIZE + packet.len().min(header_len(packet) + 8)
+}
+
+/// Writes an ICMP time exceeded message about an expired `packet` from `src` back to its source.
+/// Returns the length of the message, see [`time_exceeded_len`].
+fn emit_time_exceeded(buf: &mut [u8], src: [u8; 4], packet: &[u8]) -> usize {
+   let len = time_exceeded_len(packet);
+   let mut dst = [0; 4];
+   dst.copy_from_slice(&packet[12..16]);
+   let ip = Ipv4Header {
+      src,
+      dst,
+      protocol: IP_PROTO_ICMP,
+   };
+   ip.emit(buf, len - IPV4_HEADER_SIZE);
+
+   let message = &mut buf[IPV4_HEADER_SIZE..len];
+   message[..ICMP_HEADER_SIZE].fill(0);
+   // Code 0: time to live exceeded in transit
+   message[0] = TIME_EXCEEDED;
+   message[ICMP_HEADER_SIZE..].copy_from_slice(&packet[..len - IPV4_HEADER_SIZE - ICMP_HEADER_SIZE]);
+   let checksum = checksum_finish(checksum_add(0, message));
+   write_u16(message, 2, checksum);
+   len
+}
+
+/// Returns the flow of a packet from the host, the index of its source port or identifier
+/// in the transport header and its TCP flags, or `None`, if it cannot be translated
+fn outbound_flow(ip: &Ipv4Header, segment: &[u8]) -> Option<(Flow, usize, u8)> {
+   let (internal_port, remote_port, port_idx, flags) = match ip.protocol {
+      IP_PROTO_TCP if segment.len() >= TCP_HEADER_SIZE => {
+         (read_u16(segment, 0), read_u16(segment, 2), 0, segment[TCP_FLAGS_OFFSET])
+      }
+      IP_PROTO_UDP if segment.len() >= UDP_HEADER_SIZE => (read_u16(segment, 0), read_u16(segment, 2), 0, 0),
+      IP_PROTO_ICMP if segment.len() >= ICMP_HEADER_SIZE && segment[0] == ECHO_REQUEST => {
+         (read_u16(segment, 4), 0, 4, 0)
+      }
+      _ => return None,
+   };
+
+   let flow = Flow {
+      protocol: ip.protocol,
+      internal_addr: ip.src,
+      internal_port,
+      remote_addr: ip.dst,
+      remote_port,
+   };
+   Some((flow, port_idx, flags))
+}
+
+/// Returns the key of a packet from the uplink, how it is translated and its TCP flags,
+/// or `None`, if it cannot be translated
+fn inbound_key(ip: &Ipv4Header, segment: &[u8]) -> Option<(InboundKey, Translation, u8)> {
+   let key = |protocol, external_port, remote_addr, remote_port| InboundKey {
+      protocol,
+      external_port,
+      remote_addr,
+      remote_port,
+   };
+
+   match ip.protocol {
+      IP_PROTO_TCP if segment.len() >= TCP_HEADER_SIZE => Some((
+         key(IP_PROTO_TCP, read_u16(segment, 2), ip.src, read_u16(segment, 0)),
+         Translation::Packet { port_idx: 2 },
+         segment[TCP_FLAGS_OFFSET],
+      )),
+      IP_PROTO_UDP if segment.len() >= UDP_HEADER_SIZE => Some((
+         key(IP_PROTO_UDP, read_u16(segment, 2), ip.src, read_u16(segment, 0)),
+         Translation::Packet { port_idx: 2 },
+         0,
+      )),
+      IP_PROTO_ICMP if segment.len() >= ICMP_HEADER_SIZE && segment[0] == ECHO_REPLY => Some((
+         key(IP_PROTO_ICMP, read_u16(segment, 4), ip.src, 0),
+         Translation::Packet { port_idx: 4 },
+         0,
+      )),
+      IP_PROTO_ICMP
+         if segment.len() >= ICMP_HEADER_SIZE && matches!(segment[0], DESTINATION_UNREACHABLE | TIME_EXCEEDED) =>
+      {
+         // The error is about a packet, that has been sent from the uplink address
+         let embedded = &segment[ICMP_HEADER_SIZE..];
+         if embedded.len() < IPV4_HEADER_SIZE || embedded[0] >> 4 != 4 || embedded[12..16] != ip.dst {
+            return None;
+         }
+         let embedded_len = header_len(embedded);
+         if embedded_len < IPV4_HEADER_SIZE || embedded.len() < embedded_len + ICMP_HEADER_SIZE {
+            return None;
+         }
+
+         let transport = &embedded[embedded_len..];
+         let (external_port, remote_port) = match embedded[9] {
+            IP_PROTO_TCP | IP_PROTO_UDP => (read_u16(transport, 0), read_u16(transport, 2)),
+            IP_PROTO_ICMP if transport[0] == ECHO_REQUEST => (read_u16(transport, 4), 0),
+            _ => return None,
+         };
+
+         let mut remote_addr = [0; 4];
+         remote_addr.copy_from_slice(&embedded[16..20]);
+         Some((
+            key(embedded[9], external_port, remote_addr, remote_port),
+            Translation::IcmpError,
+            0,
+         ))
+      }
+      _ => None,
+   }
+}
+
+/// Updates the internet checksum `checksum` for `old` being replaced with `new`, RFC 1624
+fn checksum_update(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+   let sum = old.chunks_exact(2).fold(!checksum as u32, |sum, chunk| {
+      sum + !u16::from_be_bytes([chunk[0], chunk[1]]) as u32
+   });
+   checksum_finish(checksum_add(sum, new))
+}
+
+/// Recomputes the checksum of an IPv4 header
+fn update_header_checksum(header: &mut [u8]) {
+   write_u16(header, 10, 0);
+   let checksum = checksum_finish(checksum_add(0, header));
+   write_u16(header, 10, checksum);
+}
+
+/// Replaces the address at `addr_idx` of an IPv4 packet and the port or identifier at `port_idx`
+/// of its transport header, decrements the TTL and updates the checksums
+fn translate(packet: &mut [u8], addr_idx: usize, addr: [u8; 4], port_idx: usize, port: u16) {
+   let (header, segment) = packet.split_at_mut(header_len(packet));
+   let protocol = header[9];
+   let mut old_addr = [0; 4];
+   old_addr.copy_from_slice(&header[addr_idx..addr_idx + 4]);
+   header[addr_idx..addr_idx + 4].copy_from_slice(&addr);
+   header[8] -= 1;
+   update_header_checksum(header);
+   translate_segment(segment, protocol, old_addr, addr, port_idx, port);
+}
+
+/// Replaces the port or identifier at `port_idx` of a transport header and updates its checksum,
+/// also for the address `old_addr` in the IPv4 header being replaced with `addr`
+fn translate_segment(segment: &mut [u8], protocol: u8, old_addr: [u8; 4], addr: [u8; 4], port_idx: usize, port: u16) {
+   let old_port = read_u16(segment, port_idx);
+   write_u16(segment, port_idx, port);
+
+   let checksum_idx = match protocol {
+      IP_PROTO_TCP => 16,
+      IP_PROTO_UDP => 6,
+      _ => 2,
+   };
+   // The segment embedded in an ICMP error may be cut off before the checksum
+   if segment.len() < checksum_idx + 2 {
+      return;
+   }
+   let mut checksum = read_u16(segment, checksum_idx);
+   // A UDP checksum of zero means, that the sender did not compute one
+   if protocol == IP_PROTO_UDP && checksum == 0 {
+      return;
+   }
+
+   // The ICMP checksum does not cover a pseudo header
+   if protocol != IP_PROTO_ICMP {
+      checksum = checksum_update(checksum, &old_addr, &addr);
+   }
+   checksum = checksum_update(checksum, &old_port.to_be_bytes(), &port.to_be_bytes());
+   if protocol == IP_PROTO_UDP && checksum == 0 {
+      checksum = 0xffff;
+   }
+   write_u16(segment, checksum_idx, checksum);
+}
+
+/// Translates an ICMP error about a packet of `flow` back to the host
+fn translate_icmp_error(packet: &mut [u8], flow: &Flow) {
+   let (header, message) = packet.split_at_mut(header_len(packet));
+   header[16..20].copy_from_slice(&flow.internal_addr);
+   header[8] -= 1;
+   update_header_checksum(header);
+
+   let embedded = &mut message[ICMP_HEADER_SIZE..];
+   let (embedded_header, transport) = embedded.split_at_mut(header_len(embedded));
+   let mut old_addr = [0; 4];
+   old_addr.copy_from_slice(&embedded_header[12..16]);
+   embedded_header[12..16].copy_from_slice(&flow.internal_addr);
+   update_header_checksum(embedded_header);
+   let port_idx = match flow.protocol {
+      IP_PROTO_ICMP => 4,
+      _ => 0,
+   };
+   // The embedded checksums are updated as well, RFC 5508 section 4.2
+   translate_segment(transport, flow.protocol, old_addr, flow.internal_addr, port_idx, flow.internal_port);
+
+   // The checksum covers the whole message, including the embedded packet
+   write_u16(message, 2, 0);
+   let checksum = checksum_finish(checksum_add(0, message));
+   write_u16(message, 2, checksum);
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{
+      buffer::TxBuf,
+      link::Link,
+      wire::{PseudoHeader, UdpHeader, ETH_ADDR_BROADCAST},
+   };
+   extern crate std;
+   use std::{collections::VecDeque, vec::Vec};
+
+   const MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 1];
+   const HOST_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 2];
+   const GATEWAY: [u8; 4] = [192, 168, 7, 1];
+   const HOST: [u8; 4] = [192, 168, 7, 2];
+   const EXTERNAL: [u8; 4] = [10, 0, 0, 5];
+   const REMOTE: [u8; 4] = [93, 184, 216, 34];
+
+   #[derive(Default)]
+   struct QueueUplink {
+      rx: VecDeque<Vec<u8>>,
+      tx: Vec<Vec<u8>>,
+   }
+
+   impl Uplink for QueueUplink {
+      fn try_receive_packet<F>(&mut self, f: F) -> Option<usize>
+      where
+         F: FnOnce(&[u8]),
+      {
+         let packet = self.rx.pop_front()?;
+         f(&packet);
+         Some(packet.len())
+      }
+
+      fn try_send_packet<F>(&mut self, len: usize, f: F) -> bool
+      where
+         F: FnOnce(&mut [u8]),
+      {
+         let mut packet = std::vec![0; len];
+         f(&mut packet);
+         self.tx.push(packet);
+         true
+      }
+   }
+
+   fn router() -> NaptRouter<QueueUplink> {
+      let config = NaptConfig {
+         mac: MAC,
+         gateway_addr: GATEWAY,
+         prefix_len: 24,
+      };
+      let mut router = NaptRouter::new(config, QueueUplink::default());
+      router.set_external_addr(Some(EXTERNAL));
+      router
+   }
+
+   /// Returns an IPv4 packet with a transport `segment`, whose checksum is computed
+   fn packet(src: [u8; 4], dst: [u8; 4], protocol: u8, segment: &[u8]) -> Vec<u8> {
+      let ip = Ipv4Header { src, dst, protocol };
+      let mut packet = std::vec![0; IPV4_HEADER_SIZE + segment.len()];
+      ip.emit(&mut packet, segment.len());
+      packet[IPV4_HEADER_SIZE..].copy_from_slice(segment);
+
+      let checksum_idx = IPV4_HEADER_SIZE
+         + match protocol {
+            IP_PROTO_TCP => 16,
+            IP_PROTO_UDP => 6,
+            _ => 2,
+         };
+      let sum = match protocol {
+         IP_PROTO_ICMP => 0,
+         _ => ip.pseudo_header_checksum(segment.len()),
+      };
+      write_u16(&mut packet, checksum_idx, 0);
+      let checksum = checksum_finish(checksum_add(sum, &packet[IPV4_HEADER_SIZE..]));
+      write_u16(&mut packet, checksum_idx, checksum);
+      packet
+   }
+
+   /// Returns the ports and the transport checksum of a packet, after checking both checksums
+   fn check(packet: &[u8]) -> (Ipv4Header, u16, u16) {
+      let (ip, segment) = Ipv4Header::parse(packet).unwrap();
+      let sum = match ip.protocol {
+         IP_PROTO_ICMP => 0,
+         _ => ip.pseudo_header_checksum(segment.len()),
+      };
+      assert_eq!(checksum_finish(checksum_add(sum, segment)), 0);
+      match ip.protocol {
+         IP_PROTO_ICMP => (ip, read_u16(segment, 4), 0),
+         _ => (ip, read_u16(segment, 0), read_u16(segment, 2)),
+      }
+   }
+
+   fn frame(packet: &[u8]) -> Vec<u8> {
+      let mut frame = std::vec![0; ETH_HEADER_SIZE + packet.len()];
+      EthernetHeader {
+         dst: MAC,
+         src: HOST_MAC,
+         ethertype: ETHERTYPE_IPV4,
+      }
+      .emit(&mut frame);
+      frame[ETH_HEADER_SIZE..].copy_from_slice(packet);
+      frame
+   }
+
+   /// Takes the oldest frame out of the transmit queue
+   fn sent_frame(tx_buf: &TxBuf) -> Option<Vec<u8>> {
+      let mut consumer = tx_buf.consumer()?;
+      let frame = consumer.peek_mut()?;
+      let mut sent = Vec::new();
+      while let Some(packet) = frame.try_get_packet() {
+         sent.extend_from_slice(packet);
+         let len = packet.len();
+         frame.advance(len);
+      }
+      frame.reset();
+      consumer.pop();
+      Some(sent)
+   }
+
+   #[test]
+   fn udp_flows_are_translated_both_ways() {
+      let (tx_buf, link) = (TxBuf::new(), Link::new());
+      let mut sender = FrameSender::new(&tx_buf, &link, None, None);
+      let mut router = router();
+
+      let mut datagram = [0; UDP_HEADER_SIZE + 4];
+      UdpHeader {
+         src_port: 5000,
+         dst_port: 53,
+      }
+      .emit(
+         &mut datagram,
+         &Ipv4Header {
+            src: HOST,
+            dst: REMOTE,
+            protocol: IP_PROTO_UDP,
+         },
+         4,
+      );
+      assert!(router.process(&frame(&packet(HOST, REMOTE, IP_PROTO_UDP, &datagram)), 0, &mut sender));
+
+      let sent = router.uplink().tx.pop().unwrap();
+      let (ip, src_port, dst_port) = check(&sent);
+      assert_eq!((ip.src, ip.dst, dst_port), (EXTERNAL, REMOTE, 53));
+      assert_eq!(sent[8], 63);
+
+      // Packets for the gateway are passed on
+      assert!(!router.process(&frame(&packet(HOST, GATEWAY, IP_PROTO_UDP, &datagram)), 0, &mut sender));
+
+      // The reply is translated back, but not from other endpoints
+      let mut reply = [0; UDP_HEADER_SIZE];
+      write_u16(&mut reply, 0, 53);
+      write_u16(&mut reply, 2, src_port);
+      write_u16(&mut reply, 4, UDP_HEADER_SIZE as u16);
+      router
+         .uplink()
+         .rx
+         .push_back(packet(REMOTE, EXTERNAL, IP_PROTO_UDP, &reply));
+      router
+         .uplink()
+         .rx
+         .push_back(packet([1, 1, 1, 1], EXTERNAL, IP_PROTO_UDP, &reply));
+      assert!(router.poll(1, &mut sender));
+
+      let received = sent_frame(&tx_buf).unwrap();
+      let (eth, _) = EthernetHeader::parse(&received).unwrap();
+      assert_eq!((eth.dst, eth.src), (HOST_MAC, MAC));
+      let (ip, src_port, dst_port) = check(&received[ETH_HEADER_SIZE..]);
+      assert_eq!((ip.src, ip.dst, src_port, dst_port), (REMOTE, HOST, 53, 5000));
+      assert!(sent_frame(&tx_buf).is_none());
+      assert_eq!(router.dropped_packets(), 1);
+
+      // The datagram embedded in an ICMP error is translated back completely
+      let mut error = std::vec![DESTINATION_UNREACHABLE, 3, 0, 0, 0, 0, 0, 0];
+      error.extend_from_slice(&sent);
+      router
+         .uplink()
+         .rx
+         .push_back(packet(REMOTE, EXTERNAL, IP_PROTO_ICMP, &error));
+      assert!(router.poll(1, &mut sender));
+
+      let received = sent_frame(&tx_buf).unwrap();
+      let (ip, message) = Ipv4Header::parse(&received[ETH_HEADER_SIZE..]).unwrap();
+      assert_eq!(ip.dst, HOST);
+      assert_eq!(checksum_finish(checksum_add(0, message)), 0);
+      let (ip, src_port, dst_port) = check(&message[ICMP_HEADER_SIZE..]);
+      assert_eq!((ip.src, ip.dst, src_port, dst_port), (HOST, REMOTE, 5000, 53));
+
+      // Once the flow timed out, replies are dropped
+      router
+         .uplink()
+         .rx
+         .push_back(packet(REMOTE, EXTERNAL, IP_PROTO_UDP, &reply));
+      router.poll(1 + UDP_TIMEOUT, &mut sender);
+      assert!(sent_frame(&tx_buf).is_none());
+      assert_eq!(router.dropped_packets(), 2);
+   }
+
+   #[test]
+   fn tcp_connections_and_icmp_errors_are_translated() {
+      let (tx_buf, link) = (TxBuf::new(), Link::new());
+      let mut sender = FrameSender::new(&tx_buf, &link, None, None);
+      let mut router = router();
+
+      let mut segment = [0; TCP_HEADER_SIZE];
+      write_u16(&mut segment, 0, 40000);
+      write_u16(&mut segment, 2, 443);
+      segment[12] = 0x50;
+      segment[TCP_FLAGS_OFFSET] = TCP_FLAG_SYN;
+      let syn = packet(HOST, REMOTE, IP_PROTO_TCP, &segment);
+      assert!(router.process(&frame(&syn), 0, &mut sender));
+      let sent = router.uplink().tx.pop().unwrap();
+      let (_, external_port, _) = check(&sent);
+
+      // A router on the way reports, that the SYN did not make it
+      let mut error = std::vec![TIME_EXCEEDED, 0, 0, 0, 0, 0, 0, 0];
+      error.extend_from_slice(&sent[..IPV4_HEADER_SIZE + 8]);
+      router
+         .uplink()
+         .rx
+         .push_back(packet([10, 0, 0, 1], EXTERNAL, IP_PROTO_ICMP, &error));
+      router.poll(0, &mut sender);
+
+      let received = sent_frame(&tx_buf).unwrap();
+      let (ip, message) = Ipv4Header::parse(&received[ETH_HEADER_SIZE..]).unwrap();
+      assert_eq!(ip.dst, HOST);
+      assert_eq!(checksum_finish(checksum_add(0, message)), 0);
+      // The embedded packet is truncated, so only its header is checked
+      let embedded = &message[ICMP_HEADER_SIZE..];
+      assert_eq!(checksum_finish(checksum_add(0, &embedded[..IPV4_HEADER_SIZE])), 0);
+      assert_eq!(&embedded[12..16], &HOST);
+      assert_eq!(read_u16(embedded, IPV4_HEADER_SIZE), 40000);
+
+      // Unanswered connections time out early, established ones are kept
+      let mut syn_ack = segment;
+      write_u16(&mut syn_ack, 0, 443);
+      write_u16(&mut syn_ack, 2, external_port);
+      router
+         .uplink()
+         .rx
+         .push_back(packet(REMOTE, EXTERNAL, IP_PROTO_TCP, &syn_ack));
+      router.poll(TCP_TRANSITORY_TIMEOUT, &mut sender);
+      assert!(sent_frame(&tx_buf).is_none());
+
+      assert!(router.process(&frame(&syn), TCP_TRANSITORY_TIMEOUT, &mut sender));
+      let (_, external_port, _) = check(&router.uplink().tx.pop().unwrap());
+      write_u16(&mut syn_ack, 2, external_port);
+      syn_ack[TCP_FLAGS_OFFSET] = TCP_FLAG_SYN | 0x10;
+      router
+         .uplink()
+         .rx
+         .push_back(packet(REMOTE, EXTERNAL, IP_PROTO_TCP, &syn_ack));
+      router.poll(TCP_TRANSITORY_TIMEOUT, &mut sender);
+      let received = sent_frame(&tx_buf).unwrap();
+      let (ip, src_port, dst_port) = check(&received[ETH_HEADER_SIZE..]);
+      assert_eq!((ip.dst, src_port, dst_port), (HOST, 443, 40000));
+
+      router
+         .uplink()
+         .rx
+         .push_back(packet(REMOTE, EXTERNAL, IP_PROTO_TCP, &syn_ack));
+      router.poll(3 * TCP_TRANSITORY_TIMEOUT, &mut sender);
+      assert!(sent_frame(&tx_buf).is_some());
+   }
+
+   #[test]
+   fn expired_packets_are_reported() {
+      let (tx_buf, link) = (TxBuf::new(), Link::new());
+      let mut sender = FrameSender::new(&tx_buf, &link, None, None);
+      let mut router = router();
+
+      let mut datagram = [0; UDP_HEADER_SIZE + 12];
+      write_u16(&mut datagram, 0, 5000);
+      write_u16(&mut datagram, 2, 33434);
+      write_u16(&mut datagram, 4, (UDP_HEADER_SIZE + 12) as u16);
+      let mut probe = packet(HOST, REMOTE, IP_PROTO_UDP, &datagram);
+      probe[8] = 1;
+      update_header_checksum(&mut probe[..IPV4_HEADER_SIZE]);
+
+      // The host learns about the gateway
+      assert!(router.process(&frame(&probe), 0, &mut sender));
+      assert!(router.uplink().tx.is_empty());
+      let received = sent_frame(&tx_buf).unwrap();
+      let (eth, _) = EthernetHeader::parse(&received).unwrap();
+      assert_eq!((eth.dst, eth.src), (HOST_MAC, MAC));
+      let (ip, message) = Ipv4Header::parse(&received[ETH_HEADER_SIZE..]).unwrap();
+      assert_eq!((ip.src, ip.dst, ip.protocol), (GATEWAY, HOST, IP_PROTO_ICMP));
+      assert_eq!(checksum_finish(checksum_add(0, message)), 0);
+      assert_eq!((message[0], message[1]), (TIME_EXCEEDED, 0));
+      assert_eq!(&message[ICMP_HEADER_SIZE..], &probe[..IPV4_HEADER_SIZE + 8]);
+
+      // A remote endpoint learns about the router
+      probe[8] = 64;
+      update_header_checksum(&mut probe[..IPV4_HEADER_SIZE]);
+      assert!(router.process(&frame(&probe), 0, &mut sender));
+      let sent = router.uplink().tx.pop().unwrap();
+      let (_, external_port, _) = check(&sent);
+
+      let mut reply = [0; UDP_HEADER_SIZE];
+      write_u16(&mut reply, 0, 33434);
+      write_u16(&mut reply, 2, external_port);
+      write_u16(&mut reply, 4, UDP_HEADER_SIZE as u16);
+      let mut reply = packet(REMOTE, EXTERNAL, IP_PROTO_UDP, &reply);
+      reply[8] = 1;
+      update_header_checksum(&mut reply[..IPV4_HEADER_SIZE]);
+      router.uplink().rx.push_back(reply.clone());
+      assert!(router.poll(0, &mut sender));
+      assert!(sent_frame(&tx_buf).is_none());
+
+      let sent = router.uplink().tx.pop().unwrap();
+      let (ip, message) = Ipv4Header::parse(&sent).unwrap();
+      assert_eq!((ip.src, ip.dst, ip.protocol), (EXTERNAL, REMOTE, IP_PROTO_ICMP));
+      assert_eq!(checksum_finish(checksum_add(0, message)), 0);
+      assert_eq!(message[0], TIME_EXCEEDED);
+      assert_eq!(&message[ICMP_HEADER_SIZE..], &reply[..]);
+      assert_eq!(router.dropped_packets(), 2);
+
+      // Expired ICMP errors are not reported
+      let mut error = std::vec![TIME_EXCEEDED, 0, 0, 0, 0, 0, 0, 0];
+      error.extend_from_slice(&sent[..IPV4_HEADER_SIZE + 8]);
+      let mut error = packet([10, 0, 0, 1], EXTERNAL, IP_PROTO_ICMP, &error);
+      error[8] = 1;
+      update_header_checksum(&mut error[..IPV4_HEADER_SIZE]);
+      router.uplink().rx.push_back(error);
+      assert!(router.poll(0, &mut sender));
+      assert!(router.uplink().tx.is_empty());
+   }
+
+   #[test]
+   fn gateway_is_resolved_and_table_is_bounded() {
+      let (tx_buf, link) = (TxBuf::new(), Link::new());
+      let mut sender = FrameSender::new(&tx_buf, &link, None, None);
+      let mut router = router();
+
+      let mut buf = [0; 64];
+      let request = ArpPacket {
+         op: ARP_OP_REQUEST,
+         sender_mac: HOST_MAC,
+         sender_ip: HOST,
+         target_mac: [0; 6],
+         target_ip: GATEWAY,
+      };
+      let len = emit_arp_frame(&mut buf, ETH_ADDR_BROADCAST, &request);
+      assert!(router.process(&buf[..len], 0, &mut sender));
+      let reply = sent_frame(&tx_buf).unwrap();
+      let arp = ArpPacket::parse(&reply[ETH_HEADER_SIZE..]).unwrap();
+      assert_eq!((arp.op, arp.sender_mac, arp.sender_ip), (ARP_OP_REPLY, MAC, GATEWAY));
+
+      // Pings with different identifiers are separate flows
+      let ping = |id: u16| {
+         let mut message = [ECHO_REQUEST, 0, 0, 0, 0, 0, 0, 1];
+         write_u16(&mut message, 4, id);
+         frame(&packet(HOST, REMOTE, IP_PROTO_ICMP, &message))
+      };
+      for id in 0..NAPT_TABLE_SIZE as u16 {
+         assert!(router.process(&ping(id), 0, &mut sender));
+      }
+      assert_eq!(router.uplink().tx.len(), NAPT_TABLE_SIZE);
+      assert_eq!(router.dropped_packets(), 0);
+
+      assert!(router.process(&ping(NAPT_TABLE_SIZE as u16), 0, &mut sender));
+      assert_eq!(router.dropped_packets(), 1);
+      assert!(router.process(&ping(NAPT_TABLE_SIZE as u16), ICMP_TIMEOUT, &mut sender));
+      assert_eq!(router.uplink().tx.len(), NAPT_TABLE_SIZE + 1);
+      check(router.uplink().tx.last().unwrap());
+   }
+}
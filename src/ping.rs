@@ -0,0 +1,233 @@
+//! This module implements a tiny responder, that answers ARP requests and ICMP echo requests
+//! for an IPv4 address, such that the device can be pinged without a network stack.
+//!
+//! Frames received from the host, e.g. through [`FrameReceiver::try_receive_frame`](crate::FrameReceiver::try_receive_frame),
+//! are passed to [`PingResponder::process`], which writes the replies directly into the
+//! transmit queue of the [`FrameSender`].
+
+use crate::{
+   wire::{
+      checksum_add, checksum_finish, emit_arp_frame, write_u16, ArpPacket, EthernetHeader, Ipv4Header,
+      ARP_OP_REPLY, ARP_OP_REQUEST, ARP_PACKET_SIZE, ETHERTYPE_ARP, ETHERTYPE_IPV4, IPV4_HEADER_SIZE, IP_PROTO_ICMP,
+   },
+   FrameSender, ETH_HEADER_SIZE,
+};
+
+// ICMP message types, RFC 792
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+const ICMP_HEADER_SIZE: usize = 8;
+
+/// Answers ARP requests and pings for an IPv4 address.
+#[derive(Debug, Clone)]
+pub struct PingResponder {
+   mac: [u8; 6],
+   addr: Option<[u8; 4]>,
+}
+
+/// The reply to a frame
+enum Reply<'f> {
+   /// The frame was for the responder, but needs no reply
+   Nothing,
+   Arp(ArpPacket),
+   Echo {
+      eth: EthernetHeader,
+      ip: Ipv4Header,
+      /// The ICMP echo request, which is echoed back
+      message: &'f [u8],
+   },
+}
+
+impl Reply<'_> {
+   fn len(&self) -> usize {
+      match self {
+         Reply::Nothing => 0,
+         Reply::Arp(_) => ETH_HEADER_SIZE + ARP_PACKET_SIZE,
+         Reply::Echo { message, .. } => ETH_HEADER_SIZE + IPV4_HEADER_SIZE + message.len(),
+      }
+   }
+
+   /// Writes the reply frame into `buf`
+   fn emit(&self, buf: &mut [u8]) {
+      match self {
+         Reply::Nothing => (),
+         Reply::Arp(arp) => {
+            emit_arp_frame(buf, arp.target_mac, arp);
+         }
+         Reply::Echo { eth, ip, message } => {
+            let icmp_start = ETH_HEADER_SIZE + IPV4_HEADER_SIZE;
+            eth.emit(buf);
+            ip.emit(&mut buf[ETH_HEADER_SIZE..], message.len());
+
+            let icmp = &mut buf[icmp_start..icmp_start + message.len()];
+            icmp.copy_from_slice(message);
+            icmp[0] = ECHO_REPLY;
+            write_u16(icmp, 2, 0);
+            let checksum = checksum_finish(checksum_add(0, icmp));
+            write_u16(icmp, 2, checksum);
+         }
+      }
+   }
+}
+
+impl PingResponder {
+   /// Creates a responder for the device with `mac`, which answers for `addr`, if any
+   pub fn new(mac: [u8; 6], addr: Option<[u8; 4]>) -> Self {
+      Self { mac, addr }
+   }
+
+   /// Changes the address, that is answered for, e.g. once a link-local address is claimed
+   pub fn set_addr(&mut self, addr: Option<[u8; 4]>) {
+      self.addr = addr;
+   }
+
+   /// Processes a frame received from the host.
+   ///
+   /// # Returns
+   /// - `true`, if the frame was an ARP request or ping for the address, which should not be passed on
+   /// - `false` otherwise
+   pub fn process(&self, frame: &[u8], sender: &mut FrameSender) -> bool {
+      let reply = match self.reply(frame) {
+         None => return false,
+         Some(reply) => reply,
+      };
+
+      if !matches!(reply, Reply::Nothing) && !sender.try_send_frame(reply.len(), |buf| reply.emit(buf)) {
+         log::debug!("transmit queue full, dropping reply");
+      }
+      true
+   }
+
+   /// Returns the reply to `frame`, or `None`, if the frame is not for the responder
+   fn reply<'f>(&self, frame: &'f [u8]) -> Option<Reply<'f>> {
+      let addr = self.addr?;
+      let (eth, packet) = EthernetHeader::parse(frame)?;
+
+      match eth.ethertype {
+         ETHERTYPE_ARP => {
+            let arp = ArpPacket::parse(packet)?;
+            if arp.op != ARP_OP_REQUEST || arp.target_ip != addr {
+               return None;
+            }
+
+            // Probes of hosts without an address, RFC 5227, are answered as well, which defends it
+            Some(Reply::Arp(ArpPacket {
+               op: ARP_OP_REPLY,
+               sender_mac: self.mac,
+               sender_ip: addr,
+               target_mac: arp.sender_mac,
+               target_ip: arp.sender_ip,
+            }))
+         }
+         ETHERTYPE_IPV4 => {
+            let (ip, message) = Ipv4Header::parse(packet)?;
+            if ip.protocol != IP_PROTO_ICMP || ip.dst != addr {
+               return None;
+            }
+
+            if message.len() < ICMP_HEADER_SIZE
+               || message[0] != ECHO_REQUEST
+               || checksum_finish(checksum_add(0, message)) != 0
+            {
+               return Some(Reply::Nothing);
+            }
+
+            log::trace!("answering ping from {:?}", ip.src);
+            Some(Reply::Echo {
+               eth: EthernetHeader {
+                  dst: eth.src,
+                  src: self.mac,
+                  ethertype: ETHERTYPE_IPV4,
+               },
+               ip: Ipv4Header {
+                  src: addr,
+                  dst: ip.src,
+                  protocol: IP_PROTO_ICMP,
+               },
+               message,
+            })
+         }
+         _ => None,
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::wire::ETH_ADDR_BROADCAST;
+
+   const MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 1];
+   const HOST_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 2];
+   const ADDR: [u8; 4] = [192, 168, 7, 1];
+   const HOST_ADDR: [u8; 4] = [192, 168, 7, 2];
+
+   fn respond(responder: &PingResponder, frame: &[u8], buf: &mut [u8]) -> Option<usize> {
+      let reply = responder.reply(frame)?;
+      reply.emit(buf);
+      Some(reply.len())
+   }
+
+   #[test]
+   fn arp_requests_are_answered() {
+      let responder = PingResponder::new(MAC, Some(ADDR));
+      let (mut frame, mut buf) = ([0; 64], [0; 64]);
+
+      let request = ArpPacket {
+         op: ARP_OP_REQUEST,
+         sender_mac: HOST_MAC,
+         sender_ip: HOST_ADDR,
+         target_mac: [0; 6],
+         target_ip: ADDR,
+      };
+      let len = emit_arp_frame(&mut frame, ETH_ADDR_BROADCAST, &request);
+      let reply_len = respond(&responder, &frame[..len], &mut buf).unwrap();
+
+      let (eth, packet) = EthernetHeader::parse(&buf[..reply_len]).unwrap();
+      assert_eq!(eth.dst, HOST_MAC);
+      let reply = ArpPacket::parse(packet).unwrap();
+      assert_eq!((reply.op, reply.sender_mac, reply.sender_ip), (ARP_OP_REPLY, MAC, ADDR));
+      assert_eq!((reply.target_mac, reply.target_ip), (HOST_MAC, HOST_ADDR));
+
+      // Requests for other addresses are passed on
+      let len = emit_arp_frame(&mut frame, ETH_ADDR_BROADCAST, &ArpPacket { target_ip: HOST_ADDR, ..request });
+      assert!(responder.reply(&frame[..len]).is_none());
+   }
+
+   #[test]
+   fn pings_are_echoed() {
+      let responder = PingResponder::new(MAC, Some(ADDR));
+      let (mut frame, mut buf) = ([0; 100], [0; 100]);
+
+      let message_len = ICMP_HEADER_SIZE + 4;
+      let message = &mut frame[ETH_HEADER_SIZE + IPV4_HEADER_SIZE..][..message_len];
+      message.copy_from_slice(&[ECHO_REQUEST, 0, 0, 0, 0, 1, 0, 7, 1, 2, 3, 4]);
+      let checksum = checksum_finish(checksum_add(0, message));
+      write_u16(message, 2, checksum);
+      let eth = EthernetHeader {
+         dst: MAC,
+         src: HOST_MAC,
+         ethertype: ETHERTYPE_IPV4,
+      };
+      eth.emit(&mut frame);
+      let ip = Ipv4Header {
+         src: HOST_ADDR,
+         dst: ADDR,
+         protocol: IP_PROTO_ICMP,
+      };
+      ip.emit(&mut frame[ETH_HEADER_SIZE..], message_len);
+      let len = ETH_HEADER_SIZE + IPV4_HEADER_SIZE + message_len;
+
+      let reply_len = respond(&responder, &frame[..len], &mut buf).unwrap();
+      assert_eq!(reply_len, len);
+      let (ip, message) = Ipv4Header::parse(&buf[ETH_HEADER_SIZE..reply_len]).unwrap();
+      assert_eq!((ip.src, ip.dst), (ADDR, HOST_ADDR));
+      assert_eq!(message[0], ECHO_REPLY);
+      assert_eq!(checksum_finish(checksum_add(0, message)), 0);
+      assert_eq!(&message[4..], &[0, 1, 0, 7, 1, 2, 3, 4]);
+
+      // Without an address, nothing is answered
+      let responder = PingResponder::new(MAC, None);
+      assert!(responder.reply(&frame[..len]).is_none());
+   }
+}
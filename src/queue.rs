@@ -0,0 +1,246 @@
+//! This module implements a lock-free single-producer single-consumer queue of frame buffers.
+//!
+//! The producer owns the slot at `head` and prepares the next element in it, which can take
+//! several calls, e.g. one per USB packet.
+//! Once prepared, the element is committed, which hands it over to the consumer.
+//! The consumer owns the slots from `tail` up to, but excluding, `head`.
+//!
+//! Since `head` is only written by the producer and `tail` only by the consumer, atomic loads
+//! and stores suffice, which are available on every platform.
+//! Neither side ever has to wait for the other one.
+//! The producer and consumer handles are guarded by a lock each, which is never contended in
+//! normal operation, but ensures, that there is only ever one producer and one consumer.
+//! The only exception is [`Producer::drop_oldest`], which needs to exclude the consumer.
+
+use crate::{
+   buffer::{try_lock, BufGuard, BufLock},
+   QUEUE_DEPTH,
+};
+use core::{
+   cell::UnsafeCell,
+   fmt,
+   sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// One slot more than the depth is needed, since the producer always owns one slot
+const QUEUE_SLOTS: usize = QUEUE_DEPTH + 1;
+
+pub struct Queue<T> {
+   slots: [UnsafeCell<T>; QUEUE_SLOTS],
+   head: AtomicUsize,
+   tail: AtomicUsize,
+   producer: BufLock<()>,
+   consumer: BufLock<()>,
+}
+
+// SAFETY: The slots are only accessed through the `Producer` and `Consumer`, which partition
+// them between each other, and the locks guarantee, that at most one of each exists at any time.
+#[cfg(feature = "sync")]
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+impl<T> Queue<T> {
+   pub fn new<F>(mut f: F) -> Self
+   where
+      F: FnMut() -> T,
+   {
+      Self {
+         slots: core::array::from_fn(|_| UnsafeCell::new(f())),
+         head: AtomicUsize::new(0),
+         tail: AtomicUsize::new(0),
+         producer: BufLock::new(()),
+         consumer: BufLock::new(()),
+      }
+   }
+
+   /// Returns the producer handle, or `None`, if there already is one
+   pub fn producer(&self) -> Option<Producer<'_, T>> {
+      Some(Producer {
+         _lock: try_lock(&self.producer)?,
+         queue: self,
+      })
+   }
+
+   /// Returns the consumer handle, or `None`, if there already is one
+   pub fn consumer(&self) -> Option<Consumer<'_, T>> {
+      Some(Consumer {
+         _lock: try_lock(&self.consumer)?,
+         queue: self,
+      })
+   }
+
+   fn next(idx: usize) -> usize {
+      (idx + 1) % QUEUE_SLOTS
+   }
+
+   fn is_full(&self) -> bool {
+      Self::next(self.head.load(Ordering::Acquire)) == self.tail.load(Ordering::Acquire)
+   }
+
+   fn is_empty(&self) -> bool {
+      self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+   }
+
+   /// Frees the slot at `tail`.
+   ///
+   /// # Safety
+   /// The caller needs to hold the consumer lock and the queue may not be empty.
+   unsafe fn pop(&self) {
+      let tail = self.tail.load(Ordering::Acquire);
+      self.tail.store(Self::next(tail), Ordering::Release);
+   }
+}
+
+impl<T> fmt::Debug for Queue<T> {
+   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      f.debug_struct("Queue")
+         .field("head", &self.head.load(Ordering::Relaxed))
+         .field("tail", &self.tail.load(Ordering::Relaxed))
+         .finish()
+   }
+}
+
+/// The producing side of a [`Queue`].
+pub struct Producer<'a, T> {
+   _lock: BufGuard<'a, ()>,
+   queue: &'a Queue<T>,
+}
+
+impl<T> Producer<'_, T> {
+   /// Returns the slot, in which the next element is prepared
+   pub fn slot(&self) -> &T {
+      // SAFETY: The slot at head is owned by the producer
+      unsafe { &*self.queue.slots[self.queue.head.load(Ordering::Acquire)].get() }
+   }
+
+   /// Returns the slot, in which the next element is prepared
+   pub fn slot_mut(&mut self) -> &mut T {
+      // SAFETY: The slot at head is owned by the producer
+      unsafe { &mut *self.queue.slots[self.queue.head.load(Ordering::Acquire)].get() }
+   }
+
+   /// Returns `true`, if the prepared element can not be committed right now
+   pub fn is_full(&self) -> bool {
+      self.queue.is_full()
+   }
+
+   /// Hands the prepared element over to the consumer.
+   /// Returns `false`, if the queue is full.
+   ///
+   /// NOTE: The slot of the next element may still hold old data.
+   pub fn commit(&mut self) -> bool {
+      if self.queue.is_full() {
+         return false;
+      }
+
+      let head = self.queue.head.load(Ordering::Acquire);
+      self.queue.head.store(Queue::<T>::next(head), Ordering::Release);
+      true
+   }
+
+   /// Drops the oldest element in the queue, to make room for the prepared element.
+   /// Returns `false`, if the queue is empty or the consumer is accessing it right now.
+   pub fn drop_oldest(&mut self) -> bool {
+      let _consumer = match try_lock(&self.queue.consumer) {
+         None => return false,
+         Some(consumer) => consumer,
+      };
+
+      if self.queue.is_empty() {
+         return false;
+      }
+
+      // SAFETY: We hold the consumer lock and checked, that the queue is not empty
+      unsafe { self.queue.pop() };
+      true
+   }
+}
+
+/// The consuming side of a [`Queue`].
+pub struct Consumer<'a, T> {
+   _lock: BufGuard<'a, ()>,
+   queue: &'a Queue<T>,
+}
+
+impl<T> Consumer<'_, T> {
+   /// Returns the oldest element, or `None` if the queue is empty
+   pub fn peek(&self) -> Option<&T> {
+      match self.queue.is_empty() {
+         true => None,
+         // SAFETY: The slot at tail is owned by the consumer, if the queue is not empty
+         false => Some(unsafe { &*self.queue.slots[self.queue.tail.load(Ordering::Acquire)].get() }),
+      }
+   }
+
+   /// Returns the oldest element, or `None` if the queue is empty
+   pub fn peek_mut(&mut self) -> Option<&mut T> {
+      match self.queue.is_empty() {
+         true => None,
+         // SAFETY: The slot at tail is owned by the consumer, if the queue is not empty
+         false => Some(unsafe { &mut *self.queue.slots[self.queue.tail.load(Ordering::Acquire)].get() }),
+      }
+   }
+
+   /// Frees the oldest element, such that the producer can reuse its slot
+   pub fn pop(&mut self) {
+      if !self.queue.is_empty() {
+         // SAFETY: We hold the consumer lock and checked, that the queue is not empty
+         unsafe { self.queue.pop() };
+      }
+   }
+}
+
+// NOTE: These tests are meant to be run under Miri as well: `cargo +nightly miri test`
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn queue_holds_depth_elements() {
+      let queue = Queue::new(|| 0u32);
+      let mut producer = queue.producer().unwrap();
+
+      for value in 1..=QUEUE_DEPTH as u32 {
+         *producer.slot_mut() = value;
+         assert!(producer.commit());
+      }
+      assert!(producer.is_full());
+      assert!(!producer.commit());
+
+      let mut consumer = queue.consumer().unwrap();
+      for value in 1..=QUEUE_DEPTH as u32 {
+         assert_eq!(consumer.peek(), Some(&value));
+         consumer.pop();
+      }
+      assert!(consumer.peek().is_none());
+   }
+
+   #[test]
+   fn only_one_producer_and_consumer() {
+      let queue = Queue::new(|| 0u32);
+
+      let producer = queue.producer().unwrap();
+      assert!(queue.producer().is_none());
+      drop(producer);
+      assert!(queue.producer().is_some());
+
+      let consumer = queue.consumer().unwrap();
+      assert!(queue.consumer().is_none());
+      drop(consumer);
+      assert!(queue.consumer().is_some());
+   }
+
+   #[test]
+   fn drop_oldest_excludes_consumer() {
+      let queue = Queue::new(|| 0u32);
+      let mut producer = queue.producer().unwrap();
+      while producer.commit() {}
+
+      // The consumer is accessing the oldest element, so it can not be dropped
+      let consumer = queue.consumer().unwrap();
+      assert!(!producer.drop_oldest());
+      drop(consumer);
+
+      assert!(producer.drop_oldest());
+      assert!(producer.commit());
+   }
+}
@@ -0,0 +1,477 @@
+//! This module implements a minimal IPv6 router advertisement responder, such that the host
+//! on the other side of the USB link configures an address through SLAAC, RFC 4862.
+//!
+//! Like the [`DhcpServer`](crate::DhcpServer), it works on raw ethernet frames:
+//! Frames received from the host are passed to [`RouterAdvertiser::process`] first, which answers
+//! router solicitations, and [`RouterAdvertiser::poll`] sends the unsolicited advertisements.
+//! With a network stack, the same is done on IPv6 packets through
+//! [`RouterAdvertiser::process_packet`] and [`RouterAdvertiser::poll_packet`], e.g. with a raw
+//! ICMPv6 socket of `smoltcp`.
+
+use crate::{
+   wire::{
+      checksum_add, checksum_finish, ipv6_link_local, ipv6_multicast_mac, write_u16, EthernetHeader,
+      Ipv6Header, PseudoHeader, ETHERTYPE_IPV6, IPV6_ALL_NODES, IPV6_HEADER_SIZE, IP_PROTO_ICMPV6,
+   },
+   FrameSender, ETH_HEADER_SIZE,
+};
+
+// ICMPv6 message types, RFC 4861
+const ROUTER_SOLICITATION: u8 = 133;
+const ROUTER_ADVERTISEMENT: u8 = 134;
+
+/// Neighbor discovery messages are only accepted, if they have not been forwarded
+const ND_HOP_LIMIT: u8 = 255;
+
+// Neighbor discovery options
+const OPT_SOURCE_LL_ADDR: u8 = 1;
+const OPT_PREFIX_INFO: u8 = 3;
+const OPT_MTU: u8 = 5;
+const OPT_RDNSS: u8 = 25;
+
+const PREFIX_FLAG_ON_LINK: u8 = 0x80;
+const PREFIX_FLAG_AUTONOMOUS: u8 = 0x40;
+
+/// The length of a prefix, from which hosts can configure an address through SLAAC, RFC 4862
+const SLAAC_PREFIX_LEN: u8 = 64;
+
+/// The hop limit, that hosts should use
+const CUR_HOP_LIMIT: u8 = 64;
+
+// Router constants, RFC 4861 section 10
+const MAX_INITIAL_RTR_ADVERT_INTERVAL: u64 = 16_000_000;
+const MAX_INITIAL_RTR_ADVERTISEMENTS: u8 = 3;
+const MIN_DELAY_BETWEEN_RAS: u64 = 3_000_000;
+/// The minimum of `MaxRtrAdvInterval` in seconds
+const MIN_RTR_ADV_INTERVAL: u32 = 4;
+
+/// The size of an advertisement with all options
+const MAX_ADVERTISEMENT_SIZE: usize = 16 + 8 + 8 + 32 + 24;
+
+/// The size of a packet written by [`RouterAdvertiser::poll_packet`] at most
+pub const MAX_RA_PACKET_SIZE: usize = IPV6_HEADER_SIZE + MAX_ADVERTISEMENT_SIZE;
+
+/// Configures the [`RouterAdvertiser`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaConfig {
+   /// The mac address, the advertisements are sent from.
+   /// The link-local address of the router is derived from it.
+   pub router_mac: [u8; 6],
+   /// The advertised prefix
+   pub prefix: [u8; 16],
+   /// The length of the prefix, which has to be 64 for SLAAC.
+   /// Other prefixes are only advertised as on-link, longer ones than 128 are clamped.
+   pub prefix_len: u8,
+   /// The time in seconds, for which addresses from the prefix are valid
+   pub valid_lifetime: u32,
+   /// The time in seconds, for which addresses from the prefix are preferred
+   pub preferred_lifetime: u32,
+   /// The time in seconds, for which the device is the default router of the host.
+   /// Zero, if the device should not be used as default router.
+   pub router_lifetime: u16,
+   /// The MTU, that is advertised to the host, if any
+   pub mtu: Option<u32>,
+   /// The DNS server, that is advertised to the host, if any, RFC 8106
+   pub dns_server: Option<[u8; 16]>,
+   /// The interval in seconds between unsolicited advertisements.
+   /// Shorter ones than the minimum of 4 seconds of RFC 4861 are clamped.
+   pub interval: u32,
+}
+
+/// Sends IPv6 router advertisements to the host.
+#[derive(Debug, Clone)]
+pub struct RouterAdvertiser {
+   config: RaConfig,
+   /// The time in microseconds, at which the next unsolicited advertisement is due
+   next_at: u64,
+   /// The time in microseconds, at which the last advertisement was sent, if any
+   last_sent: Option<u64>,
+   /// The number of advertisements sent since the last reset
+   sent: u8,
+}
+
+impl RouterAdvertiser {
+   pub fn new(mut config: RaConfig) -> Self {
+      if config.prefix_len > 128 {
+         log::warn!("clamping the prefix length {} to 128", config.prefix_len);
+         config.prefix_len = 128;
+      }
+      if config.interval < MIN_RTR_ADV_INTERVAL {
+         log::warn!("clamping the advertisement interval {}s to {}s", config.interval, MIN_RTR_ADV_INTERVAL);
+         config.interval = MIN_RTR_ADV_INTERVAL;
+      }
+      if config.prefix_len != SLAAC_PREFIX_LEN {
+         log::warn!("prefix length {} is not usable for SLAAC", config.prefix_len);
+      }
+      // The bits after the prefix have to be zero
+      for (n, byte) in config.prefix.iter_mut().enumerate() {
+         let bits = (config.prefix_len as usize).saturating_sub(n * 8).min(8);
+         *byte &= !(0xff_u16 >> bits) as u8;
+      }
+
+      Self {
+         config,
+         next_at: 0,
+         last_sent: None,
+         sent: 0,
+      }
+   }
+
+   /// Returns the link-local address, the advertisements are sent from
+   pub fn link_local_addr(&self) -> [u8; 16] {
+      ipv6_link_local(&self.config.router_mac)
+   }
+
+   /// Sends the next advertisement right away and repeats the initial advertisements.
+   ///
+   /// This should be called, once the data interface is activated,
+   /// see [`Event::DataActivated`](crate::Event::DataActivated).
+   pub fn reset(&mut self) {
+      self.next_at = 0;
+      self.last_sent = None;
+      self.sent = 0;
+   }
+
+   /// Sends an unsolicited advertisement, if one is due at `now`, in microseconds
+   pub fn poll(&mut self, now: u64, sender: &mut FrameSender) {
+      let mut frame = [0; ETH_HEADER_SIZE + MAX_RA_PACKET_SIZE];
+      if let Some(len) = self.poll_packet(now, &mut frame[ETH_HEADER_SIZE..]) {
+         self.send(&mut frame, len, sender);
+      }
+   }
+
+   /// Processes a frame received from the host.
+   ///
+   /// `now` is the current time in microseconds, like the one of a [`Clock`](crate::Clock).
+   ///
+   /// # Returns
+   /// - `true`, if the frame was a router solicitation, which should not be passed on
+   /// - `false` otherwise
+   pub fn process(&mut self, frame: &[u8], now: u64, sender: &mut FrameSender) -> bool {
+      let packet = match EthernetHeader::parse(frame) {
+         Some((eth, packet)) if eth.ethertype == ETHERTYPE_IPV6 => packet,
+         _ => return false,
+      };
+
+      let mut reply = [0; ETH_HEADER_SIZE + MAX_RA_PACKET_SIZE];
+      match self.respond(packet, now, &mut reply[ETH_HEADER_SIZE..]) {
+         None => false,
+         Some(0) => true,
+         Some(len) => {
+            self.send(&mut reply, len, sender);
+            true
+         }
+      }
+   }
+
+   /// Sends an advertisement, if one is due at `now`, without a network stack.
+   ///
+   /// The IPv6 packet is written into `buf`, which needs to hold [`MAX_RA_PACKET_SIZE`] bytes,
+   /// and its length is returned.
+   /// It is addressed to all nodes, `ff02::1`, and has to be sent as is, e.g. through a raw socket.
+   /// Returns `None`, if `buf` is too short.
+   pub fn poll_packet(&mut self, now: u64, buf: &mut [u8]) -> Option<usize> {
+      if now < self.next_at || !fits_packet(buf) {
+         return None;
+      }
+
+      log::debug!("sending unsolicited router advertisement");
+      Some(self.advertise(now, buf))
+   }
+
+   /// Processes an IPv6 packet received from the host, e.g. through a raw ICMPv6 socket.
+   ///
+   /// If the packet is a router solicitation, the advertisement is written into `reply`,
+   /// like by [`poll_packet`](Self::poll_packet), and its length is returned.
+   pub fn process_packet(&mut self, packet: &[u8], now: u64, reply: &mut [u8]) -> Option<usize> {
+      if !fits_packet(reply) {
+         return None;
+      }
+      self.respond(packet, now, reply).filter(|len| *len != 0)
+   }
+
+   /// Writes the advertisement answering `packet` into `reply`.
+   ///
+   /// Returns `None`, if the packet is not a router solicitation and `Some(0)`,
+   /// if it is one, that does not need a reply.
+   fn respond(&mut self, packet: &[u8], now: u64, reply: &mut [u8]) -> Option<usize> {
+      let (ip, message) = Ipv6Header::parse(packet)?;
+      if ip.next_header != IP_PROTO_ICMPV6 || message.len() < 8 || message[0] != ROUTER_SOLICITATION {
+         return None;
+      }
+
+      if ip.hop_limit != ND_HOP_LIMIT
+         || message[1] != 0
+         || checksum_finish(checksum_add(ip.pseudo_header_checksum(message.len()), message)) != 0
+      {
+         log::debug!("ignoring invalid router solicitation");
+         return Some(0);
+      }
+
+      // Advertisements are rate limited, so the answer is sent as soon as possible instead,
+      // RFC 4861 section 6.2.6
+      if let Some(last_sent) = self.last_sent.filter(|last_sent| now < last_sent + MIN_DELAY_BETWEEN_RAS) {
+         self.next_at = self.next_at.min(last_sent + MIN_DELAY_BETWEEN_RAS);
+         return Some(0);
+      }
+
+      log::debug!("answering router solicitation from {:02x?}", ip.src);
+      Some(self.advertise(now, reply))
+   }
+
+   /// Writes an advertisement into `buf`, schedules the next one and returns its length
+   fn advertise(&mut self, now: u64, buf: &mut [u8]) -> usize {
+      let interval = self.config.interval as u64 * 1_000_000;
+      let interval = match self.sent < MAX_INITIAL_RTR_ADVERTISEMENTS {
+         true => interval.min(MAX_INITIAL_RTR_ADVERT_INTERVAL),
+         false => interval,
+      };
+      self.next_at = now + interval;
+      self.last_sent = Some(now);
+      self.sent = self.sent.saturating_add(1);
+
+      self.emit_packet(buf)
+   }
+
+   /// Writes the advertisement packet into `buf` and returns its length
+   fn emit_packet(&self, buf: &mut [u8]) -> usize {
+      let config = &self.config;
+      let message = &mut buf[IPV6_HEADER_SIZE..IPV6_HEADER_SIZE + MAX_ADVERTISEMENT_SIZE];
+      message.fill(0);
+
+      message[0] = ROUTER_ADVERTISEMENT;
+      message[4] = CUR_HOP_LIMIT;
+      write_u16(message, 6, config.router_lifetime);
+      let mut len = 16;
+
+      message[len] = OPT_SOURCE_LL_ADDR;
+      message[len + 1] = 1;
+      message[len + 2..len + 8].copy_from_slice(&config.router_mac);
+      len += 8;
+
+      if let Some(mtu) = config.mtu {
+         message[len] = OPT_MTU;
+         message[len + 1] = 1;
+         message[len + 4..len + 8].copy_from_slice(&mtu.to_be_bytes());
+         len += 8;
+      }
+
+      message[len] = OPT_PREFIX_INFO;
+      message[len + 1] = 4;
+      message[len + 2] = config.prefix_len;
+      message[len + 3] = match config.prefix_len {
+         SLAAC_PREFIX_LEN => PREFIX_FLAG_ON_LINK | PREFIX_FLAG_AUTONOMOUS,
+         _ => PREFIX_FLAG_ON_LINK,
+      };
+      message[len + 4..len + 8].copy_from_slice(&config.valid_lifetime.to_be_bytes());
+      message[len + 8..len + 12].copy_from_slice(&config.preferred_lifetime.to_be_bytes());
+      message[len + 16..len + 32].copy_from_slice(&config.prefix);
+      len += 32;
+
+      if let Some(dns_server) = config.dns_server {
+         // The server stays valid, as long as the device is the router
+         let lifetime = (config.interval as u64 * 3).max(config.router_lifetime as u64) as u32;
+         message[len] = OPT_RDNSS;
+         message[len + 1] = 3;
+         message[len + 4..len + 8].copy_from_slice(&lifetime.to_be_bytes());
+         message[len + 8..len + 24].copy_from_slice(&dns_server);
+         len += 24;
+      }
+
+      let ip = Ipv6Header {
+         src: self.link_local_addr(),
+         dst: IPV6_ALL_NODES,
+         next_header: IP_PROTO_ICMPV6,
+         hop_limit: ND_HOP_LIMIT,
+      };
+      let checksum = checksum_finish(checksum_add(ip.pseudo_header_checksum(len), &message[..len]));
+      write_u16(message, 2, checksum);
+
+      ip.emit(buf, len);
+      IPV6_HEADER_SIZE + len
+   }
+
+   /// Adds the ethernet header to the packet in `frame` and sends it
+   fn send(&self, frame: &mut [u8], packet_len: usize, sender: &mut FrameSender) {
+      let eth = EthernetHeader {
+         dst: ipv6_multicast_mac(&IPV6_ALL_NODES),
+         src: self.config.router_mac,
+         ethertype: ETHERTYPE_IPV6,
+      };
+      eth.emit(frame);
+
+      let len = ETH_HEADER_SIZE + packet_len;
+      if !sender.try_send_frame(len, |buf| buf.copy_from_slice(&frame[..len])) {
+         log::debug!("transmit queue full, dropping router advertisement");
+      }
+   }
+}
+
+/// Returns `true`, if `buf` can hold an advertisement
+fn fits_packet(buf: &[u8]) -> bool {
+   if buf.len() < MAX_RA_PACKET_SIZE {
+      log::warn!("buffer of {} bytes is too short for a router advertisement", buf.len());
+      return false;
+   }
+   true
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   const PREFIX: [u8; 16] = [0xfd, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 0];
+   const SECOND: u64 = 1_000_000;
+
+   fn advertiser() -> RouterAdvertiser {
+      RouterAdvertiser::new(config())
+   }
+
+   fn config() -> RaConfig {
+      RaConfig {
+         router_mac: [0x02, 0, 0, 0, 0, 1],
+         prefix: PREFIX,
+         prefix_len: 64,
+         valid_lifetime: 3600,
+         preferred_lifetime: 1800,
+         router_lifetime: 0,
+         mtu: Some(1500),
+         dns_server: Some([0xfd, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 1]),
+         interval: 600,
+      }
+   }
+
+   /// Returns the prefix information option advertised in `packet`
+   fn prefix_info(packet: &[u8]) -> Option<&[u8]> {
+      let (ip, message) = Ipv6Header::parse(packet)?;
+      assert_eq!(checksum_finish(checksum_add(ip.pseudo_header_checksum(message.len()), message)), 0);
+      assert_eq!(message[0], ROUTER_ADVERTISEMENT);
+
+      let mut options = &message[16..];
+      while options.len() >= 8 {
+         let len = options[1] as usize * 8;
+         if options[0] == OPT_PREFIX_INFO {
+            return Some(&options[..len]);
+         }
+         options = &options[len..];
+      }
+      None
+   }
+
+   /// Returns the prefix advertised in `packet`
+   fn advertised_prefix(packet: &[u8]) -> Option<[u8; 16]> {
+      let mut prefix = [0; 16];
+      prefix.copy_from_slice(&prefix_info(packet)?[16..32]);
+      Some(prefix)
+   }
+
+   /// Builds a router solicitation packet from a host without an address
+   fn solicitation(buf: &mut [u8], hop_limit: u8) -> usize {
+      let ip = Ipv6Header {
+         src: [0; 16],
+         dst: [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x02],
+         next_header: IP_PROTO_ICMPV6,
+         hop_limit,
+      };
+      let message = &mut buf[IPV6_HEADER_SIZE..IPV6_HEADER_SIZE + 8];
+      message.fill(0);
+      message[0] = ROUTER_SOLICITATION;
+      let checksum = checksum_finish(checksum_add(ip.pseudo_header_checksum(8), message));
+      write_u16(message, 2, checksum);
+
+      ip.emit(buf, 8);
+      IPV6_HEADER_SIZE + 8
+   }
+
+   #[test]
+   fn solicitation_is_answered() {
+      let mut advertiser = advertiser();
+      let (mut packet, mut reply) = ([0; 100], [0; MAX_RA_PACKET_SIZE]);
+
+      let len = solicitation(&mut packet, ND_HOP_LIMIT);
+      assert!(advertiser.process_packet(&packet[..len], 0, &mut reply[..MAX_RA_PACKET_SIZE - 1]).is_none());
+      assert!(advertiser.poll_packet(0, &mut reply[..MAX_RA_PACKET_SIZE - 1]).is_none());
+      let reply_len = advertiser.process_packet(&packet[..len], 0, &mut reply).unwrap();
+      assert_eq!(reply_len, MAX_RA_PACKET_SIZE);
+      assert_eq!(advertised_prefix(&reply[..reply_len]), Some(PREFIX));
+
+      let (ip, _) = Ipv6Header::parse(&reply[..reply_len]).unwrap();
+      assert_eq!(ip.src, advertiser.link_local_addr());
+      assert_eq!(ip.dst, IPV6_ALL_NODES);
+
+      // Solicitations right after an advertisement are answered, once the minimum delay passed
+      assert_eq!(advertiser.respond(&packet[..len], SECOND, &mut reply), Some(0));
+      assert!(advertiser.poll_packet(3 * SECOND - 1, &mut reply).is_none());
+      assert!(advertiser.poll_packet(3 * SECOND, &mut reply).is_some());
+      assert!(advertiser.process_packet(&packet[..len], 6 * SECOND, &mut reply).is_some());
+
+      // Forwarded solicitations are invalid
+      let len = solicitation(&mut packet, 64);
+      assert_eq!(advertiser.respond(&packet[..len], 10 * SECOND, &mut reply), Some(0));
+
+      // Other packets are passed on
+      assert_eq!(advertiser.respond(&[0; 60], 10 * SECOND, &mut reply), None);
+   }
+
+   #[test]
+   fn unsolicited_advertisements_are_scheduled() {
+      let mut advertiser = advertiser();
+      let mut buf = [0; MAX_RA_PACKET_SIZE];
+
+      // The initial advertisements are sent faster
+      for n in 0..MAX_INITIAL_RTR_ADVERTISEMENTS as u64 {
+         let now = n * 16 * SECOND;
+         assert!(advertiser.poll_packet(now, &mut buf).is_some());
+         assert!(advertiser.poll_packet(now + SECOND, &mut buf).is_none());
+      }
+
+      let now = MAX_INITIAL_RTR_ADVERTISEMENTS as u64 * 16 * SECOND;
+      assert!(advertiser.poll_packet(now, &mut buf).is_some());
+      assert!(advertiser.poll_packet(now + 599 * SECOND, &mut buf).is_none());
+      assert!(advertiser.poll_packet(now + 600 * SECOND, &mut buf).is_some());
+
+      advertiser.reset();
+      assert!(advertiser.poll_packet(now + 601 * SECOND, &mut buf).is_some());
+   }
+
+   #[test]
+   fn prefix_length_is_validated() {
+      let mut buf = [0; MAX_RA_PACKET_SIZE];
+
+      let mut advertiser = advertiser();
+      let len = advertiser.poll_packet(0, &mut buf).unwrap();
+      let info = prefix_info(&buf[..len]).unwrap();
+      assert_eq!((info[2], info[3]), (64, PREFIX_FLAG_ON_LINK | PREFIX_FLAG_AUTONOMOUS));
+
+      // Hosts can not configure an address from other prefixes
+      let mut short = config();
+      short.prefix_len = 48;
+      short.prefix[6] = 0xff;
+      let mut advertiser = RouterAdvertiser::new(short);
+      let len = advertiser.poll_packet(0, &mut buf).unwrap();
+      let info = prefix_info(&buf[..len]).unwrap();
+      assert_eq!((info[2], info[3]), (48, PREFIX_FLAG_ON_LINK));
+      assert_eq!(advertised_prefix(&buf[..len]), Some([0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+
+      let mut long = config();
+      long.prefix_len = 200;
+      let mut advertiser = RouterAdvertiser::new(long);
+      let len = advertiser.poll_packet(0, &mut buf).unwrap();
+      let info = prefix_info(&buf[..len]).unwrap();
+      assert_eq!((info[2], info[3]), (128, PREFIX_FLAG_ON_LINK));
+      assert_eq!(advertised_prefix(&buf[..len]), Some(PREFIX));
+   }
+
+   #[test]
+   fn interval_is_clamped() {
+      let mut buf = [0; MAX_RA_PACKET_SIZE];
+      let mut config = config();
+      config.interval = 0;
+      let mut advertiser = RouterAdvertiser::new(config);
+
+      assert!(advertiser.poll_packet(0, &mut buf).is_some());
+      assert!(advertiser.poll_packet(1, &mut buf).is_none());
+      assert!(advertiser.poll_packet(4 * SECOND - 1, &mut buf).is_none());
+      assert!(advertiser.poll_packet(4 * SECOND, &mut buf).is_some());
+   }
+}
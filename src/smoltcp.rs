@@ -1,101 +1,434 @@
 use crate::{
-   buffer::{RxBufInner, TxBufInner},
-   lock::{Guard, LockHandle},
-   UsbEthernetDevice,
+   buffer::{RxBuf, RxConsumer, TxProducer},
+   FrameReceiver, FrameSender, UsbEthernetClass, UsbEthernetDevice, QUEUE_DEPTH,
 };
-use core::convert::TryInto;
 use smoltcp::{
-   phy::{Device, DeviceCapabilities, RxToken, TxToken},
+   iface::{Config, Interface},
+   phy::{Checksum, ChecksumCapabilities, Device, DeviceCapabilities, Medium, RxToken, TxToken},
    time::Instant,
-   wire::EthernetAddress,
-   Result as SmolResult,
+   wire::{EthernetAddress, HardwareAddress, IpCidr, Ipv4Address},
 };
 use usb_device::bus::{UsbBus, UsbBusAllocator};
 
-pub struct SmolUsb<'a> {
-   tx_buf: LockHandle<'a, TxBufInner>,
-   rx_buf: LockHandle<'a, RxBufInner>,
+pub struct SmolUsb<'b> {
+   rx_buf: &'b RxBuf,
+   sender: FrameSender<'b>,
+   config: SmolConfig,
+   last_rx_timestamp: Option<Instant>,
+}
+
+/// Configures the [`DeviceCapabilities`], that a [`SmolUsb`] device reports to `smoltcp`.
+///
+/// The MTU is not part of the configuration, it follows the maximum frame size
+/// set through [`UsbEthernetDevice::set_max_frame_size`].
+#[derive(Debug, Clone)]
+pub struct SmolConfig {
+   /// Which checksums `smoltcp` computes and verifies, per protocol.
+   ///
+   /// Defaults to computing and verifying all checksums.
+   pub checksum: ChecksumCapabilities,
+   /// How many frames `smoltcp` may send in a row.
+   ///
+   /// Defaults to [`QUEUE_DEPTH`], the number of frames the transmit queue holds.
+   pub max_burst_size: Option<usize>,
+}
+
+impl Default for SmolConfig {
+   fn default() -> Self {
+      Self {
+         checksum: ChecksumCapabilities::default(),
+         max_burst_size: Some(QUEUE_DEPTH),
+      }
+   }
+}
+
+impl SmolConfig {
+   /// Skips verifying the checksums of received packets.
+   ///
+   /// The USB bulk transfers are already protected by a CRC, so corrupted frames
+   /// do not make it through to `smoltcp`.
+   /// The checksums of sent packets are still computed.
+   ///
+   /// NOTE: The ICMPv6 checksums are only skipped with the `ipv6` feature of this crate.
+   /// If IPv6 is only enabled in `smoltcp` by another crate, through its `proto-ipv6` feature,
+   /// they are still verified. Enable the `ipv6` feature, whenever IPv6 is used.
+   pub fn skip_rx_verification(mut self) -> Self {
+      self.checksum.ipv4 = Checksum::Tx;
+      self.checksum.udp = Checksum::Tx;
+      self.checksum.tcp = Checksum::Tx;
+      self.checksum.icmpv4 = Checksum::Tx;
+      #[cfg(feature = "ipv6")]
+      {
+         self.checksum.icmpv6 = Checksum::Tx;
+      }
+      self
+   }
+}
+
+/// Configures the [`Interface`], that is set up by [`UsbEthernetDevice::get_smol_interface`].
+#[derive(Debug, Clone, Default)]
+pub struct SmolIfaceConfig<'c> {
+   /// The IP addresses of the interface
+   pub ip_addrs: &'c [IpCidr],
+   /// The default IPv4 gateway, if any
+   pub ipv4_gateway: Option<Ipv4Address>,
+   /// The seed for the random numbers of `smoltcp`, e.g. TCP initial sequence numbers.
+   ///
+   /// This should be different on every boot, e.g. taken from a hardware RNG.
+   pub random_seed: u64,
 }
 
 impl<'a, B> UsbEthernetDevice<'a, B>
 where
    B: UsbBus,
 {
-   // TODO: Documentation
+   /// Create a new [`UsbEthernetDevice`] with a `smoltcp` [`EthernetAddress`] as the mac address of the host.
    pub fn with_ethernet(alloc: &'a UsbBusAllocator<B>, addr: &EthernetAddress) -> Self {
-      Self::new(alloc, &addr.as_bytes().try_into().unwrap())
+      Self::new(alloc, &addr.0)
    }
 
-   // TODO: Documetation
-   pub fn get_smol<'b>(&'b self) -> SmolUsb<'b> {
-      SmolUsb {
-         tx_buf: self.tx_buf.get_handle(),
-         rx_buf: self.rx_buf.get_handle(),
+   /// Splits the device into its USB side and a [`SmolUsb`] device,
+   /// that can be used with `smoltcp`.
+   ///
+   /// The [`SmolConfig`] determines the capabilities reported to `smoltcp`.
+   pub fn get_smol<'b>(&'b mut self, config: SmolConfig) -> (UsbEthernetClass<'b, 'a, B>, SmolUsb<'b>) {
+      let (class, receiver, sender) = self.split();
+      (class, SmolUsb::new(receiver, sender, config))
+   }
+
+   /// Splits the device like [`get_smol`](UsbEthernetDevice::get_smol) and sets up
+   /// a ready to use `smoltcp` [`Interface`] on top of the [`SmolUsb`] device.
+   ///
+   /// The interface uses the mac address of the device, see
+   /// [`device_mac_address`](UsbEthernetDevice::device_mac_address).
+   /// `smoltcp` keeps the IP addresses, routes and the neighbor cache inside of the [`Interface`],
+   /// so only the sockets need storage provided by the caller.
+   pub fn get_smol_interface<'b>(
+      &'b mut self,
+      config: SmolConfig,
+      iface_config: &SmolIfaceConfig,
+      now: Instant,
+   ) -> (UsbEthernetClass<'b, 'a, B>, SmolUsb<'b>, Interface) {
+      let mac_addr = EthernetAddress(self.device_mac_address());
+      let (class, mut smol) = self.get_smol(config);
+      let iface = smol.interface(mac_addr, iface_config, now);
+      (class, smol, iface)
+   }
+}
+
+impl<'b> SmolUsb<'b> {
+   /// Create a new [`SmolUsb`] device from the application side handles.
+   pub fn new(receiver: FrameReceiver<'b>, sender: FrameSender<'b>, config: SmolConfig) -> Self {
+      Self {
+         rx_buf: receiver.rx_buf(),
+         sender,
+         config,
+         last_rx_timestamp: None,
       }
    }
+
+   /// Returns the receive timestamp of the last frame, that has been consumed by `smoltcp`.
+   ///
+   /// If no [`Clock`](crate::Clock) has been set, this falls back to the timestamp
+   /// `smoltcp` received the frame at.
+   pub fn last_rx_timestamp(&self) -> Option<Instant> {
+      self.last_rx_timestamp
+   }
+
+   /// Sets up a `smoltcp` [`Interface`] for this device.
+   pub fn interface(&mut self, mac_addr: EthernetAddress, iface_config: &SmolIfaceConfig, now: Instant) -> Interface {
+      let mut config = Config::new(HardwareAddress::Ethernet(mac_addr));
+      config.random_seed = iface_config.random_seed;
+
+      let mut iface = Interface::new(config, self, now);
+      iface.update_ip_addrs(|addrs| {
+         for addr in iface_config.ip_addrs {
+            if addrs.push(*addr).is_err() {
+               log::warn!("interface is out of address slots, ignoring {}", addr);
+            }
+         }
+      });
+
+      if let Some(gateway) = iface_config.ipv4_gateway {
+         if iface.routes_mut().add_default_ipv4_route(gateway).is_err() {
+            log::warn!("interface is out of route slots, ignoring gateway {}", gateway);
+         }
+      }
+      iface
+   }
+
+   /// Returns the transmit producer, if a frame can be queued right now
+   fn tx_producer(&self) -> Option<TxProducer<'b>> {
+      if !self.sender.accepts_frames() {
+         return None;
+      }
+      self.sender.tx_buf().producer().filter(|producer| !producer.is_full())
+   }
 }
 
-impl<'a> Device<'a> for SmolUsb<'a> {
-   type TxToken = UsbTxToken<'a>;
-   type RxToken = UsbRxToken<'a>;
+impl Device for SmolUsb<'_> {
+   type RxToken<'a>
+      = UsbRxToken<'a>
+   where
+      Self: 'a;
+   type TxToken<'a>
+      = UsbTxToken<'a>
+   where
+      Self: 'a;
 
    fn capabilities(&self) -> DeviceCapabilities {
       let mut cap = DeviceCapabilities::default();
-      cap.max_transmission_unit = 1514;
-      cap.max_burst_size = Some(1);
+      cap.medium = Medium::Ethernet;
+      cap.max_transmission_unit = self.rx_buf.max_frame_size();
+      cap.max_burst_size = self.config.max_burst_size;
+      cap.checksum = self.config.checksum.clone();
 
       cap
    }
 
-   fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
-      // Try to acquire both buffers
-      // Only proceed, if there is a frame ready and also no output transmission
-      match (self.rx_buf.try_lock(), self.tx_buf.try_lock()) {
-         (Some(rx_buf), Some(tx_buf)) => match (rx_buf.frame_complete(), tx_buf.is_sending()) {
-            (true, false) => Some((UsbRxToken(rx_buf), UsbTxToken(tx_buf))),
-            _ => None,
+   fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+      // Only proceed, if there is a frame ready and also room for a response
+      let rx_consumer = self.rx_buf.consumer().filter(|consumer| consumer.peek().is_some())?;
+      let tx_producer = self.tx_producer()?;
+
+      Some((
+         UsbRxToken {
+            consumer: rx_consumer,
+            received_at: timestamp,
+            last_timestamp: &mut self.last_rx_timestamp,
          },
-         _ => None,
-      }
+         UsbTxToken::new(tx_producer, &self.sender),
+      ))
    }
 
-   fn transmit(&'a mut self) -> Option<Self::TxToken> {
-      // Try to acquire the tx buffer
-      // Return early, if there is a sending in progress
-      match self.tx_buf.try_lock() {
-         Some(tx_buf) => match tx_buf.is_sending() {
-            false => Some(UsbTxToken(tx_buf)),
-            true => None,
-         },
-         None => None,
-      }
+   fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+      // Return early, if the transmit queue is full
+      let tx_producer = self.tx_producer()?;
+      Some(UsbTxToken::new(tx_producer, &self.sender))
    }
 }
 
-impl<'a> SmolUsb<'a> {}
+pub struct UsbTxToken<'a> {
+   producer: TxProducer<'a>,
+   sender: &'a FrameSender<'a>,
+   pad: bool,
+}
 
-pub struct UsbTxToken<'a>(Guard<'a, TxBufInner>);
+impl<'a> UsbTxToken<'a> {
+   fn new(producer: TxProducer<'a>, sender: &'a FrameSender<'a>) -> Self {
+      Self {
+         producer,
+         sender,
+         pad: sender.tx_buf().pad(),
+      }
+   }
 
-impl<'a> TxToken for UsbTxToken<'a> {
-   fn consume<R, F>(mut self, _timestamp: Instant, len: usize, f: F) -> SmolResult<R>
+   /// Commits the frame and triggers its transmission
+   fn commit(mut self) {
+      self.producer.commit();
+      drop(self.producer);
+      self.sender.frame_queued();
+   }
+}
+
+impl TxToken for UsbTxToken<'_> {
+   fn consume<R, F>(mut self, len: usize, f: F) -> R
    where
-      F: FnOnce(&mut [u8]) -> SmolResult<R>,
+      F: FnOnce(&mut [u8]) -> R,
    {
-      // We know that we are ready to send, because we checked
-      // and have not released the lock since
-      f(self.0.try_send_frame(len).unwrap())
+      // We know that there is room in the queue, because we checked
+      // and nobody else can produce frames in the meantime
+      let result = f(self.producer.slot_mut().try_send_frame(len, self.pad).unwrap());
+      self.commit();
+      result
    }
 }
 
-pub struct UsbRxToken<'a>(Guard<'a, RxBufInner>);
+pub struct UsbRxToken<'a> {
+   consumer: RxConsumer<'a>,
+   received_at: Instant,
+   last_timestamp: &'a mut Option<Instant>,
+}
 
-impl<'a> RxToken for UsbRxToken<'a> {
-   fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> SmolResult<R>
+impl<'a> UsbRxToken<'a> {
+   /// Returns the time, at which the frame has been received completely.
+   /// Returns `None`, if no [`Clock`](crate::Clock) has been set.
+   pub fn timestamp(&self) -> Option<Instant> {
+      self
+         .consumer
+         .peek()
+         .and_then(|buf| buf.timestamp())
+         .map(|micros| Instant::from_micros(micros as i64))
+   }
+}
+
+impl RxToken for UsbRxToken<'_> {
+   fn consume<R, F>(mut self, f: F) -> R
    where
-      F: FnOnce(&mut [u8]) -> SmolResult<R>,
+      F: FnOnce(&[u8]) -> R,
    {
+      *self.last_timestamp = Some(self.timestamp().unwrap_or(self.received_at));
+
       // We know that we have a frame ready because we checked
-      // and have not released the lock since.
-      f(self.0.try_get_frame().unwrap())
+      // and nobody else can consume frames in the meantime.
+      let result = f(self.consumer.peek_mut().unwrap().try_get_frame().unwrap());
+
+      // Free the slot after reading the frame
+      self.consumer.pop();
+      result
+   }
+}
+
+// NOTE: These tests are meant to be run under Miri as well: `cargo +nightly miri test`
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{
+      buffer::{RxProducer, TxBuf},
+      link::Link,
+      TxKick,
+   };
+   use core::sync::atomic::{AtomicUsize, Ordering};
+   extern crate std;
+   #[cfg(feature = "sync")]
+   use std::thread;
+
+   const FRAME_LEN: usize = 42;
+
+   /// Writes a frame into the producer slot, like the USB side does
+   fn prepare_frame(producer: &mut RxProducer, pattern: u8) {
+      let buf = producer.slot_mut();
+      buf.reset();
+      buf.insert_packet()[..FRAME_LEN].iter_mut().for_each(|byte| *byte = pattern);
+      buf.advance(FRAME_LEN);
+   }
+
+   #[test]
+   fn usb_side_and_smoltcp_side_do_not_alias() {
+      let (rx_buf, tx_buf, link) = (RxBuf::new(), TxBuf::new(), Link::new());
+      let mut smol = SmolUsb::new(
+         FrameReceiver::new(&rx_buf, &link),
+         FrameSender::new(&tx_buf, &link, None, None),
+         SmolConfig::default(),
+      );
+
+      // A frame, that is still being received, is not visible to smoltcp
+      let mut usb_rx = rx_buf.producer().unwrap();
+      prepare_frame(&mut usb_rx, 1);
+      assert!(smol.receive(Instant::from_millis(0)).is_none());
+      assert!(usb_rx.commit());
+
+      // While smoltcp holds the tokens, the USB side receives into a different slot
+      let (rx, tx) = smol.receive(Instant::from_millis(0)).unwrap();
+      assert!(rx_buf.consumer().is_none());
+      assert!(tx_buf.producer().is_none());
+      prepare_frame(&mut usb_rx, 2);
+
+      rx.consume(|frame| assert_eq!(frame, &[1; FRAME_LEN][..]));
+
+      // A frame is only visible to the USB side, once smoltcp has written it
+      let mut usb_tx = tx_buf.consumer().unwrap();
+      assert!(usb_tx.peek().is_none());
+      tx.consume(FRAME_LEN, |frame| frame.iter_mut().for_each(|byte| *byte = 3));
+      assert_eq!(usb_tx.peek_mut().unwrap().try_get_packet().unwrap(), &[3; FRAME_LEN][..]);
+   }
+
+   #[test]
+   fn capabilities_follow_config_and_frame_size() {
+      let (mut rx_buf, tx_buf, link) = (RxBuf::new(), TxBuf::new(), Link::new());
+      rx_buf.set_max_frame_size(1000);
+      let smol = SmolUsb::new(
+         FrameReceiver::new(&rx_buf, &link),
+         FrameSender::new(&tx_buf, &link, None, None),
+         SmolConfig::default().skip_rx_verification(),
+      );
+
+      let cap = smol.capabilities();
+      assert_eq!(cap.max_transmission_unit, 1000);
+      assert_eq!(cap.max_burst_size, Some(QUEUE_DEPTH));
+      assert!(!cap.checksum.tcp.rx() && cap.checksum.tcp.tx());
+      #[cfg(feature = "ipv6")]
+      assert!(!cap.checksum.icmpv6.rx() && cap.checksum.icmpv6.tx());
+   }
+
+   #[test]
+   fn interface_is_set_up_from_config() {
+      let (rx_buf, tx_buf, link) = (RxBuf::new(), TxBuf::new(), Link::new());
+      let mut smol = SmolUsb::new(
+         FrameReceiver::new(&rx_buf, &link),
+         FrameSender::new(&tx_buf, &link, None, None),
+         SmolConfig::default(),
+      );
+
+      let mac_addr = EthernetAddress([0x02, 0, 0, 0, 0, 1]);
+      let ip_addr = IpCidr::new(Ipv4Address::new(192, 168, 7, 1).into(), 24);
+      let iface_config = SmolIfaceConfig {
+         ip_addrs: &[ip_addr],
+         ipv4_gateway: Some(Ipv4Address::new(192, 168, 7, 2)),
+         random_seed: 42,
+      };
+
+      let iface = smol.interface(mac_addr, &iface_config, Instant::from_millis(0));
+      assert_eq!(iface.hardware_addr(), HardwareAddress::Ethernet(mac_addr));
+      assert_eq!(iface.ip_addrs(), &[ip_addr]);
+   }
+
+   #[test]
+   fn committed_frame_kicks_usb_side() {
+      struct Counter(AtomicUsize);
+      impl TxKick for Counter {
+         fn kick(&self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+         }
+      }
+
+      let (rx_buf, tx_buf, link) = (RxBuf::new(), TxBuf::new(), Link::new());
+      let counter = Counter(AtomicUsize::new(0));
+      let sender = FrameSender::new(&tx_buf, &link, Some(&counter), None);
+      let mut smol = SmolUsb::new(FrameReceiver::new(&rx_buf, &link), sender, SmolConfig::default());
+
+      let tx = smol.transmit(Instant::from_millis(0)).unwrap();
+      assert_eq!(counter.0.load(Ordering::Relaxed), 0);
+      tx.consume(FRAME_LEN, |_| ());
+      assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+   }
+
+   #[test]
+   #[cfg(feature = "sync")]
+   fn usb_side_and_smoltcp_side_in_different_threads() {
+      const FRAMES: u8 = 20;
+      let (rx_buf, tx_buf, link) = (RxBuf::new(), TxBuf::new(), Link::new());
+      let (receiver, sender) = (FrameReceiver::new(&rx_buf, &link), FrameSender::new(&tx_buf, &link, None, None));
+
+      thread::scope(|scope| {
+         scope.spawn(|| {
+            let mut producer = rx_buf.producer().unwrap();
+            let mut pattern = 0;
+            while pattern < FRAMES {
+               prepare_frame(&mut producer, pattern);
+               while !producer.commit() {
+                  thread::yield_now();
+               }
+               pattern += 1;
+            }
+         });
+
+         scope.spawn(move || {
+            let mut smol = SmolUsb::new(receiver, sender, SmolConfig::default());
+            let mut pattern = 0;
+            while pattern < FRAMES {
+               let (rx, _tx) = match smol.receive(Instant::from_millis(0)) {
+                  None => {
+                     thread::yield_now();
+                     continue;
+                  }
+                  Some(tokens) => tokens,
+               };
+
+               rx.consume(|frame| assert!(frame.iter().all(|byte| *byte == pattern)));
+               pattern += 1;
+            }
+         });
+      });
    }
 }
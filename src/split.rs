@@ -0,0 +1,882 @@
+//! This module implements the halves a [`UsbEthernetDevice`](crate::UsbEthernetDevice)
+//! can be split into.
+//!
+//! The [`UsbEthernetClass`] drives the USB endpoints and is handed to the `usb-device` stack,
+//! while the [`FrameReceiver`] and [`FrameSender`] are used by the application to consume and
+//! produce ethernet frames.
+//! With the `sync` feature enabled, the halves are `Send` and can therefore be used from
+//! different contexts, e.g. the USB interrupt handler and the main loop.
+//! The network stack adapters, like [`SmolUsb`](crate::SmolUsb), are built on top of
+//! these handles, so any other stack can be attached the same way.
+//!
+//! Besides polling, the application side can `await` frames and room in the transmit queue.
+//! The waiting tasks are woken from the endpoint callbacks of the [`UsbEthernetClass`].
+
+use crate::{
+   buffer::{RxBuf, RxOverflowPolicy, RxProducer, TxBuf, TxSuspendPolicy},
+   clock::Clock,
+   ecm::CdcEcmClass,
+   event::{Event, EventHandler},
+   kick::TxKick,
+   link::Link,
+   mac::local_unicast_mac,
+   wakeup::RemoteWakeup,
+   EP_PKG_USIZE, ETH_FRAME_SIZE, ETH_HEADER_SIZE,
+};
+use core::{fmt, future::poll_fn, task::Poll};
+use usb_device::{
+   bus::{StringIndex, UsbBus},
+   class::{ControlIn, ControlOut, UsbClass},
+   control::{Recipient, Request, RequestType},
+   descriptor::DescriptorWriter,
+   device::UsbDeviceState,
+   endpoint::EndpointAddress,
+   Result as UsbResult, UsbError,
+};
+
+/// The USB side of a split [`UsbEthernetDevice`](crate::UsbEthernetDevice).
+///
+/// It implements [`UsbClass`] and has to be polled by the `usb-device` stack.
+pub struct UsbEthernetClass<'b, 'a, B: UsbBus> {
+   ecm: &'b mut CdcEcmClass<'a, B>,
+   tx_buf: &'b TxBuf,
+   rx_buf: &'b RxBuf,
+   link: &'b Link,
+   clock: Option<&'a dyn Clock>,
+   events: Option<&'a dyn EventHandler>,
+   /// The address of the device, which the host must not use
+   device_mac: [u8; 6],
+}
+
+impl<'b, 'a, B: UsbBus> UsbEthernetClass<'b, 'a, B> {
+   pub(crate) fn new(
+      ecm: &'b mut CdcEcmClass<'a, B>,
+      tx_buf: &'b TxBuf,
+      rx_buf: &'b RxBuf,
+      link: &'b Link,
+      clock: Option<&'a dyn Clock>,
+      events: Option<&'a dyn EventHandler>,
+      device_mac: [u8; 6],
+   ) -> Self {
+      Self {
+         ecm,
+         tx_buf,
+         rx_buf,
+         link,
+         clock,
+         events,
+         device_mac,
+      }
+   }
+
+   /// Passes the state of the `UsbDevice` on to the class.
+   ///
+   /// `usb-device` does not notify the classes about suspend and resume.
+   /// Call this after every poll of the `UsbDevice` with the value of `UsbDevice::state`,
+   /// to get the [`Event::Suspend`] and [`Event::Resume`] events.
+   pub fn update_device_state(&mut self, state: UsbDeviceState) {
+      let suspended = state == UsbDeviceState::Suspend;
+      if !self.link.set_suspended(suspended) {
+         return;
+      }
+
+      log::debug!("bus {}", if suspended { "suspended" } else { "resumed" });
+      match suspended {
+         true if self.tx_buf.suspend_policy() == TxSuspendPolicy::Refuse => self.drop_queued_frames(),
+         true => (),
+         // Pick up the parked frames
+         false => self.try_send(),
+      }
+      self.raise(if suspended { Event::Suspend } else { Event::Resume });
+   }
+
+   /// Changes the mac address, that is reported to the host, e.g. once it has been provisioned.
+   ///
+   /// The host only reads the address while enumerating the device.
+   /// Returns `true`, if the address changed, in which case `UsbDevice::force_reset`
+   /// makes the host re-enumerate the device and pick up the new address.
+   ///
+   /// The address is made a locally administered unicast address, see [`local_unicast_mac`].
+   /// It is refused, if it equals the address of the device.
+   pub fn set_mac_address(&mut self, mac_addr: &[u8; 6]) -> bool {
+      let mac_addr = local_unicast_mac(*mac_addr);
+      if mac_addr == self.device_mac {
+         log::warn!("refusing the mac address of the device as address of the host");
+         return false;
+      }
+      self.ecm.set_mac_address(&mac_addr)
+   }
+
+   /// Drops the frames waiting in the transmit queue, since they can not be delivered
+   fn drop_queued_frames(&self) {
+      for _ in 0..drop_queued_frames(self.tx_buf) {
+         self.raise(Event::FrameDropped);
+      }
+   }
+
+   /// Passes an event on to the [`EventHandler`], if there is one
+   fn raise(&self, event: Event) {
+      if let Some(events) = self.events {
+         events.handle_event(event);
+      }
+   }
+
+   /// Takes over the state of the data interface as the link state
+   fn update_link(&self) {
+      if self.link.set_up(self.ecm.is_data_active()) {
+         log::debug!("link is {}", if self.link.is_up() { "up" } else { "down" });
+         self.raise(match self.link.is_up() {
+            true => Event::DataActivated,
+            false => Event::DataDeactivated,
+         });
+      }
+   }
+
+   /// Attempts to receive data into rx_buf
+   fn try_recv(&self) {
+      let mut producer = match self.rx_buf.producer() {
+         None => return,
+         Some(producer) => producer,
+      };
+
+      // If a complete frame is still waiting to be queued, the pipe stalls
+      // until the application processed a frame. The frame is then queued by the
+      // application side and the reading is picked up again in `poll`.
+      if producer.slot().frame_complete() && !self.commit_frame(&mut producer) {
+         return;
+      }
+
+      let buf = producer.slot_mut();
+
+      // Read and drop the packets of a discarded frame
+      if buf.is_discarding() {
+         let mut pkg = [0; EP_PKG_USIZE];
+         match self.ecm.get_read_ep().read(&mut pkg) {
+            Ok(bytes_read) => {
+               buf.advance_discarding(bytes_read);
+            }
+            Err(UsbError::WouldBlock) => (),
+            Err(err) => log::error!("unexpected usb error: {:?}", err),
+         }
+         return;
+      }
+
+      // Read a packet from the host
+      match self.ecm.get_read_ep().read(buf.insert_packet()) {
+         Ok(bytes_read) => {
+            buf.advance(bytes_read);
+
+            // Reject frames, that can not be valid ethernet frames
+            let len = buf.received_len();
+            if buf.frame_complete() && len < ETH_HEADER_SIZE {
+               log::debug!("received runt frame of {} bytes, dropping frame", len);
+               self.rx_buf.count_invalid();
+               self.raise(Event::FrameDropped);
+               buf.reset();
+            } else if len > self.rx_buf.max_frame_size() {
+               log::debug!("received frame longer than {} bytes, dropping frame", self.rx_buf.max_frame_size());
+               self.rx_buf.count_invalid();
+               self.raise(Event::FrameDropped);
+               if !buf.frame_complete() {
+                  buf.start_discarding();
+               }
+               buf.reset();
+            }
+
+            // Timestamp the frame, as soon as the last packet arrived, and queue it
+            if buf.frame_complete() {
+               buf.set_timestamp(self.clock.map(|clock| clock.now_micros()));
+               self.commit_frame(&mut producer);
+            }
+         }
+         // This can only be triggered by a a host ingoring our boundaries
+         Err(UsbError::BufferOverflow) => {
+            log::warn!("received more data than fits in one ethernet packet, dropping frame");
+            self.rx_buf.count_invalid();
+            self.raise(Event::FrameDropped);
+            buf.start_discarding();
+            buf.reset();
+         }
+         // Nothing to read, e.g. when called from `poll`
+         Err(UsbError::WouldBlock) => (),
+         Err(err) => {
+            log::error!("unexpected usb error: {:?}", err);
+            //self.reset();
+         }
+      }
+   }
+
+   /// Hands a completely received frame over to the application.
+   ///
+   /// If the receive queue is full, the [`RxOverflowPolicy`] decides what happens.
+   /// Returns `false`, if the frame is still waiting to be queued.
+   fn commit_frame(&self, producer: &mut RxProducer) -> bool {
+      if !producer.commit() {
+         match self.rx_buf.policy() {
+            RxOverflowPolicy::Backpressure => return false,
+            RxOverflowPolicy::DropNewest => {
+               log::debug!("receive queue full, dropping newest frame");
+               self.rx_buf.count_dropped();
+               self.raise(Event::FrameDropped);
+            }
+            // If the application is reading the oldest frame right now, we drop the newest instead
+            RxOverflowPolicy::OverwriteOldest => {
+               log::debug!("receive queue full, dropping oldest frame");
+               self.rx_buf.count_dropped();
+               self.raise(Event::FrameDropped);
+               if producer.drop_oldest() {
+                  producer.commit();
+               }
+            }
+         }
+      }
+
+      // The slot of the next frame may still hold an old frame
+      producer.slot_mut().reset();
+      self.rx_buf.wake();
+      true
+   }
+
+   /// Attempts to write the next packet of a queued frame out to the host.
+   ///
+   /// This is done automatically on USB events. Call this from the context triggered by
+   /// the [`TxKick`] hook, to start sending a frame right after it has been queued.
+   pub fn try_send(&self) {
+      // While the bus is suspended, the frames are parked in the queue
+      if self.link.is_suspended() {
+         return;
+      }
+
+      let mut consumer = match self.tx_buf.consumer() {
+         None => return,
+         Some(consumer) => consumer,
+      };
+
+      // Skip if there is no data to send
+      let buf = match consumer.peek_mut() {
+         None => return,
+         Some(buf) => buf,
+      };
+
+      // Retreive the packet
+      let pkg = match buf.try_get_packet() {
+         None => return,
+         Some(pkg) => pkg,
+      };
+
+      // Send the packet to the host
+      match self.ecm.get_write_ep().write(pkg) {
+         Ok(bytes_written) if pkg.len() == bytes_written => {
+            buf.advance(bytes_written);
+
+            // Free the slot, once the frame has been sent completely
+            if !buf.is_sending() {
+               consumer.pop();
+               self.tx_buf.wake();
+            }
+         }
+         Ok(bytes_written) => {
+            log::error!("wrote {} bytes, expected {}", bytes_written, pkg.len());
+            //self.reset();
+         }
+         Err(UsbError::WouldBlock) => (),
+         Err(err) => {
+            log::error!("received unexpected error {:?}", err);
+            //self.reset();
+         }
+      }
+   }
+}
+
+impl<B: UsbBus> UsbClass<B> for UsbEthernetClass<'_, '_, B> {
+   fn endpoint_out(&mut self, addr: EndpointAddress) {
+      if addr == self.ecm.get_read_ep().address() {
+         self.try_recv();
+      }
+   }
+
+   fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+      if addr == self.ecm.get_write_ep().address() {
+         self.try_send();
+      } else if addr == self.ecm.get_comm_ep_address() {
+         self.ecm.send_notification();
+      }
+   }
+
+   fn reset(&mut self) {
+      abort_transfers(self.rx_buf, self.tx_buf);
+      self.ecm.reset();
+      self.raise(Event::Reset);
+      self.update_link();
+   }
+
+   // Pass through the control and setup calls
+   fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> UsbResult<()> {
+      self.ecm.get_configuration_descriptors(writer)
+   }
+
+   fn get_string(&self, index: StringIndex, lang_id: u16) -> Option<&str> {
+      self.ecm.get_string(index, lang_id)
+   }
+
+   fn control_in(&mut self, xfer: ControlIn<B>) {
+      self.ecm.control_in(xfer);
+   }
+
+   fn control_out(&mut self, xfer: ControlOut<B>) {
+      let req = *xfer.request();
+      let packet_filter = self.ecm.packet_filter();
+      self.ecm.control_out(xfer);
+
+      // We only observe the standard request, it is handled by `usb-device`
+      if req.request_type == RequestType::Standard
+         && req.recipient == Recipient::Device
+         && req.request == Request::SET_CONFIGURATION
+         && req.value != 0
+      {
+         self.raise(Event::Configured);
+      }
+
+      if self.ecm.packet_filter() != packet_filter {
+         self.raise(Event::PacketFilterChanged(self.ecm.packet_filter()));
+      }
+      self.update_link();
+   }
+
+   fn poll(&mut self) {
+      // NOTE: Once the receive queue has been full, the host is not read from, until there is
+      // room again. No further `endpoint_out` event arrives, so the reading is picked up here.
+      if self.link.is_up() {
+         self.try_recv();
+      }
+      // NOTE: Without a `TxKick` hook, we can not trigger try_send from the application side.
+      // Therefore we rely on poll to pick up and send out the data
+      self.try_send();
+      self.ecm.send_notification();
+   }
+}
+
+/// The receiving application side of a split [`UsbEthernetDevice`](crate::UsbEthernetDevice).
+#[derive(Debug)]
+pub struct FrameReceiver<'b> {
+   rx_buf: &'b RxBuf,
+   link: &'b Link,
+}
+
+impl<'b> FrameReceiver<'b> {
+   pub(crate) fn new(rx_buf: &'b RxBuf, link: &'b Link) -> Self {
+      Self { rx_buf, link }
+   }
+
+   #[cfg(any(feature = "smoltcp", feature = "embassy-net-driver", feature = "ip-only"))]
+   pub(crate) fn rx_buf(&self) -> &'b RxBuf {
+      self.rx_buf
+   }
+
+   /// Check, wether an ethernet frame is ready to be received
+   pub fn frame_ready(&self) -> bool {
+      match self.rx_buf.consumer() {
+         None => false,
+         Some(consumer) => consumer.peek().is_some(),
+      }
+   }
+
+   /// Returns the number of frames, that have been dropped due to the [`RxOverflowPolicy`]
+   pub fn dropped_frames(&self) -> u32 {
+      self.rx_buf.dropped_frames()
+   }
+
+   /// Returns the number of frames, that have been rejected as invalid
+   pub fn invalid_frames(&self) -> u32 {
+      self.rx_buf.invalid_frames()
+   }
+
+   /// Tries to receive an ethernet frame.
+   ///
+   /// If a frame is ready, the closure will be executed, which allows to copy out the ethernet frame.
+   ///
+   /// # Returns
+   /// - the length of the frame, if a frame was received
+   /// - `None`: otherwise
+   pub fn try_receive_frame<F>(&mut self, f: F) -> Option<usize>
+   where
+      F: FnOnce(&[u8]),
+   {
+      self.try_receive_frame_timestamped(|frame, _| f(frame))
+   }
+
+   /// Tries to receive an ethernet frame together with its receive timestamp.
+   ///
+   /// Works like [`try_receive_frame`](FrameReceiver::try_receive_frame), but the closure
+   /// also gets the time in microseconds at which the frame has been received completely.
+   /// The timestamp is `None`, if no [`Clock`] has been set.
+   pub fn try_receive_frame_timestamped<F>(&mut self, f: F) -> Option<usize>
+   where
+      F: FnOnce(&[u8], Option<u64>),
+   {
+      self.receive(f)
+   }
+
+   /// Takes the oldest frame out of the receive queue.
+   ///
+   /// NOTE: The consumer lock ensures, that only one caller gets to see the frame.
+   fn receive<F>(&self, f: F) -> Option<usize>
+   where
+      F: FnOnce(&[u8], Option<u64>),
+   {
+      let mut consumer = self.rx_buf.consumer()?;
+      let buf = consumer.peek_mut()?;
+      let timestamp = buf.timestamp();
+
+      let frame = buf.try_get_frame()?;
+      let len = frame.len();
+      f(frame, timestamp);
+
+      // Free the slot after reading the frame
+      consumer.pop();
+      Some(len)
+   }
+
+   /// Receives an ethernet frame into `buf` and returns its length.
+   ///
+   /// Waits until the [`UsbEthernetClass`] received a frame from the host.
+   /// If the frame is longer than `buf`, it is truncated.
+   pub async fn receive_frame(&self, buf: &mut [u8]) -> usize {
+      poll_fn(|cx| {
+         // Register first, such that a frame arriving in between is not missed
+         self.rx_buf.register_waker(cx.waker());
+         match self.receive(|frame, _| {
+            let len = frame.len().min(buf.len());
+            buf[..len].copy_from_slice(&frame[..len]);
+         }) {
+            Some(len) => Poll::Ready(len.min(buf.len())),
+            None => Poll::Pending,
+         }
+      })
+      .await
+   }
+
+   #[cfg(feature = "embassy-net-driver")]
+   pub(crate) fn link(&self) -> &'b Link {
+      self.link
+   }
+
+   /// Returns `true`, if the host has activated the data interface
+   pub fn is_link_up(&self) -> bool {
+      self.link.is_up()
+   }
+
+   /// Waits until the link is in the requested state.
+   pub async fn wait_for_link(&self, up: bool) {
+      poll_fn(|cx| {
+         self.link.register_waker(cx.waker());
+         match self.link.is_up() == up {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+         }
+      })
+      .await
+   }
+}
+
+impl fmt::Debug for FrameSender<'_> {
+   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      f.debug_struct("FrameSender")
+         .field("tx_buf", &self.tx_buf)
+         .field("link", &self.link)
+         .field("kick", &self.kick.is_some())
+         .field("wakeup", &self.wakeup.is_some())
+         .finish()
+   }
+}
+
+/// The sending application side of a split [`UsbEthernetDevice`](crate::UsbEthernetDevice).
+pub struct FrameSender<'b> {
+   tx_buf: &'b TxBuf,
+   link: &'b Link,
+   kick: Option<&'b dyn TxKick>,
+   wakeup: Option<&'b dyn RemoteWakeup>,
+}
+
+impl<'b> FrameSender<'b> {
+   pub(crate) fn new(
+      tx_buf: &'b TxBuf,
+      link: &'b Link,
+      kick: Option<&'b dyn TxKick>,
+      wakeup: Option<&'b dyn RemoteWakeup>,
+   ) -> Self {
+      Self {
+         tx_buf,
+         link,
+         kick,
+         wakeup,
+      }
+   }
+
+   #[cfg(any(feature = "smoltcp", feature = "embassy-net-driver"))]
+   pub(crate) fn tx_buf(&self) -> &'b TxBuf {
+      self.tx_buf
+   }
+
+   /// Returns `true`, if the bus is suspended
+   pub fn is_suspended(&self) -> bool {
+      self.link.is_suspended()
+   }
+
+   /// Returns the number of queued frames, that have been dropped due to the [`TxSuspendPolicy`]
+   pub fn dropped_frames(&self) -> u32 {
+      self.tx_buf.dropped_frames()
+   }
+
+   /// Returns `false`, if frames are refused right now due to the [`TxSuspendPolicy`]
+   pub(crate) fn accepts_frames(&self) -> bool {
+      !(self.link.is_suspended() && self.tx_buf.suspend_policy() == TxSuspendPolicy::Refuse)
+   }
+
+   /// Triggers the transmission of a frame, that has just been queued
+   pub(crate) fn frame_queued(&self) {
+      if let Some(kick) = self.kick {
+         kick.kick();
+      }
+
+      if self.link.is_suspended() && self.tx_buf.suspend_policy() == TxSuspendPolicy::Wakeup {
+         if let Some(wakeup) = self.wakeup {
+            wakeup.request_wakeup();
+         }
+      }
+   }
+
+   /// Tries to send an ethernet frame
+   ///
+   /// If there is room in the transmit queue, the closure is executed to allow copying in the bytes.
+   /// The frame is picked up by the [`UsbEthernetClass`] the next time it gets polled.
+   ///
+   /// # Returns
+   /// - `true`, if the packet was sent
+   /// - `false` otherwise, e.g. if `len` is shorter than an ethernet header
+   pub fn try_send_frame<F>(&mut self, len: usize, f: F) -> bool
+   where
+      F: FnOnce(&mut [u8]),
+   {
+      self.send(len, |buf| {
+         f(buf);
+         true
+      })
+   }
+
+   /// Like [`try_send_frame`](FrameSender::try_send_frame), but the frame is only queued,
+   /// if the closure returns `true`.
+   #[cfg(feature = "ip-only")]
+   pub(crate) fn try_send_frame_checked<F>(&mut self, len: usize, f: F) -> bool
+   where
+      F: FnOnce(&mut [u8]) -> bool,
+   {
+      self.send(len, f)
+   }
+
+   /// Queues a frame in the transmit queue, unless the closure writing it returns `false`.
+   ///
+   /// NOTE: The producer lock ensures, that only one caller can write a frame at a time.
+   fn send<F>(&self, len: usize, f: F) -> bool
+   where
+      F: FnOnce(&mut [u8]) -> bool,
+   {
+      // Frames without an ethernet header or longer than a buffer can never be sent.
+      // An empty frame would also never be picked up and stall the queue.
+      if !(ETH_HEADER_SIZE..=ETH_FRAME_SIZE).contains(&len) {
+         return false;
+      }
+
+      if !self.accepts_frames() {
+         log::debug!("bus suspended, refusing frame");
+         return false;
+      }
+
+      let mut producer = match self.tx_buf.producer() {
+         Some(producer) if !producer.is_full() => producer,
+         _ => return false,
+      };
+
+      match producer.slot_mut().try_send_frame(len, self.tx_buf.pad()) {
+         None => false,
+         Some(buf) => {
+            if !f(buf) {
+               producer.slot_mut().reset();
+               return false;
+            }
+            producer.commit();
+            drop(producer);
+
+            self.frame_queued();
+            true
+         }
+      }
+   }
+
+   /// Tries to send an ethernet frame, that is assembled from multiple parts.
+   ///
+   /// This allows to send e.g. a separately held header and payload, without
+   /// first copying them into a scratch buffer.
+   ///
+   /// # Returns
+   /// - `true`, if the packet was sent
+   /// - `false` otherwise
+   pub fn try_send_frame_vectored(&mut self, parts: &[&[u8]]) -> bool {
+      let len = parts.iter().map(|part| part.len()).sum();
+
+      self.try_send_frame(len, |buf| {
+         let mut idx = 0;
+         for part in parts {
+            buf[idx..idx + part.len()].copy_from_slice(part);
+            idx += part.len();
+         }
+      })
+   }
+
+   /// Sends an ethernet frame.
+   ///
+   /// Waits until there is room in the transmit queue.
+   /// Frames shorter than [`ETH_HEADER_SIZE`] or longer than [`ETH_FRAME_SIZE`] can never be sent and are dropped.
+   pub async fn send_frame(&self, frame: &[u8]) {
+      if !(ETH_HEADER_SIZE..=ETH_FRAME_SIZE).contains(&frame.len()) {
+         log::warn!("frame of {} bytes has an invalid length, dropping frame", frame.len());
+         return;
+      }
+
+      poll_fn(|cx| {
+         // Register first, such that room freed up in between is not missed
+         self.tx_buf.register_waker(cx.waker());
+         match self.send(frame.len(), |buf| {
+            buf[..frame.len()].copy_from_slice(frame);
+            true
+         }) {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+         }
+      })
+      .await
+   }
+}
+
+/// Abandons the transfers, that have been interrupted by a bus reset.
+///
+/// The partially received frame and the partially sent frame are only touched by the USB side,
+/// so this is safe to do from its context.
+fn abort_transfers(rx_buf: &RxBuf, tx_buf: &TxBuf) {
+   // The rest of a partially received frame never arrives
+   if let Some(mut producer) = rx_buf.producer() {
+      let buf = producer.slot_mut();
+      if !buf.frame_complete() {
+         buf.reset();
+      }
+      buf.stop_discarding();
+   }
+
+   // A partially sent frame is sent again from its beginning
+   if let Some(buf) = tx_buf.consumer().as_mut().and_then(|consumer| consumer.peek_mut()) {
+      buf.rewind();
+   }
+}
+
+/// Drops the frames waiting in the transmit queue and counts them as dropped.
+///
+/// Returns the number of dropped frames.
+fn drop_queued_frames(tx_buf: &TxBuf) -> usize {
+   let mut consumer = match tx_buf.consumer() {
+      None => return 0,
+      Some(consumer) => consumer,
+   };
+
+   let mut dropped = 0;
+   while let Some(buf) = consumer.peek_mut() {
+      log::debug!("bus suspended, dropping queued frame");
+      buf.reset();
+      consumer.pop();
+      tx_buf.count_dropped();
+      dropped += 1;
+   }
+   tx_buf.wake();
+   dropped
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::QUEUE_DEPTH;
+   extern crate std;
+   use core::{
+      future::Future,
+      pin::pin,
+      sync::atomic::{AtomicUsize, Ordering},
+      task::{Context, Waker},
+   };
+   use std::{sync::Arc, task::Wake};
+
+   /// Counts, how often it has been woken
+   #[derive(Default)]
+   struct CountingWaker(AtomicUsize);
+
+   impl Wake for CountingWaker {
+      fn wake(self: Arc<Self>) {
+         self.0.fetch_add(1, Ordering::Relaxed);
+      }
+   }
+
+   #[test]
+   fn receive_frame_is_woken_by_usb_side() {
+      let (rx_buf, link) = (RxBuf::new(), Link::new());
+      let receiver = FrameReceiver::new(&rx_buf, &link);
+      let counter = Arc::new(CountingWaker::default());
+      let waker = Waker::from(counter.clone());
+      let mut cx = Context::from_waker(&waker);
+
+      let mut buf = [0; ETH_FRAME_SIZE];
+      {
+         let mut future = pin!(receiver.receive_frame(&mut buf));
+         assert!(future.as_mut().poll(&mut cx).is_pending());
+
+         // Receive a frame like the USB side does
+         let mut producer = rx_buf.producer().unwrap();
+         let slot = producer.slot_mut();
+         slot.insert_packet()[..ETH_HEADER_SIZE].copy_from_slice(&[7; ETH_HEADER_SIZE]);
+         slot.advance(ETH_HEADER_SIZE);
+         assert!(producer.commit());
+         rx_buf.wake();
+         drop(producer);
+
+         assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+         assert_eq!(future.as_mut().poll(&mut cx), Poll::Ready(ETH_HEADER_SIZE));
+      }
+      assert_eq!(buf[..ETH_HEADER_SIZE], [7; ETH_HEADER_SIZE]);
+   }
+
+   #[test]
+   fn held_back_frame_is_queued_once_there_is_room() {
+      let (rx_buf, link) = (RxBuf::new(), Link::new());
+      let mut receiver = FrameReceiver::new(&rx_buf, &link);
+
+      // Receive one frame more than fits, like the USB side does
+      let mut producer = rx_buf.producer().unwrap();
+      for n in 0..=QUEUE_DEPTH {
+         let slot = producer.slot_mut();
+         slot.insert_packet()[..ETH_HEADER_SIZE].fill(n as u8);
+         slot.advance(ETH_HEADER_SIZE);
+         assert_eq!(producer.commit(), n < QUEUE_DEPTH);
+         if n < QUEUE_DEPTH {
+            producer.slot_mut().reset();
+         }
+      }
+      drop(producer);
+
+      // Processing a frame makes room for the held back one without another packet from the host
+      for n in 0..=QUEUE_DEPTH {
+         assert_eq!(receiver.try_receive_frame(|frame| assert_eq!(frame[0], n as u8)), Some(ETH_HEADER_SIZE));
+      }
+      assert!(!receiver.frame_ready());
+      assert!(!rx_buf.producer().unwrap().slot().frame_complete());
+   }
+
+   #[test]
+   fn suspend_policy_gates_frames() {
+      let (mut tx_buf, link) = (TxBuf::new(), Link::new());
+      link.set_suspended(true);
+
+      let mut sender = FrameSender::new(&tx_buf, &link, None, None);
+      assert!(sender.is_suspended());
+      assert!(!sender.try_send_frame(ETH_HEADER_SIZE, |_| ()));
+
+      tx_buf.set_suspend_policy(TxSuspendPolicy::Park);
+      let mut sender = FrameSender::new(&tx_buf, &link, None, None);
+      assert!(sender.try_send_frame(ETH_HEADER_SIZE, |_| ()));
+   }
+
+   #[test]
+   fn queued_frames_are_dropped_and_counted() {
+      let (tx_buf, link) = (TxBuf::new(), Link::new());
+      let mut sender = FrameSender::new(&tx_buf, &link, None, None);
+      assert!(sender.try_send_frame(ETH_HEADER_SIZE, |_| ()));
+      assert!(sender.try_send_frame(ETH_HEADER_SIZE, |_| ()));
+
+      assert_eq!(drop_queued_frames(&tx_buf), 2);
+      assert_eq!(tx_buf.dropped_frames(), 2);
+      assert!(tx_buf.consumer().unwrap().peek().is_none());
+      assert_eq!(drop_queued_frames(&tx_buf), 0);
+   }
+
+   #[test]
+   fn frames_without_header_are_refused() {
+      let (tx_buf, link) = (TxBuf::new(), Link::new());
+      let mut sender = FrameSender::new(&tx_buf, &link, None, None);
+
+      assert!(!sender.try_send_frame(0, |_| ()));
+      assert!(!sender.try_send_frame(ETH_HEADER_SIZE - 1, |_| ()));
+      assert!(!sender.try_send_frame_vectored(&[]));
+      assert!(tx_buf.consumer().unwrap().peek().is_none());
+
+      // The queue does not stall
+      assert!(sender.try_send_frame_vectored(&[&[1; ETH_HEADER_SIZE], &[2; 4]]));
+      let mut consumer = tx_buf.consumer().unwrap();
+      assert_eq!(consumer.peek_mut().unwrap().try_get_packet().unwrap().len(), ETH_HEADER_SIZE + 4);
+   }
+
+   #[test]
+   fn bus_reset_aborts_partial_transfers() {
+      let (rx_buf, tx_buf, link) = (RxBuf::new(), TxBuf::new(), Link::new());
+
+      // The host is in the middle of sending a frame
+      {
+         let mut producer = rx_buf.producer().unwrap();
+         let slot = producer.slot_mut();
+         slot.insert_packet()[..EP_PKG_USIZE].fill(1);
+         slot.advance(EP_PKG_USIZE);
+         slot.start_discarding();
+      }
+
+      // The device is in the middle of sending a frame
+      let mut sender = FrameSender::new(&tx_buf, &link, None, None);
+      assert!(sender.try_send_frame(ETH_FRAME_SIZE, |buf| buf.fill(2)));
+      {
+         let mut consumer = tx_buf.consumer().unwrap();
+         let buf = consumer.peek_mut().unwrap();
+         let len = buf.try_get_packet().unwrap().len();
+         buf.advance(len);
+      }
+
+      abort_transfers(&rx_buf, &tx_buf);
+
+      // The next frame from the host starts over
+      let mut producer = rx_buf.producer().unwrap();
+      assert_eq!(producer.slot().received_len(), 0);
+      assert!(!producer.slot().is_discarding());
+      assert!(!producer.slot_mut().frame_complete());
+
+      // The interrupted frame is sent completely
+      let mut consumer = tx_buf.consumer().unwrap();
+      let buf = consumer.peek_mut().unwrap();
+      let mut sent = 0;
+      while let Some(pkg) = buf.try_get_packet() {
+         let len = pkg.len();
+         sent += len;
+         buf.advance(len);
+      }
+      assert_eq!(sent, ETH_FRAME_SIZE);
+   }
+
+   #[test]
+   fn link_changes_are_awaitable() {
+      let (rx_buf, link) = (RxBuf::new(), Link::new());
+      let receiver = FrameReceiver::new(&rx_buf, &link);
+      let counter = Arc::new(CountingWaker::default());
+      let waker = Waker::from(counter.clone());
+      let mut cx = Context::from_waker(&waker);
+
+      let mut future = pin!(receiver.wait_for_link(true));
+      assert!(future.as_mut().poll(&mut cx).is_pending());
+
+      assert!(link.set_up(true));
+      assert!(!link.set_up(true));
+      assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+      assert!(future.as_mut().poll(&mut cx).is_ready());
+      assert!(receiver.is_link_up());
+   }
+}
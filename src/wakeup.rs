@@ -0,0 +1,15 @@
+//! This module contains the [`RemoteWakeup`] trait, which lets the application side
+//! wake up a suspended host, if it has frames to send.
+
+/// A hook, provided by the user, to signal a remote wakeup to the host.
+///
+/// `usb-device` does not implement remote wakeup signaling, since it is hardware specific.
+/// If the [`TxSuspendPolicy::Wakeup`](crate::TxSuspendPolicy::Wakeup) is set, this is called
+/// every time a frame has been queued, while the bus is suspended.
+pub trait RemoteWakeup: Sync {
+   /// Called after a frame has been queued, while the bus is suspended.
+   ///
+   /// The implementation has to check `UsbDevice::remote_wakeup_enabled`, since the host
+   /// needs to allow the device to wake it up.
+   fn request_wakeup(&self);
+}
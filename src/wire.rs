@@ -0,0 +1,361 @@
+//! This module contains minimal helpers to parse and emit the frames of the built-in
+//! network services, such that they also work without a network stack.
+//!
+//! Only what the services need is implemented, e.g. IPv4 options and fragments are not supported.
+
+use crate::ETH_HEADER_SIZE;
+
+pub const ETH_ADDR_BROADCAST: [u8; 6] = [0xff; 6];
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+pub const ARP_PACKET_SIZE: usize = 28;
+pub const ARP_OP_REQUEST: u16 = 1;
+pub const ARP_OP_REPLY: u16 = 2;
+
+pub const IPV4_HEADER_SIZE: usize = 20;
+pub const IPV4_ADDR_BROADCAST: [u8; 4] = [0xff; 4];
+pub const IP_PROTO_ICMP: u8 = 1;
+pub const IP_PROTO_TCP: u8 = 6;
+pub const IP_PROTO_UDP: u8 = 17;
+
+pub const UDP_HEADER_SIZE: usize = 8;
+
+pub const IPV6_HEADER_SIZE: usize = 40;
+pub const IP_PROTO_ICMPV6: u8 = 58;
+/// The all nodes multicast address `ff02::1`
+pub const IPV6_ALL_NODES: [u8; 16] = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01];
+
+/// Returns the ethernet address, an IPv4 multicast address is mapped to, RFC 1112
+pub fn ipv4_multicast_mac(addr: &[u8; 4]) -> [u8; 6] {
+   [0x01, 0x00, 0x5e, addr[1] & 0x7f, addr[2], addr[3]]
+}
+
+/// The time to live of the IP packets we send
+const IP_TTL: u8 = 64;
+
+pub fn read_u16(buf: &[u8], idx: usize) -> u16 {
+   u16::from_be_bytes([buf[idx], buf[idx + 1]])
+}
+
+pub fn write_u16(buf: &mut [u8], idx: usize, value: u16) {
+   buf[idx..idx + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Adds `data` to the internet checksum `sum`, RFC 1071
+pub fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+   let mut chunks = data.chunks_exact(2);
+   for chunk in &mut chunks {
+      sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+   }
+   if let [last] = chunks.remainder() {
+      sum += (*last as u32) << 8;
+   }
+   sum
+}
+
+/// Folds the internet checksum `sum` into its final form
+pub fn checksum_finish(mut sum: u32) -> u16 {
+   while sum > 0xffff {
+      sum = (sum & 0xffff) + (sum >> 16);
+   }
+   !(sum as u16)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EthernetHeader {
+   pub dst: [u8; 6],
+   pub src: [u8; 6],
+   pub ethertype: u16,
+}
+
+impl EthernetHeader {
+   /// Parses the ethernet header and returns it together with the payload
+   pub fn parse(frame: &[u8]) -> Option<(Self, &[u8])> {
+      if frame.len() < ETH_HEADER_SIZE {
+         return None;
+      }
+
+      let mut header = Self {
+         dst: [0; 6],
+         src: [0; 6],
+         ethertype: read_u16(frame, 12),
+      };
+      header.dst.copy_from_slice(&frame[0..6]);
+      header.src.copy_from_slice(&frame[6..12]);
+      Some((header, &frame[ETH_HEADER_SIZE..]))
+   }
+
+   /// Writes the header to the start of `buf`
+   pub fn emit(&self, buf: &mut [u8]) {
+      buf[0..6].copy_from_slice(&self.dst);
+      buf[6..12].copy_from_slice(&self.src);
+      write_u16(buf, 12, self.ethertype);
+   }
+}
+
+/// An ARP packet for IPv4 over ethernet, RFC 826
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+   pub op: u16,
+   pub sender_mac: [u8; 6],
+   pub sender_ip: [u8; 4],
+   pub target_mac: [u8; 6],
+   pub target_ip: [u8; 4],
+}
+
+impl ArpPacket {
+   /// Parses an ARP packet, returns `None`, if it is not for IPv4 over ethernet
+   pub fn parse(packet: &[u8]) -> Option<Self> {
+      if packet.len() < ARP_PACKET_SIZE || packet[0..6] != [0, 1, 0x08, 0x00, 6, 4] {
+         return None;
+      }
+
+      let mut arp = Self {
+         op: read_u16(packet, 6),
+         sender_mac: [0; 6],
+         sender_ip: [0; 4],
+         target_mac: [0; 6],
+         target_ip: [0; 4],
+      };
+      arp.sender_mac.copy_from_slice(&packet[8..14]);
+      arp.sender_ip.copy_from_slice(&packet[14..18]);
+      arp.target_mac.copy_from_slice(&packet[18..24]);
+      arp.target_ip.copy_from_slice(&packet[24..28]);
+      Some(arp)
+   }
+
+   /// Writes the packet to the start of `buf`
+   pub fn emit(&self, buf: &mut [u8]) {
+      buf[0..6].copy_from_slice(&[0, 1, 0x08, 0x00, 6, 4]);
+      write_u16(buf, 6, self.op);
+      buf[8..14].copy_from_slice(&self.sender_mac);
+      buf[14..18].copy_from_slice(&self.sender_ip);
+      buf[18..24].copy_from_slice(&self.target_mac);
+      buf[24..28].copy_from_slice(&self.target_ip);
+   }
+}
+
+/// Writes an ARP frame into `buf` and returns its length
+pub fn emit_arp_frame(buf: &mut [u8], dst: [u8; 6], arp: &ArpPacket) -> usize {
+   let eth = EthernetHeader {
+      dst,
+      src: arp.sender_mac,
+      ethertype: ETHERTYPE_ARP,
+   };
+   eth.emit(buf);
+   arp.emit(&mut buf[ETH_HEADER_SIZE..]);
+   ETH_HEADER_SIZE + ARP_PACKET_SIZE
+}
+
+/// The pseudo header of the transport protocols, that is covered by their checksum
+pub trait PseudoHeader {
+   /// Returns the checksum of the pseudo header for a segment of `len` bytes
+   fn pseudo_header_checksum(&self, len: usize) -> u32;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Header {
+   pub src: [u8; 4],
+   pub dst: [u8; 4],
+   pub protocol: u8,
+}
+
+impl Ipv4Header {
+   /// Parses the IPv4 header and returns it together with the payload.
+   /// Returns `None` for invalid and fragmented packets.
+   pub fn parse(packet: &[u8]) -> Option<(Self, &[u8])> {
+      if packet.len() < IPV4_HEADER_SIZE || packet[0] >> 4 != 4 {
+         return None;
+      }
+
+      let header_len = ((packet[0] & 0x0f) as usize) * 4;
+      let total_len = read_u16(packet, 2) as usize;
+      let fragmented = read_u16(packet, 6) & 0x3fff != 0;
+      if header_len < IPV4_HEADER_SIZE || total_len < header_len || total_len > packet.len() || fragmented {
+         return None;
+      }
+
+      if checksum_finish(checksum_add(0, &packet[..header_len])) != 0 {
+         return None;
+      }
+
+      let mut header = Self {
+         src: [0; 4],
+         dst: [0; 4],
+         protocol: packet[9],
+      };
+      header.src.copy_from_slice(&packet[12..16]);
+      header.dst.copy_from_slice(&packet[16..20]);
+      Some((header, &packet[header_len..total_len]))
+   }
+
+   /// Writes the header without options to the start of `buf`
+   pub fn emit(&self, buf: &mut [u8], payload_len: usize) {
+      buf[..IPV4_HEADER_SIZE].fill(0);
+      buf[0] = 0x45;
+      write_u16(buf, 2, (IPV4_HEADER_SIZE + payload_len) as u16);
+      buf[8] = IP_TTL;
+      buf[9] = self.protocol;
+      buf[12..16].copy_from_slice(&self.src);
+      buf[16..20].copy_from_slice(&self.dst);
+
+      let checksum = checksum_finish(checksum_add(0, &buf[..IPV4_HEADER_SIZE]));
+      write_u16(buf, 10, checksum);
+   }
+}
+
+impl PseudoHeader for Ipv4Header {
+   fn pseudo_header_checksum(&self, len: usize) -> u32 {
+      let sum = checksum_add(0, &self.src);
+      let sum = checksum_add(sum, &self.dst);
+      sum + self.protocol as u32 + len as u32
+   }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpHeader {
+   pub src_port: u16,
+   pub dst_port: u16,
+}
+
+impl UdpHeader {
+   /// Parses the UDP header and returns it together with the payload.
+   /// Returns `None`, if the length or the checksum is wrong.
+   pub fn parse<'p>(ip: &impl PseudoHeader, datagram: &'p [u8]) -> Option<(Self, &'p [u8])> {
+      if datagram.len() < UDP_HEADER_SIZE {
+         return None;
+      }
+
+      let len = read_u16(datagram, 4) as usize;
+      if len < UDP_HEADER_SIZE || len > datagram.len() {
+         return None;
+      }
+
+      // A checksum of zero means, that the sender did not compute one
+      let datagram = &datagram[..len];
+      if read_u16(datagram, 6) != 0 && checksum_finish(checksum_add(ip.pseudo_header_checksum(len), datagram)) != 0 {
+         return None;
+      }
+
+      let header = Self {
+         src_port: read_u16(datagram, 0),
+         dst_port: read_u16(datagram, 2),
+      };
+      Some((header, &datagram[UDP_HEADER_SIZE..]))
+   }
+
+   /// Writes the header to the start of `buf`, the payload needs to follow already
+   pub fn emit(&self, buf: &mut [u8], ip: &impl PseudoHeader, payload_len: usize) {
+      let len = UDP_HEADER_SIZE + payload_len;
+      write_u16(buf, 0, self.src_port);
+      write_u16(buf, 2, self.dst_port);
+      write_u16(buf, 4, len as u16);
+      write_u16(buf, 6, 0);
+
+      // A checksum of zero is transmitted as all ones
+      let checksum = match checksum_finish(checksum_add(ip.pseudo_header_checksum(len), &buf[..len])) {
+         0 => 0xffff,
+         checksum => checksum,
+      };
+      write_u16(buf, 6, checksum);
+   }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6Header {
+   pub src: [u8; 16],
+   pub dst: [u8; 16],
+   pub next_header: u8,
+   pub hop_limit: u8,
+}
+
+impl Ipv6Header {
+   /// Parses the IPv6 header and returns it together with the payload.
+   /// Extension headers are not parsed, they show up as `next_header`.
+   pub fn parse(packet: &[u8]) -> Option<(Self, &[u8])> {
+      if packet.len() < IPV6_HEADER_SIZE || packet[0] >> 4 != 6 {
+         return None;
+      }
+
+      let payload_len = read_u16(packet, 4) as usize;
+      if IPV6_HEADER_SIZE + payload_len > packet.len() {
+         return None;
+      }
+
+      let mut header = Self {
+         src: [0; 16],
+         dst: [0; 16],
+         next_header: packet[6],
+         hop_limit: packet[7],
+      };
+      header.src.copy_from_slice(&packet[8..24]);
+      header.dst.copy_from_slice(&packet[24..40]);
+      Some((header, &packet[IPV6_HEADER_SIZE..IPV6_HEADER_SIZE + payload_len]))
+   }
+
+   /// Writes the header to the start of `buf`
+   pub fn emit(&self, buf: &mut [u8], payload_len: usize) {
+      buf[..IPV6_HEADER_SIZE].fill(0);
+      buf[0] = 0x60;
+      write_u16(buf, 4, payload_len as u16);
+      buf[6] = self.next_header;
+      buf[7] = self.hop_limit;
+      buf[8..24].copy_from_slice(&self.src);
+      buf[24..40].copy_from_slice(&self.dst);
+   }
+}
+
+/// The pseudo header of IPv6 is also covered by the checksum of ICMPv6
+impl PseudoHeader for Ipv6Header {
+   fn pseudo_header_checksum(&self, len: usize) -> u32 {
+      let sum = checksum_add(0, &self.src);
+      let sum = checksum_add(sum, &self.dst);
+      sum + (len as u32 >> 16) + (len as u32 & 0xffff) + self.next_header as u32
+   }
+}
+
+/// Returns the ethernet address, an IPv6 multicast address is mapped to, RFC 2464
+pub fn ipv6_multicast_mac(addr: &[u8; 16]) -> [u8; 6] {
+   [0x33, 0x33, addr[12], addr[13], addr[14], addr[15]]
+}
+
+/// Returns the link-local IPv6 address, that is derived from a mac address, RFC 4291
+pub fn ipv6_link_local(mac: &[u8; 6]) -> [u8; 16] {
+   let mut addr = [0; 16];
+   addr[0..2].copy_from_slice(&[0xfe, 0x80]);
+   addr[8..11].copy_from_slice(&[mac[0] ^ 0x02, mac[1], mac[2]]);
+   addr[11..13].copy_from_slice(&[0xff, 0xfe]);
+   addr[13..16].copy_from_slice(&mac[3..6]);
+   addr
+}
+
+/// Writes the headers of a UDP datagram, whose payload already follows the headers in `buf`.
+/// Returns the length of the frame.
+pub fn emit_udp_frame(buf: &mut [u8], eth: &EthernetHeader, ip: &Ipv4Header, udp: &UdpHeader, payload_len: usize) -> usize {
+   let ip_start = ETH_HEADER_SIZE;
+   let udp_start = ip_start + IPV4_HEADER_SIZE;
+
+   eth.emit(buf);
+   ip.emit(&mut buf[ip_start..], UDP_HEADER_SIZE + payload_len);
+   udp.emit(&mut buf[udp_start..], ip, payload_len);
+   udp_start + UDP_HEADER_SIZE + payload_len
+}
+
+/// The offset of the payload of a UDP datagram in a frame written by [`emit_udp_frame`]
+pub const UDP_PAYLOAD_OFFSET: usize = ETH_HEADER_SIZE + IPV4_HEADER_SIZE + UDP_HEADER_SIZE;
+
+/// Like [`emit_udp_frame`], but for a datagram over IPv6
+pub fn emit_udp6_frame(buf: &mut [u8], eth: &EthernetHeader, ip: &Ipv6Header, udp: &UdpHeader, payload_len: usize) -> usize {
+   let ip_start = ETH_HEADER_SIZE;
+   let udp_start = ip_start + IPV6_HEADER_SIZE;
+
+   eth.emit(buf);
+   ip.emit(&mut buf[ip_start..], UDP_HEADER_SIZE + payload_len);
+   udp.emit(&mut buf[udp_start..], ip, payload_len);
+   udp_start + UDP_HEADER_SIZE + payload_len
+}
+
+/// The offset of the payload of a UDP datagram in a frame written by [`emit_udp6_frame`]
+pub const UDP6_PAYLOAD_OFFSET: usize = ETH_HEADER_SIZE + IPV6_HEADER_SIZE + UDP_HEADER_SIZE;